@@ -1,10 +1,14 @@
-use std::sync::OnceLock;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use rand::Rng;
-use std::sync::{RwLock, Arc};
-use std::sync::atomic::{AtomicI32, AtomicBool, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use std::io::{self, BufRead, Write};
 
+mod syzygy;
+mod retrograde;
+mod book;
+mod pgn;
+
 
 const WP: usize = 0;
 const WN: usize = 1;
@@ -149,7 +153,7 @@ fn create_board() -> [u64; 12] {
 
 #[inline]
 fn get_bit(bitboard: u64, square: u8) -> bool {
-    return (bitboard >> square) & 1 != 0;
+    (bitboard >> square) & 1 != 0
 }
 
 
@@ -199,18 +203,8 @@ fn get_all_empty(board: [u64; 12]) -> u64 {
 }
 
 
-fn is_square_occupied(board: &[u64; 12], square: u8) -> bool {
-    get_bit(get_all_occupied(*board), square)
-}
-
-
 fn get_piece_at_square(board: &[u64; 12], square: u8) -> Option<usize> {
-    for piece_type in 0..12 {
-        if get_bit(board[piece_type], square) {
-            return Some(piece_type);
-        }
-    }
-    None
+    (0..12).find(|&piece_type| get_bit(board[piece_type], square))
 }
 
 fn get_piece_value(piece_type: usize) -> i32 {
@@ -245,10 +239,10 @@ fn precompute_knight_attacks() {
         let mut attack_mask = 0;
         
         for &(dr, df) in &knight_moves {
-            let new_rank = rank as i32 + dr;
-            let new_file = file as i32 + df;
+            let new_rank = rank + dr;
+            let new_file = file + df;
             
-            if new_rank >= 0 && new_rank < 8 && new_file >= 0 && new_file < 8 {
+            if (0..8).contains(&new_rank) && (0..8).contains(&new_file) {
                 let target_square = (new_rank * 8 + new_file) as u8;
                 set_bit(&mut attack_mask, target_square);
             }
@@ -276,10 +270,10 @@ fn precompute_king_attacks() {
         let mut attack_mask = 0;
         
         for &(dr, df) in &king_moves {
-            let new_rank = rank as i32 + dr;
-            let new_file = file as i32 + df;
+            let new_rank = rank + dr;
+            let new_file = file + df;
             
-            if new_rank >= 0 && new_rank < 8 && new_file >= 0 && new_file < 8 {
+            if (0..8).contains(&new_rank) && (0..8).contains(&new_file) {
                 let target_square = (new_rank * 8 + new_file) as u8;
                 set_bit(&mut attack_mask, target_square);
             }
@@ -302,16 +296,16 @@ fn precompute_pawn_attacks() {
     for square in 0..64 {
         let (rank, file) = (square / 8, square % 8);
         
-        // White pawns
-        if rank < 7 {
-            if file > 0 { set_bit(&mut white_attacks[square as usize], square + 7); } // up-left
-            if file < 7 { set_bit(&mut white_attacks[square as usize], square + 9); } // up-right
+        // White pawns (moving towards a8=0)
+        if rank > 0 {
+            if file > 0 { set_bit(&mut white_attacks[square as usize], square - 9); } // up-left
+            if file < 7 { set_bit(&mut white_attacks[square as usize], square - 7); } // up-right
         }
         
-        // Black pawns
-        if rank > 0 {
-            if file > 0 { set_bit(&mut black_attacks[square as usize], square - 9); } // down-left
-            if file < 7 { set_bit(&mut black_attacks[square as usize], square - 7); } // down-right
+        // Black pawns (moving towards h1=63)
+        if rank < 7 {
+            if file > 0 { set_bit(&mut black_attacks[square as usize], square + 7); } // down-left
+            if file < 7 { set_bit(&mut black_attacks[square as usize], square + 9); } // down-right
         }
     }
     
@@ -423,7 +417,6 @@ fn get_queen_attacks(square: u8, blockers: u64) -> u64 {
 fn generate_pawn_moves(board: &[u64; 12], moves: &mut Vec<(u8, u8)>, white: bool) {
     let pawns = if white { board[WP] } else { board[BP] };
     let enemy_occupied = if white { get_all_black(*board) } else { get_all_white(*board) };
-    let all_occupied = get_all_occupied(*board);
     let empty = get_all_empty(*board);
     
     let mut pawns_copy = pawns;
@@ -432,18 +425,17 @@ fn generate_pawn_moves(board: &[u64; 12], moves: &mut Vec<(u8, u8)>, white: bool
         clear_bit(&mut pawns_copy, from_square);
         
         let rank = from_square / 8;
-        let file = from_square % 8;
         
         if white {
             // Single push forward
-            let single_push = from_square - 8;
-            if single_push >= 0 && single_push < 64 && get_bit(empty, single_push) {
+            let single_push = from_square.wrapping_sub(8);
+            if single_push < 64 && get_bit(empty, single_push) {
                 moves.push((from_square, single_push));
                 
                 // Double push from starting rank
                 if rank == 6 {  // White pawns start on rank 6 (48-55)
                     let double_push = from_square - 16;
-                    if double_push >= 0 && double_push < 64 && get_bit(empty, double_push) {
+                    if get_bit(empty, double_push) {
                         moves.push((from_square, double_push));
                     }
                 }
@@ -465,29 +457,27 @@ fn generate_pawn_moves(board: &[u64; 12], moves: &mut Vec<(u8, u8)>, white: bool
         } else { // Black pawns (moving downward)
             // Single push forward
             let single_push = from_square + 8;
-            if single_push >= 0 && single_push < 64 && get_bit(empty, single_push) {
+            if single_push < 64 && get_bit(empty, single_push) {
                 moves.push((from_square, single_push));
                 
                 // Double push from starting rank  
                 if rank == 1 {  // Black pawns start on rank 1 (8-15)
                     let double_push = from_square + 16;
-                    if double_push >= 0 && double_push < 64 && get_bit(empty, double_push) {
+                    if get_bit(empty, double_push) {
                         moves.push((from_square, double_push));
                     }
                 }
             }
             
             // Captures
-            unsafe {
-                let attacks = BLACK_PAWN_ATTACKS.get().unwrap()[from_square as usize];
-                let mut attacks_copy = attacks;
-                while attacks_copy != 0 {
-                    let target_square = get_lsb(attacks_copy).unwrap();
-                    clear_bit(&mut attacks_copy, target_square);
-                    
-                    if get_bit(enemy_occupied, target_square) {
-                        moves.push((from_square, target_square));
-                    }
+            let attacks = BLACK_PAWN_ATTACKS.get().unwrap()[from_square as usize];
+            let mut attacks_copy = attacks;
+            while attacks_copy != 0 {
+                let target_square = get_lsb(attacks_copy).unwrap();
+                clear_bit(&mut attacks_copy, target_square);
+                
+                if get_bit(enemy_occupied, target_square) {
+                    moves.push((from_square, target_square));
                 }
             }
         }
@@ -709,6 +699,75 @@ fn generate_moves(board: [u64; 12], white_move: bool, board_state: &BoardState)
     moves
 }
 
+// Captures, and pawn pushes that promote, without the quiet moves
+fn generate_captures(board_state: &BoardState) -> Vec<(u8, u8)> {
+    let board = &board_state.bitboards;
+    let white = board_state.white_to_move;
+    let enemy_occupied = if white { get_all_black(*board) } else { get_all_white(*board) };
+    let all_occupied = get_all_occupied(*board);
+    let first = if white { WP } else { BP };
+    let mut moves = Vec::new();
+    
+    for (piece, &bitboard) in board.iter().enumerate().skip(first).take(6) {
+        let mut pieces = bitboard;
+        while pieces != 0 {
+            let from_square = get_lsb(pieces).unwrap();
+            clear_bit(&mut pieces, from_square);
+            
+            let mut targets = enemy_occupied & match piece - first {
+                0 if white => WHITE_PAWN_ATTACKS.get().unwrap()[from_square as usize],
+                0 => BLACK_PAWN_ATTACKS.get().unwrap()[from_square as usize],
+                1 => KNIGHT_ATTACKS.get().unwrap()[from_square as usize],
+                2 => get_bishop_attacks(from_square, all_occupied),
+                3 => get_rook_attacks(from_square, all_occupied),
+                4 => get_queen_attacks(from_square, all_occupied),
+                _ => KING_ATTACKS.get().unwrap()[from_square as usize],
+            };
+            while targets != 0 {
+                let target_square = get_lsb(targets).unwrap();
+                clear_bit(&mut targets, target_square);
+                moves.push((from_square, target_square));
+            }
+            
+            if piece == first {
+                let push = if white { from_square.wrapping_sub(8) } else { from_square + 8 };
+                if is_promotion(piece, push) && !get_bit(all_occupied, push) {
+                    moves.push((from_square, push));
+                }
+            }
+        }
+    }
+    moves
+}
+
+fn is_promotion(piece: usize, to: u8) -> bool {
+    (piece == WP && to < 8) || (piece == BP && to >= 56)
+}
+
+// Whether a move from the TT or the killer table can be played here,
+// checked against the moves of that one piece type
+fn is_pseudo_legal(board_state: &BoardState, (from, to): (u8, u8)) -> bool {
+    let board = &board_state.bitboards;
+    let white = board_state.white_to_move;
+    let Some(piece) = get_piece_at_square(board, from) else {
+        return false;
+    };
+    if (piece < BP) != white {
+        return false;
+    }
+    
+    let mut moves = Vec::new();
+    match piece % 6 {
+        WP => generate_pawn_moves(board, &mut moves, white),
+        WN => generate_knight_moves(board, &mut moves, white),
+        WB => generate_bishop_moves(board, &mut moves, white),
+        WR => generate_rook_moves(board, &mut moves, white),
+        WQ => generate_queen_moves(board, &mut moves, white),
+        _ => generate_king_moves(board, &mut moves, white, board_state),
+    }
+    moves.contains(&(from, to))
+}
+
 //END OF MOVE GENERATION-----------------------------------------------------------------------------------
 
 
@@ -841,8 +900,8 @@ fn is_check(board: [u64; 12], white_king: bool) -> bool {
 //PRINTING FUNCTIONS---------------------------------------------------------------------------------------
 
 fn square_to_coordinates(square: u8) -> String {
-    let file: u8 = (square % 8) as u8;
-    let rank: u8 = (square / 8) as u8;
+    let file: u8 = square % 8;
+    let rank: u8 = square / 8;
     let file_char: char = (b'a' + file) as char;
     let rank_char: char = (b'8' - rank) as char; // 0=a8, 63=h1 in your system
     format!("{}{}", file_char, rank_char)
@@ -856,7 +915,7 @@ fn coordinates_to_square(coords: &str) -> Option<u8> {
     let file_char = chars.next()?;
     let rank_char = chars.next()?;
     
-    if file_char < 'a' || file_char > 'h' || rank_char < '1' || rank_char > '8' {
+    if !('a'..='h').contains(&file_char) || !('1'..='8').contains(&rank_char) {
         return None;
     }
     
@@ -887,13 +946,6 @@ fn uci_to_move(uci_move: &str) -> Option<(u8, u8)> {
 }
 
 
-fn print_moves(moves: &[(u8, u8)]) {
-    for &(from, to) in moves {
-        println!("{} -> {}", square_to_coordinates(from), square_to_coordinates(to));
-    }
-}
-
-
 fn print_board(board: &BoardState) {
     println!("\n  a b c d e f g h");
     for rank in 0..8 {
@@ -955,10 +1007,7 @@ fn make_move(board: &mut BoardState, from: u8, to: u8) -> Option<Move> {
         }
     }
     
-    let moving_piece: usize = match moving_piece {
-        Some(p) => p,
-        None => return None,  // Changed from false to None
-    };
+    let moving_piece: usize = moving_piece?;
     
     // Handle castling moves
     if (moving_piece == WK || moving_piece == BK) && (from as i32 - to as i32).abs() == 2 {
@@ -984,7 +1033,16 @@ fn make_move(board: &mut BoardState, from: u8, to: u8) -> Option<Move> {
         clear_bit(&mut board.bitboards[captured], to);
     }
     
-    set_bit(&mut board.bitboards[moving_piece], to);
+    // Pawns reaching the last rank always promote to a queen
+    let promotion = if moving_piece == WP && to < 8 {
+        Some(WQ)
+    } else if moving_piece == BP && to >= 56 {
+        Some(BQ)
+    } else {
+        None
+    };
+    
+    set_bit(&mut board.bitboards[promotion.unwrap_or(moving_piece)], to);
     
     // Update castling rights
     if moving_piece == WK || moving_piece == BK {
@@ -1004,7 +1062,7 @@ fn make_move(board: &mut BoardState, from: u8, to: u8) -> Option<Move> {
         to,
         piece: moving_piece,
         captured_piece,
-        promotion: None,
+        promotion,
         castling_move: false,
         en_passant: false,
         previous_castling_rights: previous_castling,
@@ -1015,7 +1073,7 @@ fn make_move(board: &mut BoardState, from: u8, to: u8) -> Option<Move> {
 fn unmake_move(board: &mut BoardState, mv: &Move) {
     board.white_to_move = !board.white_to_move;
     
-    clear_bit(&mut board.bitboards[mv.piece], mv.to);
+    clear_bit(&mut board.bitboards[mv.promotion.unwrap_or(mv.piece)], mv.to);
     set_bit(&mut board.bitboards[mv.piece], mv.from);
     
     if let Some(captured_piece) = mv.captured_piece {
@@ -1115,12 +1173,68 @@ fn make_castling_move(board: &mut BoardState, from: u8, to: u8, white: bool) ->
 
 //MOVE HISTORY--------------------------------------------------------------------------------------------
 
-struct SearchState {
+struct SearchState<'a> {
     move_history: Vec<Move>,
     board: BoardState,
+    nodes: u64,      // Positions visited, main search and quiescence
+    seldepth: usize, // Deepest ply reached since the last reset
+    pv: Vec<Vec<(u8, u8)>>, // Triangular PV table: best line found from each ply
+    stop: Option<&'a AtomicBool>, // Polled while searching; None searches to the end
+    node_limit: Option<u64>, // Nodes after which the search stops as if told to
+    time_limit: Option<(&'a SearchClock, Duration)>, // Likewise for time on the clock
+    stopped: bool,   // The stop flag was seen, so scores from here on are worthless
 }
 
-impl SearchState {
+impl<'a> SearchState<'a> {
+    fn new(board: BoardState) -> Self {
+        Self {
+            move_history: Vec::new(),
+            board,
+            nodes: 0,
+            seldepth: 0,
+            pv: Vec::new(),
+            stop: None,
+            node_limit: None,
+            time_limit: None,
+            stopped: false,
+        }
+    }
+    
+    // Starts an empty line at this ply, making room for the child's
+    fn clear_pv(&mut self, ply: usize) {
+        if self.pv.len() < ply + 2 {
+            self.pv.resize(ply + 2, Vec::new());
+        }
+        self.pv[ply].clear();
+    }
+    
+    // The line at this ply becomes the move followed by the child's line
+    fn update_pv(&mut self, ply: usize, mv: (u8, u8)) {
+        let (lines, child_lines) = self.pv.split_at_mut(ply + 1);
+        let line = &mut lines[ply];
+        line.clear();
+        line.push(mv);
+        line.extend_from_slice(&child_lines[0]);
+    }
+    
+    // Counts a visited node; the moves made so far give its ply. Every so
+    // often the stop flag and the clock are looked at
+    fn visit(&mut self) {
+        self.nodes += 1;
+        self.seldepth = self.seldepth.max(self.move_history.len());
+        if self.nodes.is_multiple_of(SEARCH_POLL_NODES) {
+            if let Some(stop) = self.stop {
+                self.stopped |= stop.load(Ordering::Relaxed);
+            }
+            if let Some((clock, limit)) = self.time_limit {
+                self.stopped |= clock.elapsed() >= limit;
+            }
+        }
+        if self.node_limit.is_some_and(|limit| self.nodes >= limit) {
+            self.stopped = true;
+        }
+    }
+    
     fn make_move(&mut self, from: u8, to: u8) -> bool {
         if let Some(mv) = make_move(&mut self.board, from, to) {
             self.move_history.push(mv);
@@ -1140,140 +1254,407 @@ impl SearchState {
     }
 }
 
+// Nodes between looks at the stop flag and the clock
+const SEARCH_POLL_NODES: u64 = 2048;
+
 //END OF MOVE HISTORY-------------------------------------------------------------------------------------
 
 
 //EVALUATION----------------------------------------------------------------------------------------------
 
-fn evaluate_board_fast(board: &BoardState) -> i32 {
-    // Use incremental evaluation
+/// Static evaluation from White's point of view. Use this for display only;
+/// the search must go through `evaluate`, which is side-to-move relative.
+fn evaluate_white(board: &BoardState) -> i32 {
+    if let Some(score) = evaluate_known_endgame(board) {
+        return score;
+    }
+    
+    let score = evaluate_material_and_position(board);
+    score * scale_factor(board, score) / SCALE_FACTOR_NORMAL
+}
+
+// Material plus piece-square tables, from White's point of view
+fn evaluate_material_and_position(board: &BoardState) -> i32 {
     let mut score = 0;
     
-    // Material evaluation using bit counting
+    // Material values
     const PIECE_VALUES: [i32; 6] = [100, 300, 300, 500, 900, 0]; // P, N, B, R, Q, K
+    const TABLES: [&[i32; 64]; 6] = [
+        &PAWN_TABLE, &KNIGHT_TABLE, &BISHOP_TABLE, &ROOK_TABLE, &QUEEN_TABLE, &KING_TABLE,
+    ];
     
-    for piece in 0..6 {
+    for (piece, (&value, table)) in PIECE_VALUES.iter().zip(TABLES).enumerate() {
         let white_count = count_bits(board.bitboards[piece]) as i32;
         let black_count = count_bits(board.bitboards[piece + 6]) as i32;
-        score += PIECE_VALUES[piece] * (white_count - black_count);
+        score += value * (white_count - black_count);
+        
+        let mut white_pieces = board.bitboards[piece];
+        while let Some(square) = get_lsb(white_pieces) {
+            clear_bit(&mut white_pieces, square);
+            // The tables are laid out from White's side with a8 first, which is
+            // exactly our square numbering (0=a8, 63=h1)
+            score += table[square as usize];
+        }
+        
+        let mut black_pieces = board.bitboards[piece + 6];
+        while let Some(square) = get_lsb(black_pieces) {
+            clear_bit(&mut black_pieces, square);
+            // Flip the rank only, so a black piece on e7 scores like a white one on e2
+            score -= table[(square ^ 56) as usize];
+        }
     }
     
-    // Piece-square tables using precomputed values
-    static mut WHITE_PST: [i32; 64] = [0; 64];
-    static mut BLACK_PST: [i32; 64] = [0; 64];
+    score
+}
+
+/// Static evaluation relative to the side to move: positive means the player
+/// about to move is better. This is the contract every negamax search relies on.
+fn evaluate(board: &BoardState) -> i32 {
+    let score = evaluate_white(board);
+    if board.white_to_move { score } else { -score }
+}
+
+//END OF EVALUATION---------------------------------------------------------------------------------------
+
+
+//ENDGAME EVALUATION--------------------------------------------------------------------------------------
+
+// Bonus for endgames that are won with correct play but too deep for the search
+// to see. Kept well below the mate threshold so a real mate is always preferred.
+const KNOWN_WIN: i32 = 2000;
+
+// Scale factors are applied as score * factor / SCALE_FACTOR_NORMAL
+const SCALE_FACTOR_DRAW: i32 = 0;
+const SCALE_FACTOR_NORMAL: i32 = 64;
+
+const LIGHT_SQUARES: u64 = 0xAA55_AA55_AA55_AA55; // a8 (square 0) is light
+const FILE_A: u64 = 0x0101_0101_0101_0101;
+const FILE_H: u64 = 0x8080_8080_8080_8080;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum KnownEndgame {
+    Kxk,  // Bare king against enough material to mate
+    Kbnk, // Bishop and knight mate
+    Kpk,  // King and pawn against king
+    Krkp, // Rook against pawn
+}
+
+// Piece counts, indexed like the bitboards
+fn material_counts(board: &BoardState) -> [u32; 12] {
+    let mut counts = [0; 12];
+    for (count, &bitboard) in counts.iter_mut().zip(board.bitboards.iter()) {
+        *count = count_bits(bitboard);
+    }
+    counts
+}
+
+fn non_pawn_material(counts: &[u32; 12], white: bool) -> i32 {
+    let base = if white { 0 } else { 6 };
+    (WN..=WQ).map(|piece| get_piece_value(base + piece) * counts[base + piece] as i32).sum()
+}
+
+fn square_distance(a: u8, b: u8) -> i32 {
+    let rank_distance = (a as i32 / 8 - b as i32 / 8).abs();
+    let file_distance = (a as i32 % 8 - b as i32 % 8).abs();
+    rank_distance.max(file_distance)
+}
+
+#[inline]
+fn is_light_square(square: u8) -> bool {
+    (square / 8 + square % 8).is_multiple_of(2)
+}
+
+// Bonus for driving a king away from the centre
+fn push_to_edge(square: u8) -> i32 {
+    let rank = (square / 8) as i32;
+    let file = (square % 8) as i32;
+    20 * ((3 - rank).max(rank - 4) + (3 - file).max(file - 4))
+}
+
+// Bonus for bringing the attacking king next to the defending one
+fn push_close(a: u8, b: u8) -> i32 {
+    140 - 20 * square_distance(a, b)
+}
+
+// Material signature dispatch: which dedicated evaluator, if any, applies when
+// the given side is the stronger one
+fn classify_endgame(counts: &[u32; 12], white_strong: bool) -> Option<KnownEndgame> {
+    let (strong, weak) = if white_strong { (0, 6) } else { (6, 0) };
+    let strong_pieces: u32 = counts[strong + WP..=strong + WQ].iter().sum();
+    let weak_pieces: u32 = counts[weak + WP..=weak + WQ].iter().sum();
     
-    unsafe {
-        // Precompute these once
-        for square in 0..64 {
-            let table_index = 63 - square as usize;
-            WHITE_PST[square] = PAWN_TABLE[table_index] + KNIGHT_TABLE[table_index] 
-                + BISHOP_TABLE[table_index] + ROOK_TABLE[table_index]
-                + QUEEN_TABLE[table_index] + KING_TABLE[table_index];
-            BLACK_PST[square] = -(PAWN_TABLE[square] + KNIGHT_TABLE[square] 
-                + BISHOP_TABLE[square] + ROOK_TABLE[square]
-                + QUEEN_TABLE[square] + KING_TABLE[square]);
+    if weak_pieces == 0 {
+        if strong_pieces == 1 && counts[strong + WP] == 1 {
+            return Some(KnownEndgame::Kpk);
+        }
+        if strong_pieces == 2 && counts[strong + WB] == 1 && counts[strong + WN] == 1 {
+            return Some(KnownEndgame::Kbnk);
+        }
+        if non_pawn_material(counts, white_strong) >= get_piece_value(WR) {
+            return Some(KnownEndgame::Kxk);
         }
     }
     
-    // Fast piece-square evaluation
-    for square in 0..64 {
-        let bit = 1u64 << square;
-        for piece in 0..6 {
-            if (board.bitboards[piece] & bit) != 0 {
-                unsafe { score += WHITE_PST[square]; }
-            }
-            if (board.bitboards[piece + 6] & bit) != 0 {
-                unsafe { score += BLACK_PST[square]; }
-            }
+    if strong_pieces == 1 && counts[strong + WR] == 1 && weak_pieces == 1 && counts[weak + WP] == 1 {
+        return Some(KnownEndgame::Krkp);
+    }
+    
+    None
+}
+
+/// Evaluates endgames recognised by their material signature. Returns White's
+/// score, or None when the generic evaluation should be used.
+fn evaluate_known_endgame(board: &BoardState) -> Option<i32> {
+    let counts = material_counts(board);
+    
+    for white_strong in [true, false] {
+        if let Some(endgame) = classify_endgame(&counts, white_strong) {
+            let score = match endgame {
+                KnownEndgame::Kxk => evaluate_kxk(board, &counts, white_strong),
+                KnownEndgame::Kbnk => evaluate_kbnk(board, white_strong),
+                // Pawn endings depend on the direction of play, so they are written
+                // with White as the stronger side and the board is flipped otherwise
+                KnownEndgame::Kpk | KnownEndgame::Krkp => {
+                    let normalized = if white_strong { *board } else { board.mirror() };
+                    if endgame == KnownEndgame::Kpk {
+                        evaluate_kpk(&normalized)
+                    } else {
+                        evaluate_krkp(&normalized)
+                    }
+                }
+            };
+            return Some(if white_strong { score } else { -score });
         }
     }
     
+    None
+}
+
+// A lone king to move with no safe square is stalemated. Cheaper than
+// generating moves, and exact as long as the king has no other pieces.
+fn bare_king_is_stalemated(board: &BoardState, white_king: bool) -> bool {
+    let in_check = if white_king { board.white_king_in_check } else { board.black_king_in_check };
+    if board.white_to_move != white_king || in_check {
+        return false;
+    }
+    
+    // Take the king off the board so sliders see through its square
+    let king = if white_king { WK } else { BK };
+    let mut bitboards = board.bitboards;
+    let square = get_lsb(bitboards[king]).unwrap();
+    bitboards[king] = 0;
+    
+    KING_ATTACKS.get().unwrap()[square as usize] & !complete_attacks_bitboard(&bitboards, !white_king) == 0
+}
+
+// Mop-up: drive the bare king to the edge and bring our own king closer
+fn evaluate_kxk(board: &BoardState, counts: &[u32; 12], white_strong: bool) -> i32 {
+    let (strong, weak) = if white_strong { (0, 6) } else { (6, 0) };
+    
+    // Knights alone can't force mate
+    if counts[strong + WP] == 0 && counts[strong + WB] == 0 && counts[strong + WR] == 0 && counts[strong + WQ] == 0 {
+        return 0;
+    }
+    
+    // Don't let the mop-up bonus walk us into stalemate
+    if bare_king_is_stalemated(board, !white_strong) {
+        return 0;
+    }
+    
+    let strong_king = get_lsb(board.bitboards[strong + WK]).unwrap();
+    let weak_king = get_lsb(board.bitboards[weak + WK]).unwrap();
+    
+    let mut score = non_pawn_material(counts, white_strong)
+        + get_piece_value(WP) * counts[strong + WP] as i32
+        + push_to_edge(weak_king)
+        + push_close(strong_king, weak_king);
+    
+    let bishops = board.bitboards[strong + WB];
+    let light_bishop = bishops & LIGHT_SQUARES != 0;
+    let dark_bishop = bishops & !LIGHT_SQUARES != 0;
+    if counts[strong + WQ] > 0 || counts[strong + WR] > 0
+        || (counts[strong + WB] > 0 && counts[strong + WN] > 0) || (light_bishop && dark_bishop) {
+        score += KNOWN_WIN;
+    }
+    
     score
 }
 
-fn evaluate_board_advanced(board: &BoardState) -> i32 {
-    let mut score = 0;
+// Bishop and knight: the king can only be mated in a corner of the bishop's colour
+fn evaluate_kbnk(board: &BoardState, white_strong: bool) -> i32 {
+    let (strong, weak) = if white_strong { (0, 6) } else { (6, 0) };
+    let strong_king = get_lsb(board.bitboards[strong + WK]).unwrap();
+    let weak_king = get_lsb(board.bitboards[weak + WK]).unwrap();
+    let bishop = get_lsb(board.bitboards[strong + WB]).unwrap();
+    
+    // a8 and h1 are light, a1 and h8 are dark
+    let corners: [u8; 2] = if is_light_square(bishop) { [0, 63] } else { [56, 7] };
+    let corner_distance = corners.iter()
+        .map(|&corner| square_distance(weak_king, corner))
+        .min()
+        .unwrap();
+    
+    KNOWN_WIN
+        + get_piece_value(WB) + get_piece_value(WN)
+        + push_close(strong_king, weak_king)
+        + 50 * (7 - corner_distance)
+}
+
+// King and pawn against king, using the rule of the square and key squares
+fn evaluate_kpk(board: &BoardState) -> i32 {
+    let strong_king = get_lsb(board.bitboards[WK]).unwrap();
+    let weak_king = get_lsb(board.bitboards[BK]).unwrap();
+    let pawn = get_lsb(board.bitboards[WP]).unwrap();
     
-    // Material values
-    const PIECE_VALUES: [i32; 12] = [
-        100,  // WP - Pawn
-        300,  // WN - Knight
-        300,  // WB - Bishop
-        500,  // WR - Rook
-        900,  // WQ - Queen
-        0,    // WK - King (not used in material count)
-        -100, // BP - Pawn
-        -300, // BN - Knight
-        -300, // BB - Bishop
-        -500, // BR - Rook
-        -900, // BQ - Queen
-        0,    // BK - King (not used in material count)
-    ];
+    let (row, file) = ((pawn / 8) as i32, pawn % 8);
+    let queening_square = file;
+    let weak_to_move = !board.white_to_move;
     
-    // Count material for each piece type 
-    for (piece_type, &value) in PIECE_VALUES.iter().enumerate() {
-        if value != 0 {
-            let count = count_bits(board.bitboards[piece_type]) as i32;
-            score += value * count;
-        }
+    let win = KNOWN_WIN + get_piece_value(WP) + 20 * (6 - row);
+    let drawish = 10 * (7 - row);
+    
+    // The generated bitbase is exact, the rules below are not
+    if let Some(wins) = retrograde::probe_kpk_bitbase(board) {
+        return if wins { win } else { 0 };
     }
     
-    // Piece-square table evaluation
-    for square in 0..64 {
-        // White pieces (positive values)
-        if get_bit(board.bitboards[WP], square) {
-            // For white pawns, we need to flip the square index because the table is from white's perspective but our board representation has a8=0, h1=63
-            let table_index = 63 - square as usize;
-            score += PAWN_TABLE[table_index];
-        }
-        if get_bit(board.bitboards[WN], square) {
-            let table_index = 63 - square as usize;
-            score += KNIGHT_TABLE[table_index];
-        }
-        if get_bit(board.bitboards[WB], square) {
-            let table_index = 63 - square as usize;
-            score += BISHOP_TABLE[table_index];
-        }
-        if get_bit(board.bitboards[WR], square) {
-            let table_index = 63 - square as usize;
-            score += ROOK_TABLE[table_index];
-        }
-        if get_bit(board.bitboards[WQ], square) {
-            let table_index = 63 - square as usize;
-            score += QUEEN_TABLE[table_index];
-        }
-        if get_bit(board.bitboards[WK], square) {
-            let table_index = 63 - square as usize;
-            score += KING_TABLE[table_index];
-        }
-        
-        // Black pieces (negative values)
-        if get_bit(board.bitboards[BP], square) {
-            // Black wants the opposite of what white wants
-            score -= PAWN_TABLE[square as usize];
-        }
-        if get_bit(board.bitboards[BN], square) {
-            score -= KNIGHT_TABLE[square as usize];
-        }
-        if get_bit(board.bitboards[BB], square) {
-            score -= BISHOP_TABLE[square as usize];
-        }
-        if get_bit(board.bitboards[BR], square) {
-            score -= ROOK_TABLE[square as usize];
+    if bare_king_is_stalemated(board, false) {
+        return 0;
+    }
+    
+    // The defender picks up a loose pawn
+    if weak_to_move && square_distance(weak_king, pawn) == 1 && square_distance(strong_king, pawn) > 1 {
+        return 0;
+    }
+    
+    // Promotion is always to a queen, which can stalemate a king boxed in
+    // beside the queening square
+    if row == 1 && !weak_to_move {
+        let queen_attacks = get_queen_attacks(queening_square, get_all_occupied(board.bitboards) & !(1 << pawn));
+        let escapes = KING_ATTACKS.get().unwrap()[weak_king as usize]
+            & !queen_attacks
+            & !KING_ATTACKS.get().unwrap()[strong_king as usize]
+            & !(1 << strong_king);
+        if queen_attacks & (1 << weak_king) == 0 && escapes == 0 {
+            return drawish;
         }
-        if get_bit(board.bitboards[BQ], square) {
-            score -= QUEEN_TABLE[square as usize];
+    }
+    
+    // Rule of the square: the defending king can't catch the pawn. Our own king
+    // standing in front of the pawn costs a tempo.
+    let mut pawn_moves = if row == 6 { 5 } else { row };
+    if strong_king % 8 == file && strong_king < pawn {
+        pawn_moves += 1;
+    }
+    if square_distance(weak_king, queening_square) - (weak_to_move as i32) > pawn_moves {
+        return win;
+    }
+    
+    // Otherwise a rook pawn is only won if the defender is cut off, which the
+    // search has to find on its own
+    if file == 0 || file == 7 {
+        return drawish;
+    }
+    
+    // The attacking king on a key square wins whatever the defence does
+    let key_rows = match row {
+        4..=6 => row - 2..=row - 2,
+        2..=3 => row - 2..=row - 1,
+        _ => 0..=row,
+    };
+    let king_row = (strong_king / 8) as i32;
+    let king_file = (strong_king % 8) as i32;
+    if key_rows.contains(&king_row) && (king_file - file as i32).abs() <= 1 {
+        return win;
+    }
+    
+    drawish
+}
+
+// Rook against pawn, following the usual rules about whose king is closer
+fn evaluate_krkp(board: &BoardState) -> i32 {
+    let strong_king = get_lsb(board.bitboards[WK]).unwrap();
+    let weak_king = get_lsb(board.bitboards[BK]).unwrap();
+    let rook = get_lsb(board.bitboards[WR]).unwrap();
+    let pawn = get_lsb(board.bitboards[BP]).unwrap();
+    
+    // The black pawn runs towards h1=63
+    let queening_square = 56 + pawn % 8;
+    let in_front_of_pawn = pawn + 8;
+    let rook_value = get_piece_value(WR);
+    
+    if strong_king % 8 == pawn % 8 && strong_king > pawn {
+        // Our king blocks the pawn
+        rook_value - square_distance(strong_king, pawn)
+    } else if square_distance(weak_king, pawn) >= 3 + (!board.white_to_move) as i32
+        && square_distance(weak_king, rook) >= 3 {
+        // The defending king is too far away to support its pawn
+        rook_value - square_distance(strong_king, pawn)
+    } else if weak_king / 8 >= 5 && square_distance(weak_king, pawn) == 1
+        && strong_king / 8 <= 4
+        && square_distance(strong_king, pawn) > 2 + board.white_to_move as i32 {
+        // Far advanced pawn supported by its king while ours is out of play
+        80 - 8 * square_distance(strong_king, pawn)
+    } else {
+        200 - 8 * (square_distance(strong_king, in_front_of_pawn)
+            - square_distance(weak_king, in_front_of_pawn)
+            - square_distance(pawn, queening_square))
+    }
+}
+
+// How convertible the advantage of the side that is ahead is. Drawish material
+// combinations pull the generic evaluation towards zero.
+fn scale_factor(board: &BoardState, score: i32) -> i32 {
+    if score == 0 {
+        return SCALE_FACTOR_NORMAL;
+    }
+    
+    let counts = material_counts(board);
+    let white_strong = score > 0;
+    let (strong, weak) = if white_strong { (0, 6) } else { (6, 0) };
+    let strong_material = non_pawn_material(&counts, white_strong);
+    let weak_material = non_pawn_material(&counts, !white_strong);
+    
+    // Without pawns, being up less than a rook is rarely enough to win
+    if counts[strong + WP] == 0 && strong_material - weak_material <= get_piece_value(WB) {
+        return if strong_material < get_piece_value(WR) { SCALE_FACTOR_DRAW } else { 8 };
+    }
+    
+    // Opposite-coloured bishops, as long as little else is left to play with.
+    // With queens or a second heavy piece about, the bishops don't decide much.
+    if counts[WB] == 1 && counts[BB] == 1
+        && (board.bitboards[WB] & LIGHT_SQUARES == 0) != (board.bitboards[BB] & LIGHT_SQUARES == 0) {
+        let bishops_only = non_pawn_material(&counts, true) == get_piece_value(WB)
+            && non_pawn_material(&counts, false) == get_piece_value(BB);
+        // At most one knight or rook next to each bishop
+        let one_more_piece = |base: usize| counts[base + WQ] == 0 && counts[base + WN] + counts[base + WR] <= 1;
+        if bishops_only {
+            return 32;
         }
-        if get_bit(board.bitboards[BK], square) {
-            score -= KING_TABLE[square as usize];
+        if one_more_piece(0) && one_more_piece(6) {
+            return 48;
         }
     }
     
+    // Bishop and rook pawns with the wrong-coloured queening corner
+    let pawns = board.bitboards[strong + WP];
+    let on_single_rook_file = pawns & !FILE_A == 0 || pawns & !FILE_H == 0;
+    if counts[strong + WB] == 1 && strong_material == get_piece_value(WB)
+        && weak_material == 0 && counts[weak + WP] == 0 && on_single_rook_file {
+        let file = get_lsb(pawns).unwrap() % 8;
+        let queening_square = if white_strong { file } else { 56 + file };
+        let bishop = get_lsb(board.bitboards[strong + WB]).unwrap();
+        let weak_king = get_lsb(board.bitboards[weak + WK]).unwrap();
+        if is_light_square(bishop) != is_light_square(queening_square)
+            && square_distance(weak_king, queening_square) <= 1 {
+            return SCALE_FACTOR_DRAW;
+        }
+    }
     
-    score
+    SCALE_FACTOR_NORMAL
 }
 
-//END OF EVALUATION---------------------------------------------------------------------------------------
+//END OF ENDGAME EVALUATION-------------------------------------------------------------------------------
 
 
 //TESTS---------------------------------------------------------------------------------------------------
@@ -1301,10 +1682,7 @@ fn test_performance() {
     
     let legal_moves: Vec<(u8, u8)> = moves.into_iter()
         .filter(|&(from, to)| {
-            let mut temp_state = SearchState {
-                board: board_state,
-                move_history: Vec::new(),
-            };
+            let mut temp_state = SearchState::new(board_state);
             
             if temp_state.make_move(from, to) {
                 let our_king_in_check = temp_state.board.white_king_in_check;
@@ -1323,21 +1701,21 @@ fn test_performance() {
     println!("\nTesting evaluation speed...");
     let start = std::time::Instant::now();
     for _ in 0..10000 {
-        let _ = evaluate_board_advanced(&board_state);
+        let _ = evaluate(&board_state);
     }
     let elapsed = start.elapsed();
     println!("10000 evaluations took: {:?}", elapsed);
     println!("Average per evaluation: {:?}", elapsed / 10000);
 }
 
-fn test_minimax() {
-    println!("\n=== Testing Minimax Algorithm ===");
+fn test_search() {
+    println!("\n=== Testing Search ===");
     
     let board_state = BoardState::new();
     
     // Test with depth 1
     println!("\nSearching for best move at depth 1...");
-    if let Some((from, to)) = find_best_move(&board_state, 1) {
+    if let Some((from, to)) = find_best_move_with_time(&board_state, 1, None) {
         println!("Best move found: {} -> {}", 
             square_to_coordinates(from), 
             square_to_coordinates(to));
@@ -1346,14 +1724,14 @@ fn test_minimax() {
         let mut new_state = board_state;
         make_move(&mut new_state, from, to);
         print_board(&new_state);
-        println!("Evaluation after move: {}", evaluate_board_advanced(&new_state));
+        println!("Evaluation after move: {}", evaluate_white(&new_state));
     } else {
         println!("No legal moves found!");
     }
     
     // Test with depth 2 (more thorough search)
     println!("\nSearching for best move at depth 2...");
-    if let Some((from, to)) = find_best_move(&board_state, 2) {
+    if let Some((from, to)) = find_best_move_with_time(&board_state, 2, None) {
         println!("Best move found: {} -> {}", 
             square_to_coordinates(from), 
             square_to_coordinates(to));
@@ -1361,7 +1739,7 @@ fn test_minimax() {
     
     // Test iterative deepening
     println!("\nSearching with iterative deepening (max depth 6, 5 second limit)...");
-    if let Some((from, to)) = find_best_move_iterative_deepening_optimized(&board_state, 6, 5000) {
+    if let Some((from, to)) = find_best_move_with_time(&board_state, 6, Some(Duration::from_secs(5))) {
         println!("Best move found: {} -> {}", 
             square_to_coordinates(from), 
             square_to_coordinates(to));
@@ -1371,7 +1749,7 @@ fn test_minimax() {
 
 fn test_evaluation() {
     let board = BoardState::new();
-    let score = evaluate_board_advanced(&board);
+    let score = evaluate_white(&board);
     println!("Initial position evaluation: {}", score);
     println!("(Should 0)");
     
@@ -1379,7 +1757,7 @@ fn test_evaluation() {
 
     clear_bit(&mut test_board.bitboards[BP], 8); // Remove a2 pawn
     
-    let test_score = evaluate_board_advanced(&test_board);
+    let test_score = evaluate_white(&test_board);
     println!("White up a pawn evaluation: {}", test_score);
     println!("(Should be around +100)");
     
@@ -1395,7 +1773,7 @@ fn test_evaluation() {
     // Place black knight on edge (bad square)
     set_bit(&mut knight_test.bitboards[BN], 7);  // h8
     
-    let knight_eval = evaluate_board_advanced(&knight_test);
+    let knight_eval = evaluate_white(&knight_test);
     println!("White knight in center vs black knight on edge: {}", knight_eval);
     println!("(Should show advantage for white due to better knight placement)");
 }
@@ -1514,7 +1892,7 @@ fn test_depth_x(depth: u8) {
     println!("\nTest 1: Initial Position (White to move)");
     let start = std::time::Instant::now();
     
-    if let Some((from, to)) = find_best_move(&board_state, depth) {
+    if let Some((from, to)) = find_best_move_with_time(&board_state, depth, None) {
         let elapsed = start.elapsed();
         println!("Best move found: {} -> {}", 
             square_to_coordinates(from), 
@@ -1526,7 +1904,7 @@ fn test_depth_x(depth: u8) {
         if let Some(mv) = make_move(&mut new_state, from, to) {
             println!("\nPosition after move:");
             print_board(&new_state);
-            println!("Evaluation after move: {}", evaluate_board_advanced(&new_state));
+            println!("Evaluation after move: {}", evaluate_white(&new_state));
             
             // Check if move is a capture
             if let Some(captured) = mv.captured_piece {
@@ -1542,17 +1920,16 @@ fn test_depth_x(depth: u8) {
     // Test 2: After 1.e4
     println!("\nTest 2: After 1.e4 (Black to move)");
     let mut after_e4 = BoardState::new();
-    if let Some(_) = make_move(&mut after_e4, 52, 36) { // e2-e4
+    if make_move(&mut after_e4, 52, 36).is_some() { // e2-e4
         let start = std::time::Instant::now();
         
-        if let Some((from, to)) = find_best_move(&after_e4, 6) {
+        if let Some((from, to)) = find_best_move_with_time(&after_e4, 6, None) {
             let elapsed = start.elapsed();
             println!("Best move found: {} -> {}", 
                 square_to_coordinates(from), 
                 square_to_coordinates(to));
             println!("Search time: {:?}", elapsed);
             
-            let move_names = ["e2-e4", "Nf3", "Nc3", "Bc4", "Bb5", "d3", "f4", "g3"];
             println!("Common opening moves at this position:");
             println!("- e2-e4 (already played)");
             println!("- Ng1-f3 (Knight to f3)");
@@ -1580,7 +1957,7 @@ fn test_depth_x(depth: u8) {
     print_board(&scholars_mate);
     
     let start = std::time::Instant::now();
-    if let Some((from, to)) = find_best_move(&scholars_mate, 6) {
+    if let Some((from, to)) = find_best_move_with_time(&scholars_mate, 6, None) {
         let elapsed = start.elapsed();
         println!("Best move found: {} -> {}", 
             square_to_coordinates(from), 
@@ -1595,27 +1972,8 @@ fn test_depth_x(depth: u8) {
         }
     }
     
-    // Test 4: Compare search algorithms
-    println!("\nTest 4: Algorithm Comparison at Depth 4");
-    let start_basic = std::time::Instant::now();
-    let basic_move = find_best_move(&board_state, 4);
-    let basic_time = start_basic.elapsed();
-    
-    let start_optimized = std::time::Instant::now();
-    let optimized_move = find_best_move_iterative_deepening_optimized(&board_state, 4, 10000);
-    let optimized_time = start_optimized.elapsed();
-    
-    println!("Basic minimax (depth 4): {:?} in {:?}", basic_move, basic_time);
-    println!("Optimized (depth 4): {:?} in {:?}", optimized_move, optimized_time);
-    
-    if basic_move == optimized_move {
-        println!("✓ Both algorithms agree on best move!");
-    } else {
-        println!("⚠ Algorithms disagree on best move");
-    }
-    
-    // Test 5: Performance metrics
-    println!("\nTest 5: Performance Metrics");
+    // Test 4: Performance metrics
+    println!("\nTest 4: Performance Metrics");
     test_search_performance(6);
 }
 
@@ -1626,21 +1984,17 @@ fn test_search_performance(max_depth: u8) {
         println!("\nSearching at depth {}...", depth);
         let start = std::time::Instant::now();
         
-        if let Some((from, to)) = find_best_move(&board_state, depth) {
+        if let Some((from, to)) = find_best_move_with_time(&board_state, depth, None) {
             let elapsed = start.elapsed();
-            let nodes = estimate_nodes_searched(&board_state, depth);
-            let nps = (nodes as f64 / elapsed.as_secs_f64()) as u64;
             
             println!("  Best move: {} -> {}", 
                 square_to_coordinates(from), 
                 square_to_coordinates(to));
             println!("  Time: {:?}", elapsed);
-            println!("  Estimated nodes: {}", nodes);
-            println!("  Estimated NPS: {}/sec", nps);
             
             if depth > 1 {
                 let prev_start = std::time::Instant::now();
-                let _ = find_best_move(&board_state, depth - 1);
+                let _ = find_best_move_with_time(&board_state, depth - 1, None);
                 let prev_elapsed = prev_start.elapsed();
                 
                 if prev_elapsed.as_micros() > 0 {
@@ -1652,19 +2006,6 @@ fn test_search_performance(max_depth: u8) {
     }
 }
 
-// Simple node estimation (for demonstration)
-fn estimate_nodes_searched(board_state: &BoardState, depth: u8) -> u64 {
-    // Very rough estimation based on average branching factor
-    const AVERAGE_BRANCHING: f64 = 35.0; // Typical chess branching factor
-    
-    let mut total_nodes = 0.0;
-    for d in 0..depth {
-        total_nodes += AVERAGE_BRANCHING.powf(d as f64);
-    }
-    
-    total_nodes as u64
-}
-
 // Also add a function to test move generation at depth 6
 fn test_move_generation_depth_6() {
     println!("\n=== Testing Move Generation Tree at Depth 6 ===");
@@ -1672,7 +2013,6 @@ fn test_move_generation_depth_6() {
     let board_state = BoardState::new();
     
     // Count moves at each depth
-    let mut total_positions = 0u64;
     let mut depth_counts = vec![0u64; 7]; // Depth 0 to 6
     
     // Recursive function to count positions
@@ -1702,15 +2042,15 @@ fn test_move_generation_depth_6() {
     }
     
     let start = std::time::Instant::now();
-    total_positions = count_positions(&board_state, 0, 6, &mut depth_counts);
+    let total_positions = count_positions(&board_state, 0, 6, &mut depth_counts);
     let elapsed = start.elapsed();
     
     println!("Total positions at depth 6: {}", total_positions);
     println!("Time to count: {:?}", elapsed);
     
     // Print counts per depth
-    for depth in 0..=6 {
-        println!("  Depth {}: {} positions", depth, depth_counts[depth]);
+    for (depth, count) in depth_counts.iter().enumerate() {
+        println!("  Depth {}: {} positions", depth, count);
     }
     
     // Compare with known chess statistics
@@ -1733,7 +2073,7 @@ fn play_game() {
             println!("White to move.");
             
             // Let the engine play for white
-            if let Some((from, to)) = find_best_move(&board_state, 2) {
+            if let Some((from, to)) = find_best_move_with_time(&board_state, 2, None) {
                 println!("Engine plays: {} -> {}", 
                     square_to_coordinates(from), 
                     square_to_coordinates(to));
@@ -1747,7 +2087,7 @@ fn play_game() {
             println!("Black to move.");
             
             // Let the engine play for black
-            if let Some((from, to)) = find_best_move(&board_state, 2) {
+            if let Some((from, to)) = find_best_move_with_time(&board_state, 2, None) {
                 println!("Engine plays: {} -> {}", 
                     square_to_coordinates(from), 
                     square_to_coordinates(to));
@@ -1781,7 +2121,7 @@ fn play_game() {
     
     println!("\nFinal position:");
     print_board(&board_state);
-    println!("Final evaluation: {}", evaluate_board_advanced(&board_state));
+    println!("Final evaluation: {}", evaluate_white(&board_state));
 }
 
 fn benchmark_search() {
@@ -1798,7 +2138,7 @@ fn benchmark_search() {
         
         for depth in [3, 4, 5] {
             let start = std::time::Instant::now();
-            if let Some((from, to)) = find_best_move(&position, depth) {
+            if let Some((from, to)) = find_best_move_with_time(position, depth, None) {
                 let elapsed = start.elapsed();
                 println!("  Depth {}: {} -> {} in {:?}", 
                     depth,
@@ -1833,6 +2173,162 @@ fn create_sicilian_position() -> BoardState {
     board
 }
 
+// Counts leaf nodes of the legal move tree
+fn perft(board: &BoardState, depth: u8) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    
+    let moves = generate_legal_moves(board);
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+    
+    let mut nodes = 0;
+    for (from, to) in moves {
+        let mut next = *board;
+        if make_move(&mut next, from, to).is_some() {
+            nodes += perft(&next, depth - 1);
+        }
+    }
+    nodes
+}
+
+// Every root move that scores best for the side to move. Each is searched
+// with a window just below the best so far, so moves that tie are scored
+// exactly and the set found doesn't depend on move ordering.
+fn best_root_moves(board: &BoardState, depth: u8) -> Vec<(u8, u8)> {
+    let mut search_state = SearchState::new(*board);
+    let tt = TranspositionTable::new(1);
+    let mut best = -INFINITE;
+    let mut best_moves = Vec::new();
+    
+    for (from, to) in generate_legal_moves(board) {
+        search_state.make_move(from, to);
+        let score = -negamax_enhanced(&mut search_state, depth as i32 - 1, -INFINITE, 1 - best, 1, &tt);
+        search_state.unmake_move();
+        if score > best {
+            best = score;
+            best_moves.clear();
+        }
+        if score == best {
+            best_moves.push((from, to));
+        }
+    }
+    best_moves.sort_unstable();
+    best_moves
+}
+
+// Checks that a position and its colour-mirrored twin are treated identically:
+// same perft counts, negated White-relative evaluation and the same best moves.
+fn check_mirror_symmetry(board: &BoardState, perft_depth: u8, search_depth: u8) -> Result<(), String> {
+    let mirrored = board.mirror();
+    
+    if mirrored.mirror() != *board {
+        return Err("mirroring twice does not give back the original position".to_string());
+    }
+    
+    for depth in 1..=perft_depth {
+        let (original, flipped) = (perft(board, depth), perft(&mirrored, depth));
+        if original != flipped {
+            return Err(format!("perft({}) differs: {} vs {}", depth, original, flipped));
+        }
+    }
+    
+    if evaluate_white(board) != -evaluate_white(&mirrored) {
+        return Err(format!("evaluation is not antisymmetric: {} vs {}",
+            evaluate_white(board), evaluate_white(&mirrored)));
+    }
+    if evaluate(board) != evaluate(&mirrored) {
+        return Err(format!("side-to-move evaluation differs: {} vs {}",
+            evaluate(board), evaluate(&mirrored)));
+    }
+    
+    let mut expected: Vec<(u8, u8)> = best_root_moves(board, search_depth).into_iter()
+        .map(|(from, to)| (mirror_square(from), mirror_square(to)))
+        .collect();
+    expected.sort_unstable();
+    let actual = best_root_moves(&mirrored, search_depth);
+    if expected != actual {
+        let to_uci = |moves: &[(u8, u8)]| moves.iter()
+            .map(|&(from, to)| move_to_uci(from, to))
+            .collect::<Vec<_>>()
+            .join(" ");
+        return Err(format!("best moves differ: [{}] mirrored vs [{}]", to_uci(&expected), to_uci(&actual)));
+    }
+    
+    Ok(())
+}
+
+const SYMMETRY_TEST_FENS: [&str; 20] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq -",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - -",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq -",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ -",
+    "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - -",
+    "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq -",
+    "rnbqkb1r/pp1p1ppp/4pn2/2p5/2PP4/2N5/PP2PPPP/R1BQKBNR w KQkq -",
+    "2kr3r/pp1q1ppp/2n1bn2/2bpp3/4P3/2NP1N2/PPPQBPPP/R1B2RK1 b - -",
+    "4k3/8/8/3q4/8/2N5/8/4K3 w - -",
+    "6k1/5ppp/8/8/8/8/1Q3PPP/6K1 b - -",
+    "8/8/4k3/8/2K5/8/3R4/8 w - -",
+    "8/5k2/8/8/8/8/2QK4/8 b - -",
+    "8/8/3k4/8/8/3K4/3P4/8 w - -",
+    "r1b1k2r/ppppnppp/2n2q2/2b5/3NP3/2P1B3/PP3PPP/RN1QKB1R w KQkq -",
+    "3r2k1/p4ppp/1p6/2pR4/2P5/1P4P1/P4P1P/6K1 b - -",
+    "8/8/8/4k3/8/8/8/KBN5 w - -",
+    "8/8/8/8/5k2/8/2p5/K3R3 b - -",
+    "4k3/8/2b5/3p2p1/3P2P1/4B3/8/4K3 w - -",
+    "7k/8/6KP/8/8/8/8/5B2 w - -",
+];
+
+// Random playouts from the fixed positions give a larger, reproducible set
+fn symmetry_test_positions(playouts_per_fen: usize) -> Vec<BoardState> {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+    let mut positions = Vec::new();
+    
+    for fen in SYMMETRY_TEST_FENS {
+        let start = parse_fen(fen).unwrap();
+        positions.push(start);
+        
+        for _ in 0..playouts_per_fen {
+            let mut board = start;
+            let plies = rng.random_range(1..=12);
+            for _ in 0..plies {
+                let moves = generate_legal_moves(&board);
+                if moves.is_empty() {
+                    break;
+                }
+                let (from, to) = moves[rng.random_range(0..moves.len())];
+                make_move(&mut board, from, to);
+            }
+            positions.push(board);
+        }
+    }
+    
+    positions
+}
+
+fn test_symmetry() {
+    println!("\n=== Testing Colour Symmetry ===");
+    
+    let positions = symmetry_test_positions(8);
+    let mut failures = 0;
+    
+    for board in &positions {
+        if let Err(message) = check_mirror_symmetry(board, 3, 2) {
+            failures += 1;
+            print_board(board);
+            println!("Symmetry broken: {}", message);
+        }
+    }
+    
+    println!("Checked {} positions, {} failures", positions.len(), failures);
+    assert_eq!(failures, 0, "Engine treats mirrored positions differently!");
+}
+
 //END OF TESTS--------------------------------------------------------------------------------------------
 
 
@@ -1849,25 +2345,25 @@ struct ZobristTables {
 
 impl ZobristTables {
     fn new() -> Self {
-        let mut rng = rand::thread_rng();
+        let mut rng = rand::rng();
         let mut piece_square = [[0u64; 64]; 12];
         let mut castling_rights = [0u64; 4];
         let mut en_passant_file = [0u64; 8];
         
-        for piece in 0..12 {
-            for square in 0..64 {
-                piece_square[piece][square] = rng.r#gen::<u64>();
+        for squares in piece_square.iter_mut() {
+            for key in squares.iter_mut() {
+                *key = rng.random::<u64>();
             }
         }
         
-        let black_to_move = rng.r#gen::<u64>();
+        let black_to_move = rng.random::<u64>();
         
-        for i in 0..4 {
-            castling_rights[i] = rng.r#gen::<u64>();
+        for key in castling_rights.iter_mut() {
+            *key = rng.random::<u64>();
         }
         
-        for i in 0..8 {
-            en_passant_file[i] = rng.r#gen::<u64>();
+        for key in en_passant_file.iter_mut() {
+            *key = rng.random::<u64>();
         }
         
         Self {
@@ -1879,7 +2375,7 @@ impl ZobristTables {
     }
     
     fn get() -> &'static Self {
-        ZOBRIST_TABLES.get_or_init(|| Self::new())
+        ZOBRIST_TABLES.get_or_init(Self::new)
     }
 }
 
@@ -1929,528 +2425,104 @@ fn compute_board_hash(board: &BoardState) -> u64 {
 
 
 //MINIMAX SEARCH------------------------------------------------------------------------------------------
-static DEBUG: bool = false;
-
+// Scores stay within [-INFINITE, INFINITE], so they can always be negated.
+// Being mated at ply p scores -MATE + p; everything beyond MATE_BOUND in
+// either direction is a forced mate.
+const MATE: i32 = 32000;
+const INFINITE: i32 = MATE + 1;
+const MAX_PLY: i32 = 128;
+const MATE_BOUND: i32 = MATE - MAX_PLY;
+
+fn is_mate_score(score: i32) -> bool {
+    score.abs() >= MATE_BOUND
+}
 
-fn find_best_move(board_state: &BoardState, depth: u8) -> Option<(u8, u8)> {
-    if DEBUG {
-        println!("\n=== Starting search at depth {} ===", depth);
-        println!("Position evaluation: {}", evaluate_board_advanced(board_state));
+// Mate scores count plies from the root, but the TT is shared between
+// positions at any ply, so entries store the distance from the node itself
+fn score_to_tt(score: i32, ply: usize) -> i32 {
+    if score >= MATE_BOUND {
+        score + ply as i32
+    } else if score <= -MATE_BOUND {
+        score - ply as i32
+    } else {
+        score
     }
-    
-    let mut search_state = SearchState {
-        board: *board_state,
-        move_history: Vec::new(),
-    };
-    
-    let mut best_move = None;
-    let mut best_value = if board_state.white_to_move { i32::MIN } else { i32::MAX };
-    
-    // Generate all legal moves
-    let moves = generate_moves(search_state.board.bitboards, search_state.board.white_to_move, &search_state.board);
-    
-    if DEBUG {
-        println!("[Root] Raw moves generated: {}", moves.len());
+}
+
+fn score_from_tt(score: i32, ply: usize) -> i32 {
+    if score >= MATE_BOUND {
+        score - ply as i32
+    } else if score <= -MATE_BOUND {
+        score + ply as i32
+    } else {
+        score
     }
-    
-    // Filter for legal moves (those that don't leave king in check)
-    let legal_moves: Vec<(u8, u8)> = moves.into_iter()
-        .filter(|&(from, to)| {
-            let mut temp_state = SearchState {
-                board: search_state.board,
-                move_history: Vec::new(),
-            };
-            
-            // Try to make the move
-            if temp_state.make_move(from, to) {
-                // Check if the move leaves our own king in check
-                let our_king_in_check = if search_state.board.white_to_move {
-                    temp_state.board.white_king_in_check
-                } else {
-                    temp_state.board.black_king_in_check
-                };
-                
-                // The move is legal if it doesn't leave our king in check
-                !our_king_in_check
-            } else {
-                false
-            }
-        })
-        .collect();
-    
-    if DEBUG {
-        println!("[Root] Legal moves: {}", legal_moves.len());
-        println!("[Root] Moves list:");
-        for &(from, to) in &legal_moves {
-            println!("  {} -> {}", square_to_coordinates(from), square_to_coordinates(to));
-        }
-    }
-    
-    if legal_moves.is_empty() {
-        if DEBUG {
-            println!("[Root] No legal moves found!");
-        }
-        return None;
-    }
-    
-    // Order moves at root
-    let ordered_moves = order_moves(&search_state.board, &legal_moves);
-    
-    if DEBUG {
-        println!("[Root] Ordered moves (top 10):");
-        for (i, (score, from, to)) in ordered_moves.iter().take(10).enumerate() {
-            println!("  {}. {} -> {} (score: {})", 
-                i + 1, square_to_coordinates(*from), square_to_coordinates(*to), score);
-        }
-    }
-    
-    let mut move_counter = 0;
-    for (_, from, to) in &ordered_moves {
-        move_counter += 1;
-        
-        if DEBUG {
-            println!("\n[Root] Evaluating move {} of {}: {} -> {}", 
-                move_counter, ordered_moves.len(), 
-                square_to_coordinates(*from), square_to_coordinates(*to));
-        }
-        
-        // Make the move
-        search_state.make_move(*from, *to);
-        
-        // Evaluate the position
-        let value = minimax(&mut search_state, depth as i32 - 1, i32::MIN, i32::MAX, !board_state.white_to_move);
-        
-        // Unmake the move
-        search_state.unmake_move();
-        
-        if DEBUG {
-            println!("[Root] Move {} -> {} evaluation: {}", 
-                square_to_coordinates(*from), square_to_coordinates(*to), value);
-        }
-        
-        if board_state.white_to_move {
-            // White wants to maximize the score
-            if value > best_value || best_move.is_none() {
-                if DEBUG && best_move.is_some() {
-                    println!("[Root] New best move! Old: {}, New: {}", best_value, value);
-                }
-                best_value = value;
-                best_move = Some((*from, *to));
-            }
-        } else {
-            // Black wants to minimize the score
-            if value < best_value || best_move.is_none() {
-                if DEBUG && best_move.is_some() {
-                    println!("[Root] New best move! Old: {}, New: {}", best_value, value);
-                }
-                best_value = value;
-                best_move = Some((*from, *to));
-            }
-        }
-    }
-    
-    if DEBUG {
-        if let Some((from, to)) = best_move {
-            println!("\n[Root] Best move found: {} -> {} with evaluation: {}", 
-                square_to_coordinates(from), square_to_coordinates(to), best_value);
-        }
-        println!("=== End search at depth {} ===\n", depth);
-    }
-    
-    best_move
 }
 
-fn find_best_move_iterative_deepening_optimized(
-    board_state: &BoardState, 
-    max_depth: u8, 
-    _time_limit_ms: u64  // Prefix with underscore since it's unused
-) -> Option<(u8, u8)> {
-    let mut tt = TranspositionTable::new(16);
-    let mut best_move = None;
-    let mut alpha = i32::MIN + 1;
-    let mut beta = i32::MAX - 1;
-    
-    for depth in 1..=max_depth {
-        let window = 50;
-        
-        // Fix: Add the missing ply parameter (0 for root)
-        let score = negamax_root(
-            board_state,
-            depth as i32,
-            alpha,
-            beta,
-            0,  // ply parameter for root
-            &mut tt,
-        );
-        
-        if score <= alpha {
-            alpha = i32::MIN + 1;
-            beta = score + window;
-        } else if score >= beta {
-            alpha = score - window;
-            beta = i32::MAX - 1;
-        } else {
-            alpha = score - window;
-            beta = score + window;
-            best_move = get_best_move_from_tt(board_state, &tt);
-        }
+/// UCI form of a score: `cp N`, or `mate N` in moves, negative when we are
+/// the side getting mated.
+fn uci_score(score: i32) -> String {
+    if score >= MATE_BOUND {
+        format!("mate {}", (MATE - score + 1) / 2)
+    } else if score <= -MATE_BOUND {
+        format!("mate -{}", (MATE + score) / 2)
+    } else {
+        format!("cp {}", score)
     }
-    
-    best_move
 }
 
-/// Minimax algorithm with alpha-beta pruning
-fn minimax(
-    search_state: &mut SearchState,
-    depth: i32,
-    mut alpha: i32,
-    mut beta: i32,
-    maximizing_player: bool,
-) -> i32 {
-    // Base case: reached maximum depth or terminal position
-    if depth == 0 {
-        if DEBUG && depth == 0 {
-            println!("  [Leaf node reached, evaluating position]");
-        }
-        return evaluate_board_advanced(&search_state.board);
-    }
-    
-    // Generate legal moves for the current position
-    let moves = generate_moves(
-        search_state.board.bitboards,
-        search_state.board.white_to_move,
-        &search_state.board,
-    );
-    
-    if DEBUG {
-        println!("  [Depth {}] Raw moves generated: {}", depth, moves.len());
-    }
-    
-    // Filter for legal moves (those that don't leave king in check)
-    let legal_moves: Vec<(u8, u8)> = moves.into_iter()
-        .filter(|&(from, to)| {
-            let mut temp_state = SearchState {
-                board: search_state.board,
-                move_history: Vec::new(),
-            };
-            
-            if temp_state.make_move(from, to) {
-                let our_king_in_check = if search_state.board.white_to_move {
-                    temp_state.board.white_king_in_check
-                } else {
-                    temp_state.board.black_king_in_check
-                };
-                !our_king_in_check
-            } else {
-                false
-            }
-        })
-        .collect();
-    
-    if DEBUG {
-        println!("  [Depth {}] Legal moves after check: {}", depth, legal_moves.len());
-        
-        // Print first few moves for debugging
-        if depth >= 3 && legal_moves.len() > 0 {
-            println!("    First 5 moves:");
-            for &(from, to) in legal_moves.iter().take(5) {
-                println!("      {} -> {}", square_to_coordinates(from), square_to_coordinates(to));
-            }
-            if legal_moves.len() > 5 {
-                println!("      ... and {} more", legal_moves.len() - 5);
-            }
-        }
-    }
-    
-    let mut moves_with_scores: Vec<(i32, u8, u8)> = order_moves(&search_state.board, &legal_moves);
-    
-    if DEBUG && depth >= 3 && moves_with_scores.len() > 0 {
-        println!("    After ordering - top 5 moves:");
-        for (score, from, to) in moves_with_scores.iter().take(5) {
-            println!("      {} -> {} (score: {})", 
-                square_to_coordinates(*from), 
-                square_to_coordinates(*to), 
-                score);
-        }
-    }
-    
-    // Check for terminal positions
-    if legal_moves.is_empty() {
-        // No legal moves - checkmate or stalemate
-        let in_check = if search_state.board.white_to_move {
-            search_state.board.white_king_in_check
-        } else {
-            search_state.board.black_king_in_check
-        };
-        
-        if in_check {
-            // Checkmate - return very bad score for the player who is checkmated
-            return if search_state.board.white_to_move {
-                // White is checkmated - very bad for white
-                i32::MIN + 1
-            } else {
-                // Black is checkmated - very good for white
-                i32::MAX - 1
-            };
-        } else {
-            // Stalemate - draw
-            return 0;
-        }
-    }
-    
-    if maximizing_player {
-        // Maximizing player (white in this context)
-        let mut max_eval = i32::MIN;
-        let mut moves_examined = 0;
-        let mut pruned_moves = 0;
-        
-        for (_, from, to) in &moves_with_scores {
-            moves_examined += 1;
-            
-            // Make the move
-            search_state.make_move(*from, *to);
-            
-            if DEBUG && depth >= 3 {
-                println!("    [Depth {}] Examining move {}: {} -> {}", 
-                    depth, moves_examined, square_to_coordinates(*from), square_to_coordinates(*to));
-            }
-            
-            // Recursively evaluate
-            let eval = minimax(search_state, depth - 1, alpha, beta, false);
-            
-            // Unmake the move
-            search_state.unmake_move();
-            
-            // Update max evaluation
-            max_eval = max_eval.max(eval);
-            
-            if DEBUG && depth >= 3 {
-                println!("    [Depth {}] Move {} -> {} evaluation: {} (alpha: {}, beta: {})", 
-                    depth, square_to_coordinates(*from), square_to_coordinates(*to), eval, alpha, beta);
-            }
-            
-            // Alpha-beta pruning
-            alpha = alpha.max(eval);
-            if beta <= alpha {
-                if DEBUG && depth >= 3 {
-                    println!("    [Depth {}] BETA CUTOFF after move {} -> {} (beta: {}, alpha: {})", 
-                        depth, square_to_coordinates(*from), square_to_coordinates(*to), beta, alpha);
-                }
-                pruned_moves = moves_with_scores.len() - moves_examined;
-                break; // Beta cutoff
-            }
-        }
-        
-        if DEBUG && depth >= 3 {
-            println!("  [Depth {}] Examined {} moves, pruned {} moves, max_eval: {}", 
-                depth, moves_examined, pruned_moves, max_eval);
-        }
-        
-        max_eval
+// XBoard shows a mate in N moves as 100000 + N
+fn xboard_score(score: i32) -> i32 {
+    if score >= MATE_BOUND {
+        100000 + (MATE - score + 1) / 2
+    } else if score <= -MATE_BOUND {
+        -100000 - (MATE + score) / 2
     } else {
-        // Minimizing player (black in this context)
-        let mut min_eval = i32::MAX;
-        let mut moves_examined = 0;
-        let mut pruned_moves = 0;
-        
-        for (_, from, to) in &moves_with_scores {
-            moves_examined += 1;
-            
-            // Make the move
-            search_state.make_move(*from, *to);
-            
-            if DEBUG && depth >= 3 {
-                println!("    [Depth {}] Examining move {}: {} -> {}", 
-                    depth, moves_examined, square_to_coordinates(*from), square_to_coordinates(*to));
-            }
-            
-            // Recursively evaluate
-            let eval = minimax(search_state, depth - 1, alpha, beta, true);
-            
-            // Unmake the move
-            search_state.unmake_move();
-            
-            // Update min evaluation
-            min_eval = min_eval.min(eval);
-            
-            if DEBUG && depth >= 3 {
-                println!("    [Depth {}] Move {} -> {} evaluation: {} (alpha: {}, beta: {})", 
-                    depth, square_to_coordinates(*from), square_to_coordinates(*to), eval, alpha, beta);
-            }
-            
-            // Alpha-beta pruning
-            beta = beta.min(eval);
-            if beta <= alpha {
-                if DEBUG && depth >= 3 {
-                    println!("    [Depth {}] ALPHA CUTOFF after move {} -> {} (beta: {}, alpha: {})", 
-                        depth, square_to_coordinates(*from), square_to_coordinates(*to), beta, alpha);
-                }
-                pruned_moves = moves_with_scores.len() - moves_examined;
-                break; // Alpha cutoff
-            }
-        }
-        
-        if DEBUG && depth >= 3 {
-            println!("  [Depth {}] Examined {} moves, pruned {} moves, min_eval: {}", 
-                depth, moves_examined, pruned_moves, min_eval);
-        }
-        
-        min_eval
+        score
     }
 }
 
+// Tablebase wins are certain but the distance to mate is unknown, so they
+// score below any mate the search finds itself
+const TB_WIN_SCORE: i32 = 8000;
 
-
-/// Quiescence search - extends search in capture positions to avoid horizon effect
-fn quiescence_search(
-    search_state: &mut SearchState,
-    mut alpha: i32,
-    beta: i32,
-    maximizing_player: bool,
-) -> i32 {
-    // First, get a stand-pat evaluation
-    let stand_pat = evaluate_board_advanced(&search_state.board);
-    
-    if maximizing_player {
-        if stand_pat >= beta {
-            return beta;
-        }
-        if alpha < stand_pat {
-            alpha = stand_pat;
-        }
-    } else {
-        if stand_pat <= alpha {
-            return alpha;
-        }
-        if beta > stand_pat {
-            alpha = stand_pat;
-        }
-    }
-    
-    // Generate only capture moves
-    let capture_moves = generate_capture_moves(search_state);
-    
-    for &(from, to) in &capture_moves {
-        // Make the move
-        search_state.make_move(from, to);
-        
-        // Recursively evaluate captures
-        let score = -quiescence_search(search_state, -beta, -alpha, !maximizing_player);
-        
-        // Unmake the move
-        search_state.unmake_move();
-        
-        if maximizing_player {
-            if score >= beta {
-                return beta;
-            }
-            if score > alpha {
-                alpha = score;
-            }
-        } else {
-            if score <= alpha {
-                return alpha;
-            }
-            if score < beta {
-                alpha = score;
-            }
-        }
+fn tb_score(wdl: syzygy::Wdl, ply: usize) -> i32 {
+    match wdl {
+        syzygy::Wdl::Win => TB_WIN_SCORE - ply as i32,
+        syzygy::Wdl::Loss => -TB_WIN_SCORE + ply as i32,
+        // Cursed wins and blessed losses are draws under the fifty-move rule
+        _ => 0,
     }
-    
-    alpha
 }
 
-/// Helper function to generate only capture moves
-fn generate_capture_moves(search_state: &SearchState) -> Vec<(u8, u8)> {
-    let mut capture_moves = Vec::new();
-    
-    // Get all moves
-    let all_moves = generate_moves(
-        search_state.board.bitboards,
-        search_state.board.white_to_move,
-        &search_state.board,
-    );
-    
-    // Filter for captures
-    for &(from, to) in &all_moves {
-        // Check if this move captures a piece
-        let enemy_start = if search_state.board.white_to_move { 6 } else { 0 };
-        let enemy_end = if search_state.board.white_to_move { 12 } else { 6 };
-        
-        let mut is_capture = false;
-        for piece_type in enemy_start..enemy_end {
-            if get_bit(search_state.board.bitboards[piece_type], to) {
-                is_capture = true;
-                break;
-            }
-        }
-        
-        if is_capture {
-            // Also check that the move is legal (doesn't leave king in check)
-            let mut temp_state = SearchState {
-                board: search_state.board,
-                move_history: Vec::new(),
-            };
-            
-            if temp_state.make_move(from, to) {
-                let our_king_in_check = if search_state.board.white_to_move {
-                    temp_state.board.white_king_in_check
-                } else {
-                    temp_state.board.black_king_in_check
-                };
-                
-                if !our_king_in_check {
-                    capture_moves.push((from, to));
-                }
-            }
-        }
+// Generated tables know the distance to mate, so shorter mates score higher
+fn dtm_score(dtm: retrograde::Dtm, ply: usize) -> i32 {
+    match dtm {
+        retrograde::Dtm::Win(plies) => TB_WIN_SCORE - (ply as i32 + plies as i32),
+        retrograde::Dtm::Loss(plies) => -TB_WIN_SCORE + ply as i32 + plies as i32,
+        retrograde::Dtm::Draw => 0,
     }
-    
-    capture_moves
 }
 
 
-fn minimax_with_quiescence(
-    search_state: &mut SearchState,
-    depth: i32,
-    alpha: i32,
-    beta: i32,
-    maximizing_player: bool,
-) -> i32 {
-    if depth == 0 {
-        // Use quiescence search instead of static evaluation
-        return quiescence_search(search_state, alpha, beta, search_state.board.white_to_move);
-    }
-    
-    // ... rest of the minimax function remains the same as above ...
-    // You can copy the rest of the minimax function here and modify the base case
-    
-    // For now, let's just call the regular minimax
-    minimax(search_state, depth, alpha, beta, maximizing_player)
-}
-
+/// Quiescence search - extends search in capture positions to avoid horizon effect.
+/// Negamax form: alpha, beta and the returned score are all relative to the side to move.
 fn quiescence_search_enhanced(
     search_state: &mut SearchState,
     mut alpha: i32,
     beta: i32,
-    maximizing_player: bool,
 ) -> i32 {
-    let stand_pat = evaluate_board_fast(&search_state.board);
+    search_state.visit();
+    if search_state.stopped {
+        return 0;
+    }
+    let stand_pat = evaluate(&search_state.board);
     
-    if maximizing_player {
-        if stand_pat >= beta {
-            return beta;
-        }
-        if alpha < stand_pat {
-            alpha = stand_pat;
-        }
-    } else {
-        if stand_pat <= alpha {
-            return alpha;
-        }
-        if beta > stand_pat {
-            alpha = stand_pat;
-        }
+    if stand_pat >= beta {
+        return beta;
+    }
+    if alpha < stand_pat {
+        alpha = stand_pat;
     }
     
     // Generate only capture moves (and checks)
@@ -2510,27 +2582,21 @@ fn quiescence_search_enhanced(
         })
         .collect();
     
-    scored_captures.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+    scored_captures.sort_unstable_by_key(|&(score, _, _)| std::cmp::Reverse(score));
     
     for &(_, from, to) in &scored_captures {
         search_state.make_move(from, to);
-    let score = -quiescence_search_enhanced(search_state, -beta, -alpha, !maximizing_player);
-    search_state.unmake_move();
+        let score = -quiescence_search_enhanced(search_state, -beta, -alpha);
+        search_state.unmake_move();
+        if search_state.stopped {
+            return 0;
+        }
         
-        if maximizing_player {
-            if score >= beta {
-                return beta;
-            }
-            if score > alpha {
-                alpha = score;
-            }
-        } else {
-            if score <= alpha {
-                return alpha;
-            }
-            if score < beta {
-                alpha = score;
-            }
+        if score >= beta {
+            return beta;
+        }
+        if score > alpha {
+            alpha = score;
         }
     }
     
@@ -2541,19 +2607,34 @@ fn negamax_enhanced(
     search_state: &mut SearchState,
     depth: i32,
     mut alpha: i32,
-    beta: i32,
+    mut beta: i32,
     ply: usize,
-    tt: &mut TranspositionTable,
+    tt: &TranspositionTable,
 ) -> i32  {
+    search_state.clear_pv(ply);
     if depth == 0 {
-        return quiescence_search_enhanced(search_state, alpha, beta, search_state.board.white_to_move);
+        return quiescence_search_enhanced(search_state, alpha, beta);
+    }
+    search_state.visit();
+    if search_state.stopped {
+        return 0;
+    }
+    
+    // Mate distance pruning: no line from here can beat mating at the next
+    // ply or do worse than being mated right now
+    if ply > 0 {
+        alpha = alpha.max(-MATE + ply as i32);
+        beta = beta.min(MATE - ply as i32 - 1);
+        if alpha >= beta {
+            return alpha;
+        }
     }
     
     let original_alpha = alpha;
     let hash = compute_board_hash(&search_state.board);
     
     // TT lookup with improved probing
-    if let Some((score, best_move)) = tt.probe(hash, depth, alpha, beta) {
+    if let Some((score, best_move)) = tt.probe(hash, depth, alpha, beta, ply) {
         // Store as killer move if it caused a cutoff
         if (score >= beta) && (best_move.0 != best_move.1) { // Not a null move
             add_killer_move(ply, best_move.0, best_move.1);
@@ -2561,82 +2642,64 @@ fn negamax_enhanced(
         return score;
     }
     
+    // Tablebase cutoff: the exact result makes further search pointless
+    if ply > 0 && let Some(wdl) = syzygy::probe_wdl(&search_state.board) {
+        return tb_score(wdl, ply);
+    }
+    if ply > 0 && let Some(dtm) = retrograde::probe(&search_state.board) {
+        return dtm_score(dtm, ply);
+    }
+    
     // Null move pruning (optional but effective)
     if depth >= 3 && !search_state.board.is_current_king_in_check() {
-    // Try a null move
-    search_state.board.white_to_move = !search_state.board.white_to_move;
-    // Fix: Add ply parameter
-    let null_score = -negamax_root(
-        &search_state.board,
-        depth - 1 - 2,
-        -beta,
-        -beta + 1,
-        ply + 1,  // Add ply parameter
-        tt,
-    );
-    search_state.board.white_to_move = !search_state.board.white_to_move;
-    
-    if null_score >= beta {
-        return beta;
+        // Try a null move
+        search_state.board.white_to_move = !search_state.board.white_to_move;
+        let null_score = -negamax_enhanced(search_state, depth - 1 - 2, -beta, -beta + 1, ply + 1, tt);
+        search_state.board.white_to_move = !search_state.board.white_to_move;
+        
+        if null_score >= beta && !search_state.stopped {
+            return beta;
+        }
     }
-}
-
     
-    let moves = generate_moves(
-        search_state.board.bitboards,
-        search_state.board.white_to_move,
-        &search_state.board,
-    );
     
-    // Filter for legal moves
-    let legal_moves: Vec<(u8, u8)> = moves.into_iter()
-        .filter(|&(from, to)| is_move_legal_fast(&search_state.board, from, to))
-        .collect();
-    
-    if legal_moves.is_empty() {
-        // Terminal position
-        let in_check = search_state.board.is_current_king_in_check();
-        return if in_check {
-            // Checkmate
-            i32::MIN + ply as i32 // Prefer checkmates earlier
-        } else {
-            0 // Stalemate
-        };
-    }
-    
-    let ordered_moves = order_moves(&search_state.board, &legal_moves);
-    
-    let mut best_score = i32::MIN + 1;
-    let mut best_move_found = (0, 0);
+    // Moves come a stage at a time, so a cutoff spares generating the rest
+    let mut picker = MovePicker::new(&search_state.board, tt.best_move(hash), ply);
     let mut moves_searched = 0;
+    let mut best_score = -INFINITE;
+    let mut best_move_found = (0, 0);
     
-    for &(_, from, to) in &ordered_moves {
+    while let Some((from, to)) = picker.next(&search_state.board) {
         search_state.make_move(from, to);
         let mut score;
-    
-    // Late Move Reduction (LMR)
-    if moves_searched >= 4 && depth >= 3 && 
-       !search_state.board.is_current_king_in_check() &&
-       get_piece_at_square(&search_state.board.bitboards, to).is_none() {
         
-        score = -negamax_enhanced(search_state, depth - 2, -alpha - 1, -alpha, ply + 1, tt);
-        if score > alpha {
-            // Research with full depth
-            score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, ply + 1, tt);
-        }
-    } else if moves_searched == 0 {
-        // Full window search for first move
-        score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, ply + 1, tt);
-    } else {
-        // Null window search for other moves
-        score = -negamax_enhanced(search_state, depth - 1, -alpha - 1, -alpha, ply + 1, tt);
-        if score > alpha && score < beta {
-            // Research with full window
+        // Late Move Reduction (LMR)
+        if moves_searched >= 4 && depth >= 3 && 
+           !search_state.board.is_current_king_in_check() &&
+           get_piece_at_square(&search_state.board.bitboards, to).is_none() {
+            
+            score = -negamax_enhanced(search_state, depth - 2, -alpha - 1, -alpha, ply + 1, tt);
+            if score > alpha {
+                // Research with full depth
+                score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, ply + 1, tt);
+            }
+        } else if moves_searched == 0 {
+            // Full window search for first move
             score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, ply + 1, tt);
+        } else {
+            // Null window search for other moves
+            score = -negamax_enhanced(search_state, depth - 1, -alpha - 1, -alpha, ply + 1, tt);
+            if score > alpha && score < beta {
+                // Research with full window
+                score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, ply + 1, tt);
+            }
         }
-    }
         
         search_state.unmake_move();
+        if search_state.stopped {
+            return 0;
+        }
+        moves_searched += 1;
         
         if score >= beta {
             // Beta cutoff - store as killer move
@@ -2646,7 +2709,7 @@ fn negamax_enhanced(
             }
             
             // Store in TT
-            tt.store(hash, depth, beta, 2, (from, to)); // Lower bound
+            tt.store(hash, depth, beta, 2, (from, to), ply); // Lower bound
             return beta;
         }
         
@@ -2655,10 +2718,20 @@ fn negamax_enhanced(
             best_move_found = (from, to);
             if score > alpha {
                 alpha = score;
+                search_state.update_pv(ply, (from, to));
             }
         }
-        
-        moves_searched += 1;
+    }
+    
+    if moves_searched == 0 {
+        // Terminal position
+        let in_check = search_state.board.is_current_king_in_check();
+        return if in_check {
+            // Checkmate
+            -MATE + ply as i32 // Prefer checkmates earlier
+        } else {
+            0 // Stalemate
+        };
     }
     
     // Determine TT flag
@@ -2670,26 +2743,91 @@ fn negamax_enhanced(
         0 // Exact
     };
     
-    tt.store(hash, depth, best_score, flag, best_move_found);
+    tt.store(hash, depth, best_score, flag, best_move_found, ply);
     best_score
 }
 
-fn negamax_root(
-    board_state: &BoardState,
+/// Searches the root moves in order with PVS and returns the best score and
+/// move. Fails soft: a score at or below alpha means every move failed low,
+/// at or above beta that the returned move refuted the window.
+fn search_root(
+    search_state: &mut SearchState,
+    root_moves: &[(u8, u8)],
     depth: i32,
-    alpha: i32,
+    mut alpha: i32,
     beta: i32,
-    ply: usize,  // Add this missing parameter
-    tt: &mut TranspositionTable,
-) -> i32 {
-    let mut search_state = SearchState {
-        board: *board_state,
-        move_history: Vec::new(),
-    };
-    // Fix: Add the missing ply parameter (0 for root)
-    negamax_enhanced(&mut search_state, depth, alpha, beta, ply, tt)
+    tt: &TranspositionTable,
+) -> (i32, (u8, u8)) {
+    let mut best_score = -INFINITE;
+    let mut best_move = root_moves[0];
+    search_state.clear_pv(0);
+    
+    for (moves_searched, &(from, to)) in root_moves.iter().enumerate() {
+        search_state.make_move(from, to);
+        let mut score;
+        if moves_searched == 0 {
+            score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, 1, tt);
+        } else {
+            score = -negamax_enhanced(search_state, depth - 1, -alpha - 1, -alpha, 1, tt);
+            if score > alpha && score < beta {
+                score = -negamax_enhanced(search_state, depth - 1, -beta, -alpha, 1, tt);
+            }
+        }
+        search_state.unmake_move();
+        if search_state.stopped {
+            break;
+        }
+        
+        if score > best_score {
+            best_score = score;
+            best_move = (from, to);
+            search_state.update_pv(0, (from, to));
+            if score >= beta {
+                break;
+            }
+            alpha = alpha.max(score);
+        }
+    }
+    
+    (best_score, best_move)
 }
 
+// Half-width of the first aspiration window around the previous score
+const ASPIRATION_WINDOW: i32 = 50;
+
+/// One iteration of iterative deepening. From depth 4 on the search starts
+/// with a narrow window around the previous score; when the result falls
+/// outside it, that side of the window is widened and the depth searched
+/// again, so the move returned always comes from a search inside its window.
+fn aspiration_search(
+    search_state: &mut SearchState,
+    root_moves: &[(u8, u8)],
+    depth: i32,
+    previous_score: Option<i32>,
+    tt: &TranspositionTable,
+) -> (i32, (u8, u8)) {
+    let mut delta = ASPIRATION_WINDOW;
+    let (mut alpha, mut beta) = match previous_score {
+        Some(score) if depth >= 4 => ((score - delta).max(-INFINITE), (score + delta).min(INFINITE)),
+        _ => (-INFINITE, INFINITE),
+    };
+    
+    loop {
+        let (score, best_move) = search_root(search_state, root_moves, depth, alpha, beta, tt);
+        if search_state.stopped {
+            return (score, best_move);
+        }
+        
+        if score <= alpha && alpha > -INFINITE {
+            alpha = (score - delta).max(-INFINITE);
+        } else if score >= beta && beta < INFINITE {
+            beta = (score + delta).min(INFINITE);
+        } else {
+            return (score, best_move);
+        }
+        delta = (delta * 2).min(INFINITE);
+    }
+}
 
 fn is_move_legal_fast(board: &BoardState, from: u8, to: u8) -> bool {
     let mut board_copy = *board;
@@ -2712,19 +2850,23 @@ fn is_move_legal_fast(board: &BoardState, from: u8, to: u8) -> bool {
     false
 }
 
+fn generate_legal_moves(board: &BoardState) -> Vec<(u8, u8)> {
+    generate_moves(board.bitboards, board.white_to_move, board)
+        .into_iter()
+        .filter(|&(from, to)| is_move_legal_fast(board, from, to))
+        .collect()
+}
+
 
 //END OF MINMAX SEARCH------------------------------------------------------------------------------------
 
 
 //MOVE ORDERING-------------------------------------------------------------------------------------------
 
-fn order_moves(board: &BoardState, moves: &[(u8, u8)]) -> Vec<(i32, u8, u8)> {
+// The caller passes the TT move, since the search already holds the table
+fn order_moves(board: &BoardState, moves: &[(u8, u8)], tt_move: Option<(u8, u8)>) -> Vec<(i32, u8, u8)> {
     let mut scored_moves = Vec::with_capacity(moves.len());
     
-    // Try to get TT move first
-    let hash = compute_board_hash(board);
-    let tt_move = get_tt_move(hash);
-    
     for &(from, to) in moves {
         let mut score = 0;
         
@@ -2764,12 +2906,10 @@ fn order_moves(board: &BoardState, moves: &[(u8, u8)]) -> Vec<(i32, u8, u8)> {
         
         // Add some positional bonuses
         // Center control for knights
-        if let Some(piece) = get_piece_at_square(&board.bitboards, from) {
-            if piece == WN || piece == BN {
-                let center_squares = [27, 28, 35, 36]; // d4, e4, d5, e5
-                if center_squares.contains(&(to as usize)) {
-                    score += 50;
-                }
+        if let Some(piece) = get_piece_at_square(&board.bitboards, from) && (piece == WN || piece == BN) {
+            let center_squares = [27, 28, 35, 36]; // d4, e4, d5, e5
+            if center_squares.contains(&(to as usize)) {
+                score += 50;
             }
         }
         
@@ -2777,16 +2917,222 @@ fn order_moves(board: &BoardState, moves: &[(u8, u8)]) -> Vec<(i32, u8, u8)> {
     }
     
     // Sort descending (best moves first)
-    scored_moves.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+    scored_moves.sort_unstable_by_key(|&(score, _, _)| std::cmp::Reverse(score));
     scored_moves
 }
 
+// Piece values for exchanges; the king can take but never be given up
+const SEE_VALUES: [i32; 6] = [100, 300, 300, 500, 900, 20_000]; // P, N, B, R, Q, K
+
+// The least valuable piece of one side attacking `square`, given the
+// pieces still on the board
+fn least_valuable_attacker(board: &[u64; 12], square: u8, occupied: u64, white: bool) -> Option<(u8, i32)> {
+    let first = if white { WP } else { BP };
+    let square = square as usize;
+    let pawn_attackers = if white { BLACK_PAWN_ATTACKS.get().unwrap()[square] } else { WHITE_PAWN_ATTACKS.get().unwrap()[square] };
+    let attackers = [
+        pawn_attackers,
+        KNIGHT_ATTACKS.get().unwrap()[square],
+        get_bishop_attacks(square as u8, occupied),
+        get_rook_attacks(square as u8, occupied),
+        get_queen_attacks(square as u8, occupied),
+        KING_ATTACKS.get().unwrap()[square],
+    ];
+    attackers.iter().enumerate().find_map(|(kind, &attacks)| {
+        get_lsb(attacks & board[first + kind] & occupied).map(|from| (from, SEE_VALUES[kind]))
+    })
+}
+
+/// Static exchange evaluation: what the side to move wins by capturing on
+/// `to` and letting both sides go on recapturing there, least valuable
+/// piece first, each free to stop when going on would lose.
+fn static_exchange(board_state: &BoardState, from: u8, to: u8) -> i32 {
+    let board = &board_state.bitboards;
+    let value = |square| get_piece_at_square(board, square).map_or(0, |piece| SEE_VALUES[piece % 6]);
+    let mut gains = [0; 32];
+    gains[0] = value(to);
+    let mut attacker_value = value(from);
+    let mut occupied = get_all_occupied(*board) & !(1u64 << from);
+    let mut white = !board_state.white_to_move;
+    let mut depth = 0;
+    
+    // Removing each piece that took uncovers any slider behind it
+    while depth + 1 < gains.len() {
+        depth += 1;
+        gains[depth] = attacker_value - gains[depth - 1];
+        if (-gains[depth - 1]).max(gains[depth]) < 0 {
+            break;
+        }
+        let Some((square, value)) = least_valuable_attacker(board, to, occupied, white) else {
+            break;
+        };
+        occupied &= !(1u64 << square);
+        attacker_value = value;
+        white = !white;
+    }
+    while depth > 1 {
+        depth -= 1;
+        gains[depth - 1] = -(-gains[depth - 1]).max(gains[depth]);
+    }
+    gains[0]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PickStage {
+    TtMove,
+    GoodCaptures,
+    Killers,
+    Quiets,
+    BadCaptures,
+    Done,
+}
+
+/// Hands out the legal moves of a node best first, a stage at a time: the
+/// TT move before anything is generated, then captures that don't lose
+/// material by MVV-LVA, the killers, quiet moves by history, and last the
+/// captures the exchange says lose. Each stage generates and scores its
+/// moves only once reached, so a cutoff skips the work of the rest.
+struct MovePicker {
+    stage: PickStage,
+    tt_move: Option<(u8, u8)>,
+    killers: [Option<(u8, u8)>; 2],
+    moves: Vec<(i32, u8, u8)>,        // The current stage's moves not yet picked
+    bad_captures: Vec<(i32, u8, u8)>, // Put off until the quiet moves are done
+    generated: bool,                  // Whether the current stage has its moves
+}
+
+impl MovePicker {
+    fn new(board: &BoardState, tt_move: Option<(u8, u8)>, ply: usize) -> Self {
+        let tt_move = tt_move.filter(|&mv| mv.0 != mv.1 && is_pseudo_legal(board, mv));
+        Self {
+            stage: PickStage::TtMove,
+            tt_move,
+            killers: get_killer_moves(ply),
+            moves: Vec::new(),
+            bad_captures: Vec::new(),
+            generated: false,
+        }
+    }
+    
+    fn advance(&mut self, stage: PickStage) {
+        self.stage = stage;
+        self.generated = false;
+    }
+    
+    // Takes out the highest scored move left
+    fn pick_best(&mut self) -> Option<(i32, u8, u8)> {
+        let best = (0..self.moves.len()).max_by_key(|&index| self.moves[index].0)?;
+        Some(self.moves.swap_remove(best))
+    }
+    
+    /// The next legal move, or None once all have been handed out.
+    fn next(&mut self, board: &BoardState) -> Option<(u8, u8)> {
+        loop {
+            let candidate = match self.stage {
+                PickStage::TtMove => {
+                    self.advance(PickStage::GoodCaptures);
+                    self.tt_move
+                }
+                PickStage::GoodCaptures => {
+                    if !self.generated {
+                        self.moves = generate_captures(board).into_iter()
+                            .filter(|&mv| Some(mv) != self.tt_move)
+                            .map(|(from, to)| (capture_score(board, from, to), from, to))
+                            .collect();
+                        self.generated = true;
+                    }
+                    match self.pick_best() {
+                        // Only a capture by a piece worth more than its victim can lose material
+                        Some((score, from, to)) if score < 0 && static_exchange(board, from, to) < 0 => {
+                            self.bad_captures.push((score, from, to));
+                            continue;
+                        }
+                        Some((_, from, to)) => Some((from, to)),
+                        None => {
+                            self.advance(PickStage::Killers);
+                            continue;
+                        }
+                    }
+                }
+                PickStage::Killers => {
+                    if !self.generated {
+                        let killers = self.killers.iter().rev()
+                            .flatten()
+                            .filter(|&&mv| Some(mv) != self.tt_move && is_quiet(board, mv) && is_pseudo_legal(board, mv))
+                            .map(|&(from, to)| (0, from, to));
+                        self.moves = killers.collect();
+                        self.generated = true;
+                    }
+                    match self.moves.pop() {
+                        Some((_, from, to)) => Some((from, to)),
+                        None => {
+                            self.advance(PickStage::Quiets);
+                            continue;
+                        }
+                    }
+                }
+                PickStage::Quiets => {
+                    if !self.generated {
+                        let skip = [self.tt_move, self.killers[0], self.killers[1]];
+                        self.moves = generate_moves(board.bitboards, board.white_to_move, board).into_iter()
+                            .filter(|&mv| is_quiet(board, mv) && !skip.contains(&Some(mv)))
+                            .map(|(from, to)| (get_history_score(from, to, board.white_to_move), from, to))
+                            .collect();
+                        self.generated = true;
+                    }
+                    match self.pick_best() {
+                        Some((_, from, to)) => Some((from, to)),
+                        None => {
+                            self.moves = std::mem::take(&mut self.bad_captures);
+                            self.advance(PickStage::BadCaptures);
+                            continue;
+                        }
+                    }
+                }
+                PickStage::BadCaptures => match self.pick_best() {
+                    Some((_, from, to)) => Some((from, to)),
+                    None => {
+                        self.advance(PickStage::Done);
+                        continue;
+                    }
+                },
+                PickStage::Done => return None,
+            };
+            
+            if let Some((from, to)) = candidate && is_move_legal_fast(board, from, to) {
+                return Some((from, to));
+            }
+        }
+    }
+}
+
+// MVV-LVA, with a pawn reaching the last rank gaining what the queen is
+// worth over it. Negative only when the piece taking is worth more than
+// the one taken
+fn capture_score(board: &BoardState, from: u8, to: u8) -> i32 {
+    let attacker = get_piece_at_square(&board.bitboards, from).unwrap_or(WP);
+    let mut victim = get_piece_at_square(&board.bitboards, to).map_or(0, get_piece_value);
+    if is_promotion(attacker, to) {
+        victim += get_piece_value(WQ) - get_piece_value(WP);
+    }
+    let attacker = get_piece_value(attacker);
+    if victim >= attacker { victim * 100 - attacker } else { victim - attacker }
+}
+
+// Neither a capture nor a promotion
+fn is_quiet(board: &BoardState, (from, to): (u8, u8)) -> bool {
+    get_piece_at_square(&board.bitboards, to).is_none()
+        && !get_piece_at_square(&board.bitboards, from).is_some_and(|piece| is_promotion(piece, to))
+}
+
 use std::cell::RefCell;
 use std::thread_local;
 
+type KillerTable = [[Option<(u8, u8)>; 2]; 64];
+
 // Killer moves are typically thread-local for performance
 thread_local! {
-    static KILLER_MOVES: RefCell<[[Option<(u8, u8)>; 2]; 64]> = RefCell::new([[None; 2]; 64]);
+    static KILLER_MOVES: RefCell<KillerTable> = const { RefCell::new([[None; 2]; 64]) };
 }
 
 fn is_killer_move(from: u8, to: u8, _white_to_move: bool) -> bool {
@@ -2794,10 +3140,8 @@ fn is_killer_move(from: u8, to: u8, _white_to_move: bool) -> bool {
         let km_ref = km.borrow();
         for ply in 0..64 {
             for slot in 0..2 {
-                if let Some((kfrom, kto)) = km_ref[ply][slot] {
-                    if kfrom == from && kto == to {
-                        return true;
-                    }
+                if let Some((kfrom, kto)) = km_ref[ply][slot] && kfrom == from && kto == to {
+                    return true;
                 }
             }
         }
@@ -2805,6 +3149,13 @@ fn is_killer_move(from: u8, to: u8, _white_to_move: bool) -> bool {
     })
 }
 
+fn get_killer_moves(ply: usize) -> [Option<(u8, u8)>; 2] {
+    if ply >= 64 {
+        return [None; 2];
+    }
+    KILLER_MOVES.with(|km| km.borrow()[ply])
+}
+
 fn add_killer_move(ply: usize, from: u8, to: u8) {
     if ply >= 64 {
         return;
@@ -2815,10 +3166,8 @@ fn add_killer_move(ply: usize, from: u8, to: u8) {
         
         // Check if move already exists
         for slot in 0..2 {
-            if let Some((kfrom, kto)) = km_mut[ply][slot] {
-                if kfrom == from && kto == to {
-                    return;
-                }
+            if let Some((kfrom, kto)) = km_mut[ply][slot] && kfrom == from && kto == to {
+                return;
             }
         }
         
@@ -2847,139 +3196,224 @@ fn update_history_score(from: u8, to: u8, depth: i32) {
 
 //TRANSPOSITION TABLES------------------------------------------------------------------------------------
 
-static TRANSPOSITION_TABLE: OnceLock<Arc<RwLock<TranspositionTable>>> = OnceLock::new();
+// Replaced whole when the Hash option changes; a search holds on to the
+// table it started with
+static TRANSPOSITION_TABLE: RwLock<Option<Arc<TranspositionTable>>> = RwLock::new(None);
+const DEFAULT_HASH_MB: usize = 16;
+const MAX_HASH_MB: usize = 4096;
 
 #[derive(Clone, Copy)]
+// The hash lives in the slot, XORed with the packed entry
 struct TTEntry {
-    hash: u64,
     depth: i32,
     score: i32,
     flag: u8, // 0=exact, 1=upper bound, 2=lower bound
     best_move: (u8, u8),
 }
 
+// Set in every packed entry so an empty slot never looks like one
+const TT_OCCUPIED: u64 = 1 << 56;
+// Searches are numbered modulo 128 in the top bits of each entry
+const TT_GENERATION_SHIFT: u32 = 57;
+const TT_GENERATIONS: u8 = 128;
+
+impl TTEntry {
+    // Everything but the hash in one word: score, depth, flag, from, to
+    // and the generation of the search that stored it
+    fn pack(&self, generation: u8) -> u64 {
+        (self.score as i16 as u16 as u64)
+            | (self.depth as i16 as u16 as u64) << 16
+            | (self.flag as u64) << 32
+            | (self.best_move.0 as u64) << 40
+            | (self.best_move.1 as u64) << 48
+            | TT_OCCUPIED
+            | (generation as u64) << TT_GENERATION_SHIFT
+    }
+    
+    fn unpack(data: u64) -> Self {
+        Self {
+            score: data as u16 as i16 as i32,
+            depth: (data >> 16) as u16 as i16 as i32,
+            flag: (data >> 32) as u8,
+            best_move: ((data >> 40) as u8, (data >> 48) as u8),
+        }
+    }
+}
+
+// A slot keeps the packed entry and its hash XORed with it. Threads read and
+// write the two words without locks; a slot torn by a concurrent store no
+// longer XORs back to the hash, so it reads as a miss rather than as another
+// position's entry.
+struct TTSlot {
+    key: AtomicU64,
+    data: AtomicU64,
+}
+
+impl TTSlot {
+    fn generation(data: u64) -> u8 {
+        (data >> TT_GENERATION_SHIFT) as u8
+    }
+}
+
+const TT_BUCKET_SLOTS: usize = 4;
+
+// Four slots to a cache line; a position may sit in any slot of its bucket
+#[repr(align(64))]
+struct TTBucket {
+    slots: [TTSlot; TT_BUCKET_SLOTS],
+}
+
 struct TranspositionTable {
-    entries: Vec<Option<TTEntry>>,
-    size: usize,
+    buckets: Vec<TTBucket>,
+    mask: usize,           // Bucket count minus one, the count being a power of two
+    generation: AtomicU8,  // Bumped by every search so older entries can be told apart
+}
+
+impl std::fmt::Debug for TranspositionTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "TranspositionTable({} buckets)", self.buckets.len())
+    }
 }
 
 impl TranspositionTable {
     fn new(size_mb: usize) -> Self {
-        // Calculate number of entries based on memory size
-        // Each entry is about 24 bytes (u64 + i32 + i32 + u8 + (u8, u8) padding)
-        let entry_size = std::mem::size_of::<TTEntry>();
-        let size = (size_mb * 1024 * 1024) / entry_size;
-        
-        // Make sure we have at least some minimum size
-        let size = size.max(1024); // Minimum 1024 entries
+        // The largest power of two number of buckets that fits in the size
+        let bucket_size = std::mem::size_of::<TTBucket>();
+        let buckets = ((size_mb * 1024 * 1024) / bucket_size).max(256); // Minimum 1024 entries
+        let buckets = 1 << buckets.ilog2();
         
         Self {
-            entries: vec![None; size],
-            size,
+            buckets: (0..buckets).map(|_| TTBucket {
+                slots: std::array::from_fn(|_| TTSlot { key: AtomicU64::new(0), data: AtomicU64::new(0) }),
+            }).collect(),
+            mask: buckets - 1,
+            generation: AtomicU8::new(0),
         }
     }
     
-    fn store(&mut self, hash: u64, depth: i32, score: i32, flag: u8, best_move: (u8, u8)) {
-        let index = (hash as usize) % self.size;
+    fn bucket(&self, hash: u64) -> &TTBucket {
+        &self.buckets[(hash as usize) & self.mask]
+    }
+    
+    // Starts a new search, ageing every entry already stored
+    fn new_search(&self) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.generation.store((generation + 1) % TT_GENERATIONS, Ordering::Relaxed);
+    }
+    
+    // Searches since the entry was stored
+    fn age(&self, data: u64) -> i32 {
+        let generation = self.generation.load(Ordering::Relaxed);
+        ((generation + TT_GENERATIONS - TTSlot::generation(data)) % TT_GENERATIONS) as i32
+    }
+    
+    // The entry stored for this hash, if its bucket holds one intact
+    fn load(&self, hash: u64) -> Option<TTEntry> {
+        self.bucket(hash).slots.iter().find_map(|slot| {
+            let data = slot.data.load(Ordering::Relaxed);
+            let key = slot.key.load(Ordering::Relaxed);
+            (data & TT_OCCUPIED != 0 && key ^ data == hash).then(|| TTEntry::unpack(data))
+        })
+    }
+    
+    fn store(&self, hash: u64, depth: i32, score: i32, flag: u8, best_move: (u8, u8), ply: usize) {
+        let score = score_to_tt(score, ply);
+        let bucket = self.bucket(hash);
         
-        // Replacement strategy: always replace if new entry is from deeper search
-        if let Some(existing) = &self.entries[index] {
-            if existing.depth > depth && existing.hash == hash {
-                // Keep the existing deeper entry
-                return;
+        // The position's own slot if it has one, else the least valuable:
+        // an empty slot, or the shallowest once age is counted against depth
+        let mut target = &bucket.slots[0];
+        let mut target_value = i32::MAX;
+        for slot in &bucket.slots {
+            let data = slot.data.load(Ordering::Relaxed);
+            if data & TT_OCCUPIED == 0 {
+                if target_value > i32::MIN {
+                    target = slot;
+                    target_value = i32::MIN;
+                }
+                continue;
+            }
+            if slot.key.load(Ordering::Relaxed) ^ data == hash {
+                // A deeper result from this search is worth more than a shallower one
+                let existing = TTEntry::unpack(data);
+                if existing.depth > depth && flag != 0 && self.age(data) == 0 {
+                    return;
+                }
+                target = slot;
+                break;
+            }
+            let value = TTEntry::unpack(data).depth - 8 * self.age(data);
+            if value < target_value {
+                target = slot;
+                target_value = value;
             }
         }
         
-        self.entries[index] = Some(TTEntry { 
-            hash, 
-            depth, 
-            score, 
-            flag, 
-            best_move 
-        });
+        let data = TTEntry { depth, score, flag, best_move }.pack(self.generation.load(Ordering::Relaxed));
+        target.key.store(hash ^ data, Ordering::Relaxed);
+        target.data.store(data, Ordering::Relaxed);
     }
     
-    fn probe(&self, hash: u64, depth: i32, alpha: i32, beta: i32) -> Option<(i32, (u8, u8))> {
-        let index = (hash as usize) % self.size;
+    fn probe(&self, hash: u64, depth: i32, alpha: i32, beta: i32, ply: usize) -> Option<(i32, (u8, u8))> {
+        let entry = self.load(hash)?;
         
-        if let Some(entry) = &self.entries[index] {
-            // Check if this is the right entry (not a hash collision)
-            if entry.hash == hash && entry.depth >= depth {
-                match entry.flag {
-                    0 => { // Exact score
-                        return Some((entry.score, entry.best_move));
-                    }
-                    1 => { // Upper bound (score <= actual)
-                        if entry.score <= alpha {
-                            return Some((alpha, entry.best_move));
-                        }
-                    }
-                    2 => { // Lower bound (score >= actual)
-                        if entry.score >= beta {
-                            return Some((beta, entry.best_move));
-                        }
-                    }
-                    _ => {}
+        if entry.depth >= depth {
+            let score = score_from_tt(entry.score, ply);
+            match entry.flag {
+                0 => { // Exact score
+                    return Some((score, entry.best_move));
+                }
+                1 if score <= alpha => { // Upper bound (score <= actual)
+                    return Some((alpha, entry.best_move));
                 }
+                2 if score >= beta => { // Lower bound (score >= actual)
+                    return Some((beta, entry.best_move));
+                }
+                _ => {}
             }
         }
         
         None
     }
     
-    // Clear the entire transposition table
-    fn clear(&mut self) {
-        for entry in self.entries.iter_mut() {
-            *entry = None;
-        }
+    // Permille of the table holding entries from the current search,
+    // sampled from the first thousand slots
+    fn hashfull(&self) -> usize {
+        let sample = self.buckets.len().min(1000 / TT_BUCKET_SLOTS);
+        let used = self.buckets[..sample].iter()
+            .flat_map(|bucket| &bucket.slots)
+            .map(|slot| slot.data.load(Ordering::Relaxed))
+            .filter(|&data| data & TT_OCCUPIED != 0 && self.age(data) == 0)
+            .count();
+        used * 1000 / (sample * TT_BUCKET_SLOTS)
     }
     
-    // Get statistics about the transposition table
-    fn stats(&self) -> (usize, usize) {
-        let total = self.size;
-        let used = self.entries.iter().filter(|e| e.is_some()).count();
-        (total, used)
+    fn best_move(&self, hash: u64) -> Option<(u8, u8)> {
+        self.load(hash).map(|entry| entry.best_move)
     }
-}
-
-fn init_transposition_table(size_mb: usize) {
-    let _ = TRANSPOSITION_TABLE.set(Arc::new(RwLock::new(TranspositionTable::new(size_mb))));
-}
-
-
-fn get_transposition_table() -> Arc<RwLock<TranspositionTable>> {
-    TRANSPOSITION_TABLE
-        .get_or_init(|| Arc::new(RwLock::new(TranspositionTable::new(16))))
-        .clone()
-}
-
-
-fn get_tt_move(hash: u64) -> Option<(u8, u8)> {
-    let tt = get_transposition_table();
-    let tt_guard = tt.read().unwrap(); // Use read lock for shared access
-    
-    let index = (hash as usize) % tt_guard.size;
     
-    if let Some(entry) = &tt_guard.entries[index] {
-        if entry.hash == hash {
-            return Some(entry.best_move);
+    // Clear the entire transposition table
+    fn clear(&self) {
+        for slot in self.buckets.iter().flat_map(|bucket| &bucket.slots) {
+            slot.key.store(0, Ordering::Relaxed);
+            slot.data.store(0, Ordering::Relaxed);
         }
+        self.generation.store(0, Ordering::Relaxed);
     }
     
-    None
+}
+
+fn init_transposition_table(size_mb: usize) {
+    *TRANSPOSITION_TABLE.write().unwrap() = Some(Arc::new(TranspositionTable::new(size_mb)));
 }
 
 
-fn get_best_move_from_tt(board_state: &BoardState, tt: &TranspositionTable) -> Option<(u8, u8)> {
-    let hash = compute_board_hash(board_state);
-    // Look up in TT (simplified - in reality you'd need depth and bounds)
-    let index = (hash as usize) % tt.size;
-    if let Some(entry) = &tt.entries[index] {
-        if entry.hash == hash {
-            return Some(entry.best_move);
-        }
+fn get_transposition_table() -> Arc<TranspositionTable> {
+    if let Some(tt) = &*TRANSPOSITION_TABLE.read().unwrap() {
+        return Arc::clone(tt);
     }
-    None
+    let mut tt = TRANSPOSITION_TABLE.write().unwrap();
+    Arc::clone(tt.get_or_insert_with(|| Arc::new(TranspositionTable::new(DEFAULT_HASH_MB))))
 }
 
 
@@ -3013,7 +3447,7 @@ impl ThreadSafeHistoryTable {
 
 //BOARD STATE---------------------------------------------------------------------------------------------
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 struct BoardState {
     bitboards: [u64; 12],
     white_to_move: bool,
@@ -3071,16 +3505,6 @@ impl BoardState {
         }
     }
     
-    // Method to check if white king is in check
-    fn is_white_king_in_check(&self) -> bool {
-        self.white_king_in_check
-    }
-    
-    // Method to check if black king is in check
-    fn is_black_king_in_check(&self) -> bool {
-        self.black_king_in_check
-    }
-    
     // Method to check if current side's king is in check
     fn is_current_king_in_check(&self) -> bool {
         if self.white_to_move {
@@ -3095,48 +3519,518 @@ impl BoardState {
         self.white_king_in_check = is_check(self.bitboards, true);
         self.black_king_in_check = is_check(self.bitboards, false);
     }
+    
+    // The same position with colours swapped and ranks mirrored (a1 <-> a8),
+    // including castling rights, en passant square and side to move
+    fn mirror(&self) -> Self {
+        let mut bitboards = [0u64; 12];
+        for piece in 0..6 {
+            // One byte per rank, so reversing the bytes flips the board vertically
+            bitboards[piece] = self.bitboards[piece + 6].swap_bytes();
+            bitboards[piece + 6] = self.bitboards[piece].swap_bytes();
+        }
+        
+        let mut mirrored = Self {
+            bitboards,
+            white_to_move: !self.white_to_move,
+            white_kingside_castle: self.black_kingside_castle,
+            white_queenside_castle: self.black_queenside_castle,
+            black_kingside_castle: self.white_kingside_castle,
+            black_queenside_castle: self.white_queenside_castle,
+            white_king_in_check: false,
+            black_king_in_check: false,
+            en_passant_target: self.en_passant_target.map(mirror_square),
+        };
+        mirrored.update_check_status();
+        mirrored
+    }
+}
+
+#[inline]
+fn mirror_square(square: u8) -> u8 {
+    square ^ 56
 }
 
 //END OF BOARD STATE---------------------------------------------------------------------------------------
 
-//UCI PROTOCOL-------------------------------------------------------------------------------------------
 
-fn parse_fen(fen: &str) -> Option<BoardState> {
-    let parts: Vec<&str> = fen.split_whitespace().collect();
-    if parts.len() < 1 {
-        return None;
+//TIME MANAGEMENT------------------------------------------------------------------------------------------
+
+// Below this much on the clock every move is played quickly
+const LOW_TIME_MS: u64 = 1000;
+// Milliseconds lost per move to the GUI and the connection, set by the Move Overhead option
+static MOVE_OVERHEAD: AtomicU64 = AtomicU64::new(10);
+const MAX_MOVE_OVERHEAD: u64 = 5000;
+
+/// Time for one move. Between iterations the search stops once `optimum`
+/// has passed, stretched or shrunk by how settled the best move is; in the
+/// middle of one it is cut off at `maximum`.
+#[derive(Clone, Debug)]
+struct TimeManager {
+    optimum: Duration,
+    maximum: Duration,
+    adaptive: bool,           // A clock to manage, rather than a fixed time for the move
+    best_move: Option<(u8, u8)>,
+    best_move_changes: f64,   // Iterations that changed the best move, halved every iteration
+    stable_iterations: u32,   // Iterations in a row that kept the best move
+    previous_score: Option<i32>,
+    score_drop: i32,          // How far the last iteration's score fell, up to a pawn
+}
+
+impl TimeManager {
+    /// Exactly `time` for the move, as with movetime.
+    fn fixed(time: Duration) -> Self {
+        Self {
+            optimum: time,
+            maximum: time,
+            adaptive: false,
+            best_move: None,
+            best_move_changes: 0.0,
+            stable_iterations: 0,
+            previous_score: None,
+            score_drop: 0,
+        }
     }
     
-    let board_str = parts[0];
-    let mut bitboards = [0u64; 12];
-    
-    let mut rank = 0;
-    let mut file = 0;
-    
-    for ch in board_str.chars() {
-        if ch == '/' {
-            rank += 1;
-            file = 0;
-            continue;
-        }
+    /// Budget from the clock, all in milliseconds. Without a time control
+    /// ahead the moves left are guessed from how far the game has gone.
+    fn from_clock(time_left: u64, increment: u64, moves_to_go: Option<u32>, game_ply: u32, overhead: u64) -> Self {
+        let moves_left = moves_to_go
+            .map(u64::from)
+            .unwrap_or_else(|| 50u64.saturating_sub(game_ply as u64 / 4).max(20))
+            .clamp(1, 50);
+        let usable = time_left.saturating_sub(overhead);
+        // Never more than this on a single move
+        let cap = usable * 4 / 5;
         
-        if ch.is_ascii_digit() {
-            file += ch.to_digit(10)? as usize;
-            continue;
-        }
+        let (optimum, maximum) = if time_left < LOW_TIME_MS {
+            // About to flag: small steady slices, living off the increment
+            let optimum = (usable / 40 + increment / 2).min(cap);
+            (optimum, (optimum * 2).min(cap))
+        } else {
+            let optimum = (usable / moves_left + increment * 3 / 4).min(cap);
+            (optimum, (optimum * 4).min(cap))
+        };
         
-        if file >= 8 || rank >= 8 {
-            return None;
+        Self {
+            optimum: Duration::from_millis(optimum.max(1)),
+            maximum: Duration::from_millis(maximum.max(optimum).max(1)),
+            adaptive: true,
+            ..Self::fixed(Duration::ZERO)
         }
-        
-        let square = (rank * 8 + file) as u8;
-        let piece_index = match ch {
-            'P' => Some(WP),
-            'N' => Some(WN),
-            'B' => Some(WB),
-            'R' => Some(WR),
-            'Q' => Some(WQ),
-            'K' => Some(WK),
+    }
+    
+    /// Takes in the best move and score of a finished iteration.
+    fn update(&mut self, best_move: (u8, u8), score: i32) {
+        let changed = self.best_move.is_some_and(|previous| previous != best_move);
+        self.best_move_changes = self.best_move_changes / 2.0 + if changed { 1.0 } else { 0.0 };
+        self.stable_iterations = if changed { 0 } else { self.stable_iterations + 1 };
+        self.best_move = Some(best_move);
+        self.score_drop = self.previous_score.map_or(0, |previous| (previous - score).clamp(0, 100));
+        self.previous_score = Some(score);
+    }
+    
+    // The optimum, longer while the best move keeps changing or the score
+    // falls, and halved once one move has held for several iterations
+    fn target(&self) -> Duration {
+        if !self.adaptive {
+            return self.optimum;
+        }
+        let instability = 1.0 + self.best_move_changes;
+        let falling = 1.0 + self.score_drop as f64 / 100.0;
+        let stability = if self.stable_iterations >= 4 { 0.5 } else { 1.0 };
+        self.optimum.mul_f64(instability * falling * stability).min(self.maximum)
+    }
+    
+    /// Whether to play the move now rather than start another iteration.
+    fn should_stop(&self, elapsed: Duration) -> bool {
+        elapsed >= self.target()
+    }
+}
+
+//END OF TIME MANAGEMENT-----------------------------------------------------------------------------------
+
+
+//STRENGTH LIMITING----------------------------------------------------------------------------------------
+
+const MAX_SKILL_LEVEL: i64 = 20; // Full strength
+// UCI_Elo of the calibrated skill levels, measured by `chess calibrate 40 0 2
+// 4 6 8 10 12 14 16 18 19`: 40 games between each pair of neighbouring levels
+// from random openings, with the gaps chained up from level 0. Only the gaps
+// are measured; pinning level 0 at 1000 is an assumption, as the engine has
+// not played rated opponents.
+const SKILL_ELO: [(f64, i64); 11] = [
+    (0.0, 1000),
+    (2.0, 1255),
+    (4.0, 1423),
+    (6.0, 1603),
+    (8.0, 1817),
+    (10.0, 1954),
+    (12.0, 2072),
+    (14.0, 2274),
+    (16.0, 2411),
+    (18.0, 2580),
+    (19.0, 2748),
+];
+const MIN_ELO: i64 = SKILL_ELO[0].1;
+const MAX_ELO: i64 = SKILL_ELO[SKILL_ELO.len() - 1].1;
+// Lines searched to choose a weaker move from
+const SKILL_CANDIDATES: usize = 4;
+// Longest self-play game when calibrating, after which it is a draw
+const SKILL_GAME_PLIES: usize = 300;
+
+/// Reduced playing strength: a shallower, smaller search, then a move picked
+/// at random from the best few with worse moves the more likely the lower
+/// the level. Everything grows steadily with the level, so a higher level,
+/// or a higher Elo, never plays weaker.
+#[derive(Clone, Copy, Debug)]
+struct Skill {
+    level: f64, // 0 up to MAX_SKILL_LEVEL, fractional when set from an Elo
+}
+
+impl Skill {
+    /// The Skill Level option, or UCI_Elo when UCI_LimitStrength is on.
+    /// None at full strength.
+    fn new(skill_level: i64, limit_strength: bool, elo: i64) -> Option<Self> {
+        let level = if limit_strength {
+            // Straight lines between the measured levels
+            let elo = elo.clamp(MIN_ELO, MAX_ELO);
+            let upper = SKILL_ELO.iter().position(|&(_, rated)| rated >= elo).unwrap().max(1);
+            let ((low_level, low_elo), (high_level, high_elo)) = (SKILL_ELO[upper - 1], SKILL_ELO[upper]);
+            low_level + (high_level - low_level) * (elo - low_elo) as f64 / (high_elo - low_elo) as f64
+        } else {
+            skill_level.clamp(0, MAX_SKILL_LEVEL) as f64
+        };
+        (level < MAX_SKILL_LEVEL as f64).then_some(Self { level })
+    }
+    
+    // 1 ply at level 0, 10 at level 19
+    fn depth(&self) -> u8 {
+        1 + (self.level / 2.0) as u8
+    }
+    
+    // 500 nodes at level 0, doubling every two levels
+    fn nodes(&self) -> u64 {
+        (500.0 * 2f64.powf(self.level / 2.0)) as u64
+    }
+    
+    /// Weakens the limits of a search to this level.
+    fn limit(&self, limits: &mut SearchLimits) {
+        limits.depth = limits.depth.min(self.depth());
+        limits.nodes = Some(limits.nodes.map_or(self.nodes(), |nodes| nodes.min(self.nodes())));
+        limits.multipv = limits.multipv.max(SKILL_CANDIDATES);
+    }
+    
+    /// Picks one of the lines, best first, giving each a bonus that is part
+    /// a share of how far it trails the best and part random, both larger at
+    /// lower levels. At level 0 any candidate may be played; near full
+    /// strength only those within about a pawn of the best.
+    fn pick(&self, lines: Vec<SearchResult>, rng: &mut impl Rng) -> Option<SearchResult> {
+        let top = lines.first()?.score;
+        let weakness = 120.0 - 2.0 * self.level;
+        let delta = (top - lines.last()?.score).min(100) as f64;
+        lines.into_iter()
+            .map(|line| {
+                let push = (weakness * (top - line.score) as f64 + delta * rng.random_range(0.0..weakness)) / 128.0;
+                (line.score as f64 + push, line)
+            })
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, line)| line)
+    }
+}
+
+//END OF STRENGTH LIMITING---------------------------------------------------------------------------------
+
+
+//UCI OPTIONS----------------------------------------------------------------------------------------------
+
+/// The kinds of option UCI knows, with their defaults and bounds.
+enum OptionKind {
+    Spin { default: i64, min: i64, max: i64 },
+    Check { default: bool },
+    Combo { default: &'static str, vars: &'static [&'static str] },
+    Button,
+    String { default: &'static str },
+}
+
+/// A `setoption` value, checked against the kind of its option.
+enum OptionValue {
+    Spin(i64),
+    Check(bool),
+    Text(String), // Combo choices and strings; an empty string for <empty>
+    Button,
+}
+
+impl OptionValue {
+    fn as_int(&self) -> i64 {
+        if let Self::Spin(value) = self { *value } else { 0 }
+    }
+    
+    fn as_bool(&self) -> bool {
+        matches!(self, Self::Check(true))
+    }
+    
+    fn as_str(&self) -> &str {
+        if let Self::Text(value) = self { value } else { "" }
+    }
+}
+
+/// What `setoption` changes that lives in the UCI loop rather than in globals.
+struct EngineSettings {
+    own_book: bool,
+    book: Option<book::Book>,
+    book_depth: u32, // moves
+    book_best_move: bool,
+    skill_level: i64,
+    limit_strength: bool,
+    elo: i64,
+}
+
+impl EngineSettings {
+    fn new() -> Self {
+        Self {
+            own_book: false,
+            book: None,
+            book_depth: 20,
+            book_best_move: false,
+            skill_level: MAX_SKILL_LEVEL,
+            limit_strength: false,
+            elo: MIN_ELO,
+        }
+    }
+    
+    fn skill(&self) -> Option<Skill> {
+        Skill::new(self.skill_level, self.limit_strength, self.elo)
+    }
+}
+
+/// An option as advertised in reply to `uci`. Applying a value may give a
+/// message for the GUI.
+struct UciOption {
+    name: &'static str,
+    kind: OptionKind,
+    apply: fn(&mut EngineSettings, OptionValue) -> Option<String>,
+}
+
+const UCI_OPTIONS: &[UciOption] = &[
+    UciOption {
+        name: "Hash",
+        kind: OptionKind::Spin { default: DEFAULT_HASH_MB as i64, min: 1, max: MAX_HASH_MB as i64 },
+        apply: |_, value| {
+            init_transposition_table(value.as_int() as usize);
+            None
+        },
+    },
+    UciOption {
+        name: "Clear Hash",
+        kind: OptionKind::Button,
+        apply: |_, _| {
+            get_transposition_table().clear();
+            None
+        },
+    },
+    UciOption {
+        name: "Threads",
+        kind: OptionKind::Spin { default: 1, min: 1, max: MAX_THREADS as i64 },
+        apply: |_, value| {
+            THREADS.store(value.as_int() as usize, Ordering::Relaxed);
+            None
+        },
+    },
+    UciOption {
+        name: "MultiPV",
+        kind: OptionKind::Spin { default: 1, min: 1, max: MAX_MULTI_PV as i64 },
+        apply: |_, value| {
+            MULTI_PV.store(value.as_int() as usize, Ordering::Relaxed);
+            None
+        },
+    },
+    UciOption {
+        // Only tells us the GUI may send go ponder
+        name: "Ponder",
+        kind: OptionKind::Check { default: false },
+        apply: |_, _| None,
+    },
+    UciOption {
+        name: "Move Overhead",
+        kind: OptionKind::Spin { default: 10, min: 0, max: MAX_MOVE_OVERHEAD as i64 },
+        apply: |_, value| {
+            MOVE_OVERHEAD.store(value.as_int() as u64, Ordering::Relaxed);
+            None
+        },
+    },
+    UciOption {
+        name: "SyzygyPath",
+        kind: OptionKind::String { default: "" },
+        apply: |_, value| Some(format!("found {} tablebases", syzygy::init(value.as_str()))),
+    },
+    UciOption {
+        name: "EndgameTablePath",
+        kind: OptionKind::String { default: "" },
+        apply: |_, value| Some(format!("loaded {} endgame tables", retrograde::load_dir(value.as_str()))),
+    },
+    UciOption {
+        name: "OwnBook",
+        kind: OptionKind::Check { default: false },
+        apply: |settings, value| {
+            settings.own_book = value.as_bool();
+            None
+        },
+    },
+    UciOption {
+        name: "BookFile",
+        kind: OptionKind::String { default: "" },
+        apply: |settings, value| {
+            settings.book = None;
+            let path = value.as_str();
+            if path.is_empty() {
+                return None;
+            }
+            match book::Book::open(std::path::Path::new(path)) {
+                Ok(opened) => {
+                    let message = format!("loaded {} book entries", opened.len());
+                    settings.book = Some(opened);
+                    Some(message)
+                }
+                Err(err) => Some(format!("can't open book {}: {}", path, err)),
+            }
+        },
+    },
+    UciOption {
+        name: "BookDepth",
+        kind: OptionKind::Spin { default: 20, min: 1, max: 100 },
+        apply: |settings, value| {
+            settings.book_depth = value.as_int() as u32;
+            None
+        },
+    },
+    UciOption {
+        // Always the most played book move, or one at random by its weight
+        name: "BookMove",
+        kind: OptionKind::Combo { default: "Weighted", vars: &["Best", "Weighted"] },
+        apply: |settings, value| {
+            settings.book_best_move = value.as_str() == "Best";
+            None
+        },
+    },
+    UciOption {
+        name: "Skill Level",
+        kind: OptionKind::Spin { default: MAX_SKILL_LEVEL, min: 0, max: MAX_SKILL_LEVEL },
+        apply: |settings, value| {
+            settings.skill_level = value.as_int();
+            None
+        },
+    },
+    UciOption {
+        name: "UCI_LimitStrength",
+        kind: OptionKind::Check { default: false },
+        apply: |settings, value| {
+            settings.limit_strength = value.as_bool();
+            None
+        },
+    },
+    UciOption {
+        name: "UCI_Elo",
+        kind: OptionKind::Spin { default: MIN_ELO, min: MIN_ELO, max: MAX_ELO },
+        apply: |settings, value| {
+            settings.elo = value.as_int();
+            None
+        },
+    },
+];
+
+impl UciOption {
+    // Checks a value from setoption against the kind of option, clamping spins
+    fn parse(&self, value: &str) -> Result<OptionValue, String> {
+        match &self.kind {
+            OptionKind::Spin { min, max, .. } => value.parse::<i64>()
+                .map(|value| OptionValue::Spin(value.clamp(*min, *max)))
+                .map_err(|_| format!("{} takes a number, not {:?}", self.name, value)),
+            OptionKind::Check { .. } => match value.to_ascii_lowercase().as_str() {
+                "true" => Ok(OptionValue::Check(true)),
+                "false" => Ok(OptionValue::Check(false)),
+                _ => Err(format!("{} is true or false, not {:?}", self.name, value)),
+            },
+            OptionKind::Combo { vars, .. } => vars.iter()
+                .find(|var| var.eq_ignore_ascii_case(value))
+                .map(|var| OptionValue::Text(var.to_string()))
+                .ok_or_else(|| format!("{} has no choice {:?}", self.name, value)),
+            OptionKind::Button => Ok(OptionValue::Button),
+            OptionKind::String { .. } if value == "<empty>" => Ok(OptionValue::Text(String::new())),
+            OptionKind::String { .. } => Ok(OptionValue::Text(value.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for UciOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "option name {} type ", self.name)?;
+        match &self.kind {
+            OptionKind::Spin { default, min, max } => write!(f, "spin default {} min {} max {}", default, min, max),
+            OptionKind::Check { default } => write!(f, "check default {}", default),
+            OptionKind::Combo { default, vars } => {
+                write!(f, "combo default {}", default)?;
+                vars.iter().try_for_each(|var| write!(f, " var {}", var))
+            }
+            OptionKind::Button => write!(f, "button"),
+            OptionKind::String { default: "" } => write!(f, "string default <empty>"),
+            OptionKind::String { default } => write!(f, "string default {}", default),
+        }
+    }
+}
+
+/// Applies `setoption name <name> value <value>`. Anything returned is for
+/// an info string: what the option reported, or why it was refused.
+fn set_option(settings: &mut EngineSettings, name: &str, value: &str) -> Option<String> {
+    let Some(option) = UCI_OPTIONS.iter().find(|option| option.name.eq_ignore_ascii_case(name)) else {
+        return Some(format!("no option {:?}", name));
+    };
+    match option.parse(value) {
+        Ok(value) => (option.apply)(settings, value),
+        Err(err) => Some(err),
+    }
+}
+
+//END OF UCI OPTIONS---------------------------------------------------------------------------------------
+
+//UCI PROTOCOL-------------------------------------------------------------------------------------------
+
+fn parse_fen(fen: &str) -> Option<BoardState> {
+    let parts: Vec<&str> = fen.split_whitespace().collect();
+    if parts.is_empty() {
+        return None;
+    }
+    
+    let board_str = parts[0];
+    let mut bitboards = [0u64; 12];
+    
+    let mut rank = 0;
+    let mut file = 0;
+    
+    for ch in board_str.chars() {
+        if ch == '/' {
+            rank += 1;
+            file = 0;
+            continue;
+        }
+        
+        if ch.is_ascii_digit() {
+            file += ch.to_digit(10)? as usize;
+            continue;
+        }
+        
+        if file >= 8 || rank >= 8 {
+            return None;
+        }
+        
+        let square = (rank * 8 + file) as u8;
+        let piece_index = match ch {
+            'P' => Some(WP),
+            'N' => Some(WN),
+            'B' => Some(WB),
+            'R' => Some(WR),
+            'Q' => Some(WQ),
+            'K' => Some(WK),
             'p' => Some(BP),
             'n' => Some(BN),
             'b' => Some(BB),
@@ -3205,6 +4099,10 @@ struct UCISearchParams {
     binc: Option<u64>,     // milliseconds
     movestogo: Option<u32>,
     infinite: bool,
+    ponder: bool,          // Searching the opponent's expected move on their time
+    nodes: Option<u64>,
+    mate: Option<u32>,     // moves
+    searchmoves: Vec<(u8, u8)>,
 }
 
 impl UCISearchParams {
@@ -3218,6 +4116,10 @@ impl UCISearchParams {
             binc: None,
             movestogo: None,
             infinite: false,
+            ponder: false,
+            nodes: None,
+            mate: None,
+            searchmoves: Vec::new(),
         }
     }
     
@@ -3274,6 +4176,30 @@ impl UCISearchParams {
                     params.infinite = true;
                     i += 1;
                 }
+                "ponder" => {
+                    params.ponder = true;
+                    i += 1;
+                }
+                "nodes" if i + 1 < parts.len() => {
+                    if let Ok(nodes) = parts[i + 1].parse::<u64>() {
+                        params.nodes = Some(nodes);
+                    }
+                    i += 2;
+                }
+                "mate" if i + 1 < parts.len() => {
+                    if let Ok(moves) = parts[i + 1].parse::<u32>() {
+                        params.mate = Some(moves);
+                    }
+                    i += 2;
+                }
+                "searchmoves" => {
+                    // Every move up to the next keyword
+                    i += 1;
+                    while let Some(mv) = parts.get(i).and_then(|part| uci_to_move(part)) {
+                        params.searchmoves.push(mv);
+                        i += 1;
+                    }
+                }
                 _ => {
                     i += 1;
                 }
@@ -3283,13 +4209,14 @@ impl UCISearchParams {
         params
     }
     
-    fn calculate_time_limit(&self, white_to_move: bool) -> Option<Duration> {
+    /// The time for this move, if the search has a clock to mind.
+    fn time_manager(&self, white_to_move: bool, game_ply: u32, overhead: u64) -> Option<TimeManager> {
         if self.infinite {
             return None;
         }
         
         if let Some(mt) = self.movetime {
-            return Some(Duration::from_millis(mt));
+            return Some(TimeManager::fixed(Duration::from_millis(mt.saturating_sub(overhead).max(1))));
         }
         
         let time_left = if white_to_move {
@@ -3304,155 +4231,517 @@ impl UCISearchParams {
             self.binc.unwrap_or(0)
         };
         
-        let moves_to_go = self.movestogo.unwrap_or(20) as u64;
-        
-        // Simple time management: use time_left / moves_to_go + increment
-        let time_per_move = time_left / moves_to_go.max(1) + increment;
-        
-        // Use 80% of calculated time to leave buffer
-        Some(Duration::from_millis((time_per_move * 80) / 100))
+        Some(TimeManager::from_clock(time_left, increment, self.movestogo, game_ply, overhead))
+    }
+}
+
+// Search threads including the main one, set by the Threads option
+static THREADS: AtomicUsize = AtomicUsize::new(1);
+const MAX_THREADS: usize = 64;
+// Lines reported by UCI searches, set by the MultiPV option
+static MULTI_PV: AtomicUsize = AtomicUsize::new(1);
+const MAX_MULTI_PV: usize = 256;
+// Search threads recurse deeper than the default thread stack allows for
+const SEARCH_STACK_SIZE: usize = 16 * 1024 * 1024;
+// Iterations for a search without a depth limit
+const MAX_SEARCH_DEPTH: u8 = 64;
+
+/// Outcome of a search: the move to play, its score for the side to move
+/// and the principal variation starting with that move.
+#[derive(Clone, Debug, PartialEq)]
+struct SearchResult {
+    best_move: (u8, u8),
+    score: i32,
+    depth: u8,
+    pv: Vec<(u8, u8)>,
+}
+
+impl SearchResult {
+    /// The reply we expect, for pondering.
+    fn ponder_move(&self) -> Option<(u8, u8)> {
+        self.pv.get(1).copied()
     }
 }
 
-static SEARCH_STOP: AtomicBool = AtomicBool::new(false);
+fn pv_to_uci(pv: &[(u8, u8)]) -> String {
+    pv.iter().map(|&(from, to)| move_to_uci(from, to)).collect::<Vec<_>>().join(" ")
+}
+
+// A TT cutoff ends the line where it happens; the table's best moves carry
+// it on up to the depth searched, as long as they stay legal
+fn extend_pv_from_tt(board_state: &BoardState, pv: &mut Vec<(u8, u8)>, depth: usize, tt: &TranspositionTable) {
+    let mut board = *board_state;
+    for &(from, to) in pv.iter() {
+        make_move(&mut board, from, to);
+    }
+    while pv.len() < depth {
+        match tt.best_move(compute_board_hash(&board)) {
+            Some(mv) if generate_legal_moves(&board).contains(&mv) => {
+                make_move(&mut board, mv.0, mv.1);
+                pv.push(mv);
+            }
+            _ => break,
+        }
+    }
+}
 
 fn find_best_move_with_time(
     board_state: &BoardState,
     max_depth: u8,
     time_limit: Option<Duration>,
 ) -> Option<(u8, u8)> {
-    SEARCH_STOP.store(false, Ordering::Relaxed);
+    search_position(board_state, max_depth, time_limit).map(|result| result.best_move)
+}
+
+/// Clock of a search, and its stop flag. A ponder search starts with the
+/// clock stopped, the time being the opponent's; ponderhit sets it running,
+/// so the time limit counts from the moment the move is ours.
+struct SearchClock {
+    start: Instant,
+    pondering: AtomicBool,
+    ponderhit_ms: AtomicU64, // Time from the start to ponderhit
+    stop: AtomicBool,        // Set by stop, quit and the like
+}
+
+impl SearchClock {
+    fn new(pondering: bool) -> Self {
+        Self {
+            start: Instant::now(),
+            pondering: AtomicBool::new(pondering),
+            ponderhit_ms: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        }
+    }
+    
+    fn pondering(&self) -> bool {
+        self.pondering.load(Ordering::Relaxed)
+    }
     
-    let start_time = Instant::now();
-    let mut best_move = None;
-    let mut best_score = if board_state.white_to_move { i32::MIN } else { i32::MAX };
-    let mut nodes_searched = 0u64;
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
     
-    // Output initial info to show engine is working
-    println!("info depth 0");
-    io::stdout().flush().ok();
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
     
-    // Iterative deepening with time management
-    for depth in 1..=max_depth {
-        if SEARCH_STOP.load(Ordering::Relaxed) {
-            break;
+    fn ponderhit(&self) {
+        self.ponderhit_ms.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        self.pondering.store(false, Ordering::Relaxed);
+    }
+    
+    // Time spent on our own clock
+    fn elapsed(&self) -> Duration {
+        if self.pondering() {
+            return Duration::ZERO;
+        }
+        self.start.elapsed().saturating_sub(Duration::from_millis(self.ponderhit_ms.load(Ordering::Relaxed)))
+    }
+}
+
+/// Where protocol replies go. The search thread shares it, printing its
+/// progress and its move while commands are still being read.
+#[derive(Clone)]
+struct Output(Arc<Mutex<dyn Write + Send>>);
+
+impl Output {
+    fn new(writer: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+    
+    // Lines written under one lock come out together
+    fn lock(&self) -> std::sync::MutexGuard<'_, dyn Write + Send + 'static> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+/// How a search reports each completed iteration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Thinking {
+    Uci,    // info lines
+    XBoard, // ply score time nodes pv
+    Silent,
+}
+
+/// What ends a search, whichever comes first, and what it looks at.
+#[derive(Clone, Debug)]
+struct SearchLimits {
+    depth: u8,
+    time: Option<TimeManager>,
+    nodes: Option<u64>,         // A hard limit, searched on the main thread alone
+    mate: Option<u32>,          // Stop once a mate in this many moves is proven
+    searchmoves: Vec<(u8, u8)>, // Root moves to choose from; empty for all
+    multipv: usize,
+    output: Thinking,
+    out: Output,
+    tt: Arc<TranspositionTable>, // The Hash table, unless given one of its own
+}
+
+impl SearchLimits {
+    fn new(depth: u8, time: Option<Duration>) -> Self {
+        Self {
+            depth,
+            time: time.map(TimeManager::fixed),
+            nodes: None,
+            mate: None,
+            searchmoves: Vec::new(),
+            multipv: 1,
+            // Quiet unless a protocol loop asks for its thinking
+            output: Thinking::Silent,
+            out: Output::new(io::sink()),
+            tt: get_transposition_table(),
+        }
+    }
+}
+
+/// Iterative deepening up to `max_depth` or the time limit, without output.
+/// Runs on as many threads as the Threads option.
+fn search_position(
+    board_state: &BoardState,
+    max_depth: u8,
+    time_limit: Option<Duration>,
+) -> Option<SearchResult> {
+    search_lines(board_state, max_depth, time_limit, 1).into_iter().next()
+}
+
+/// Searches for the best `multipv` moves, each with its score and principal
+/// variation, ranked best first. Fewer come back when there are fewer legal
+/// moves.
+fn search_lines(
+    board_state: &BoardState,
+    max_depth: u8,
+    time_limit: Option<Duration>,
+    multipv: usize,
+) -> Vec<SearchResult> {
+    let limits = SearchLimits { multipv, ..SearchLimits::new(max_depth, time_limit) };
+    run_search(board_state, &limits, THREADS.load(Ordering::Relaxed), &SearchClock::new(false))
+}
+
+fn run_search(
+    board_state: &BoardState,
+    limits: &SearchLimits,
+    threads: usize,
+    clock: &SearchClock,
+) -> Vec<SearchResult> {
+    syzygy::TB_HITS.store(0, Ordering::Relaxed);
+    let max_depth = limits.depth;
+    let mut time = limits.time.clone();
+    let allowed = |mv: &(u8, u8)| limits.searchmoves.is_empty() || limits.searchmoves.contains(mv);
+    
+    // With the position in the tablebases, play the move that converts fastest
+    let tb_ranked = syzygy::probe_root(board_state)
+        .map(|ranked| ranked.into_iter().filter(|(mv, _)| allowed(mv)).collect::<Vec<_>>());
+    if let Some(ranked) = tb_ranked && !ranked.is_empty() {
+        let lines: Vec<SearchResult> = ranked.iter().take(limits.multipv.max(1)).map(|&(mv, dtz)| {
+            let score = match dtz {
+                1.. => TB_WIN_SCORE - dtz,
+                0 => 0,
+                _ => -TB_WIN_SCORE - dtz,
+            };
+            SearchResult { best_move: mv, score, depth: 1, pv: vec![mv] }
+        }).collect();
+        let mut out = limits.out.lock();
+        match limits.output {
+            Thinking::Uci => for (index, line) in lines.iter().enumerate() {
+                writeln!(out, "info depth 1 multipv {} score cp {} tbhits {} pv {}",
+                    index + 1,
+                    line.score,
+                    syzygy::TB_HITS.load(Ordering::Relaxed),
+                    pv_to_uci(&line.pv)
+                ).ok();
+            },
+            Thinking::XBoard => {
+                writeln!(out, "1 {} 0 0 {}", xboard_score(lines[0].score), pv_to_uci(&lines[0].pv)).ok();
+            }
+            Thinking::Silent => {}
+        }
+        out.flush().ok();
+        return lines;
+    }
+    if let Some((mv, dtm)) = retrograde::best_move(board_state) && allowed(&mv) {
+        // The distance to mate is exact here, so it is reported as a mate
+        let score = match dtm {
+            retrograde::Dtm::Win(plies) => MATE - plies as i32,
+            retrograde::Dtm::Loss(plies) => -MATE + plies as i32,
+            retrograde::Dtm::Draw => 0,
+        };
+        let mut out = limits.out.lock();
+        match limits.output {
+            Thinking::Uci => writeln!(out, "info depth 1 multipv 1 score {} tbhits {} pv {}",
+                uci_score(score),
+                syzygy::TB_HITS.load(Ordering::Relaxed),
+                move_to_uci(mv.0, mv.1)
+            ),
+            Thinking::XBoard => writeln!(out, "1 {} 0 0 {}", xboard_score(score), move_to_uci(mv.0, mv.1)),
+            Thinking::Silent => Ok(()),
+        }.ok();
+        out.flush().ok();
+        return vec![SearchResult { best_move: mv, score, depth: 1, pv: vec![mv] }];
+    }
+    
+    // The lines of the last completed iteration, best first
+    let mut lines: Vec<SearchResult> = Vec::new();
+    
+    let mut legal_moves = generate_legal_moves(board_state);
+    if legal_moves.iter().any(allowed) {
+        legal_moves.retain(allowed);
+    }
+    if legal_moves.is_empty() {
+        return lines;
+    }
+    let multipv = limits.multipv.clamp(1, legal_moves.len());
+    let mut root_moves: Vec<(u8, u8)> = order_moves(board_state, &legal_moves, None).into_iter()
+        .map(|(_, from, to)| (from, to))
+        .collect();
+    
+    let tt = &*limits.tt;
+    tt.new_search();
+    let mut search_state = SearchState::new(*board_state);
+    // A stop or a limit can cut any iteration short, the first included
+    search_state.stop = Some(&clock.stop);
+    search_state.node_limit = limits.nodes;
+    search_state.time_limit = time.as_ref().map(|time| (clock, time.maximum));
+    
+    // Helpers only report their nodes between iterations, which would let a
+    // node limit overshoot, so a node-limited search is single-threaded
+    let threads = if limits.nodes.is_some() { 1 } else { threads.clamp(1, MAX_THREADS) };
+    let helper_nodes: Vec<AtomicU64> = (1..threads).map(|_| AtomicU64::new(0)).collect();
+    // Tells the helpers the main thread is done
+    let helpers_stop = AtomicBool::new(false);
+    
+    std::thread::scope(|scope| {
+        for (index, nodes) in helper_nodes.iter().enumerate() {
+            let board = *board_state;
+            let root_moves = root_moves.clone();
+            let helpers_stop = &helpers_stop;
+            // Failing to start a helper only costs speed, the main thread searches regardless
+            std::thread::Builder::new()
+                .stack_size(SEARCH_STACK_SIZE)
+                .spawn_scoped(scope, move || helper_search(board, root_moves, max_depth, index + 1, tt, nodes, helpers_stop))
+                .ok();
         }
         
-        // Check time before starting new depth
-        if let Some(limit) = time_limit {
-            let elapsed = start_time.elapsed();
-            if elapsed >= limit {
+        // Iterative deepening with time management
+        for depth in 1..=max_depth {
+            if clock.stopped() || limits.nodes.is_some_and(|nodes| search_state.nodes >= nodes) {
                 break;
             }
-            // Reserve some time for the next iteration
-            let remaining = limit.saturating_sub(elapsed);
-            if remaining < Duration::from_millis(50) {
-                break; // Not enough time for another depth
+        
+            // Each line searches the root moves the lines before it left over
+            search_state.seldepth = 0;
+            let mut depth_lines: Vec<SearchResult> = Vec::with_capacity(multipv);
+            for pv_index in 0..multipv {
+                let previous_score = lines.get(pv_index).map(|line| line.score);
+                let (score, mv) = aspiration_search(&mut search_state, &root_moves[pv_index..], depth as i32, previous_score, tt);
+                if search_state.stopped {
+                    break;
+                }
+                let mut pv = search_state.pv[0].clone();
+                if pv.first() != Some(&mv) {
+                    pv = vec![mv];
+                }
+                extend_pv_from_tt(board_state, &mut pv, depth as usize, tt);
+                depth_lines.push(SearchResult { best_move: mv, score, depth, pv });
+                
+                let index = pv_index + root_moves[pv_index..].iter().position(|&root_move| root_move == mv).unwrap();
+                root_moves[pv_index..=index].rotate_right(1);
             }
-        }
+            // A later line can outscore an earlier one its window cut short
+            depth_lines.sort_by_key(|line| std::cmp::Reverse(line.score));
+            if search_state.stopped {
+                // The last completed iteration stands; cut short in the first,
+                // the lines it finished are all there is
+                if lines.is_empty() {
+                    lines = depth_lines;
+                }
+                break;
+            }
+            
+            // The ranked moves are searched first, in order, in the next iteration
+            for (pv_index, line) in depth_lines.iter().enumerate() {
+                let index = root_moves.iter().position(|&root_move| root_move == line.best_move).unwrap();
+                root_moves[pv_index..=index].rotate_right(1);
+            }
+            lines = depth_lines;
+            let (score, mv) = (lines[0].score, lines[0].best_move);
+            tt.store(compute_board_hash(board_state), depth as i32, score, 0, mv, 0);
         
-        // Output info before starting depth search
-        let time_ms = start_time.elapsed().as_millis() as u64;
-        if time_ms > 0 {
-            let nps = (nodes_searched * 1000) / time_ms.max(1);
-            println!("info depth {} nodes {} time {} nps {}",
-                depth - 1,
-                nodes_searched,
-                time_ms,
-                nps
-            );
-            io::stdout().flush().ok();
-        }
+            let time_ms = clock.start.elapsed().as_millis() as u64;
+            let nodes = search_state.nodes + helper_nodes.iter().map(|nodes| nodes.load(Ordering::Relaxed)).sum::<u64>();
+            let nps = nodes * 1000 / time_ms.max(1);
         
-        if let Some(mv) = find_best_move(board_state, depth) {
-            best_move = Some(mv);
-            
-            // Get evaluation after the move
-            let mut test_state = *board_state;
-            if make_move(&mut test_state, mv.0, mv.1).is_some() {
-                best_score = evaluate_board_advanced(&test_state);
+            let mut out = limits.out.lock();
+            match limits.output {
+                Thinking::Uci => for (pv_index, line) in lines.iter().enumerate() {
+                    writeln!(out, "info depth {} seldepth {} multipv {} score {} nodes {} nps {} time {} hashfull {} tbhits {} pv {}",
+                        depth,
+                        search_state.seldepth.max(depth as usize), // TT cutoffs can end every line early
+                        pv_index + 1,
+                        uci_score(line.score),
+                        nodes,
+                        nps,
+                        time_ms,
+                        tt.hashfull(),
+                        syzygy::TB_HITS.load(Ordering::Relaxed),
+                        pv_to_uci(&line.pv)
+                    ).ok();
+                },
+                // XBoard has one line, and time in centiseconds
+                Thinking::XBoard => {
+                    writeln!(out, "{} {} {} {} {}", depth, xboard_score(score), time_ms / 10, nodes, pv_to_uci(&lines[0].pv)).ok();
+                }
+                Thinking::Silent => {}
+            }
+            out.flush().ok();
+            drop(out);
+        
+            // A mate found within the full depth can't get any shorter
+            if is_mate_score(score) && MATE - score.abs() <= depth as i32 {
+                break;
             }
             
-            // Estimate nodes searched (rough approximation)
-            nodes_searched = estimate_nodes_searched(board_state, depth);
-            let time_ms = start_time.elapsed().as_millis() as u64;
-            let nps = if time_ms > 0 { (nodes_searched * 1000) / time_ms.max(1) } else { 0 };
-            
-            // Build PV - for now just show the best move, but format it properly
-            let pv_str = move_to_uci(mv.0, mv.1);
-            
-            // Output UCI info with proper format
-            println!("info depth {} score cp {} nodes {} time {} nps {} pv {}",
-                depth,
-                best_score,
-                nodes_searched,
-                time_ms,
-                nps,
-                pv_str
-            );
-            
-            io::stdout().flush().ok();
-            
-            // If we found a mate, we can stop early
-            if best_score.abs() > 9000 {
+            // A mate search is over once it has its mate, in plies 2 * moves - 1
+            if let Some(moves) = limits.mate && score >= MATE - (2 * moves as i32 - 1) {
                 break;
             }
-        } else {
-            // No move found - might be checkmate/stalemate
-            break;
-        }
         
-        // Check if we should stop after this depth
-        if SEARCH_STOP.load(Ordering::Relaxed) {
-            break;
-        }
-        
-        if let Some(limit) = time_limit {
-            if start_time.elapsed() >= limit {
+            // Check if we should stop after this depth
+            if clock.stopped() {
                 break;
             }
+        
+            // Time is up, or there's no point spending more: a single legal
+            // move needs no search. Pondering, none of our time has been used
+            if let Some(time) = &mut time {
+                time.update(mv, score);
+                let forced = time.adaptive && root_moves.len() == 1;
+                if !clock.pondering() && (forced || time.should_stop(clock.elapsed())) {
+                    break;
+                }
+            }
+        }
+        
+        // The main thread's move stands; the helpers only fed the table
+        helpers_stop.store(true, Ordering::Relaxed);
+        
+        // Stopped before the first line finished: the move ordering's first
+        // choice, at the static evaluation
+        if lines.is_empty() {
+            let mv = root_moves[0];
+            lines.push(SearchResult { best_move: mv, score: evaluate(board_state), depth: 0, pv: vec![mv] });
+        }
+        lines
+    })
+}
+
+/// Lazy SMP helper: runs its own iterative deepening on the shared table
+/// until the main thread finishes. Helpers start at staggered depths and
+/// try the root moves after the first in a rotated order, so they spread
+/// over the tree instead of repeating the main thread's work.
+fn helper_search(
+    board_state: BoardState,
+    mut root_moves: Vec<(u8, u8)>,
+    max_depth: u8,
+    thread_id: usize,
+    tt: &TranspositionTable,
+    nodes: &AtomicU64,
+    stop: &AtomicBool,
+) {
+    if root_moves.len() > 2 {
+        let others = root_moves.len() - 1;
+        root_moves[1..].rotate_left(thread_id % others);
+    }
+    let mut search_state = SearchState::new(board_state);
+    search_state.stop = Some(stop);
+    let mut previous_score = None;
+    
+    for depth in (1 + thread_id % 2) as u8..=max_depth {
+        let (score, mv) = aspiration_search(&mut search_state, &root_moves, depth as i32, previous_score, tt);
+        nodes.store(search_state.nodes, Ordering::Relaxed);
+        if search_state.stopped {
+            break;
         }
+        previous_score = Some(score);
+        let index = root_moves.iter().position(|&root_move| root_move == mv).unwrap();
+        root_moves[..=index].rotate_right(1);
     }
-    
-    best_move
 }
 
-fn parse_xboard_move(move_str: &str) -> Option<(u8, u8)> {
-    // XBoard moves are in format like "d2d4" or "e7e8q" (with promotion)
-    if move_str.len() < 4 {
-        return None;
+/// Prints the search result as the `bestmove` reply, falling back to any
+/// legal move when the search had none.
+fn print_best_move(out: &Output, board_state: &BoardState, result: Option<SearchResult>) {
+    let mut out = out.lock();
+    if let Some(result) = result {
+        let (from, to) = result.best_move;
+        match result.ponder_move() {
+            Some((ponder_from, ponder_to)) => writeln!(out, "bestmove {} ponder {}",
+                move_to_uci(from, to), move_to_uci(ponder_from, ponder_to)),
+            None => writeln!(out, "bestmove {}", move_to_uci(from, to)),
+        }.ok();
+    } else if let Some(&(from, to)) = generate_legal_moves(board_state).first() {
+        writeln!(out, "bestmove {}", move_to_uci(from, to)).ok();
+    } else {
+        writeln!(out, "bestmove 0000").ok(); // No move found (checkmate/stalemate)
     }
-    
-    let from_coords = &move_str[0..2];
-    let to_coords = &move_str[2..4];
-    
-    let from = coordinates_to_square(from_coords)?;
-    let to = coordinates_to_square(to_coords)?;
-    
-    Some((from, to))
+    out.flush().ok();
+}
+
+/// Stops the search running on the worker thread, if any, and waits for it
+/// to print its `bestmove`.
+fn stop_search(search_thread: &mut Option<std::thread::JoinHandle<()>>, clock: &SearchClock) {
+    if let Some(handle) = search_thread.take() {
+        clock.stop();
+        handle.thread().unpark();
+        handle.join().ok();
+    }
+}
+
+// Plays the moves of a position command, stopping at the first that is
+// malformed or illegal. Gives the number played
+fn play_uci_moves(board_state: &mut BoardState, moves: &[&str], out: &mut dyn Write) -> u32 {
+    let mut played = 0;
+    for &mv in moves {
+        let legal = uci_to_move(mv).filter(|mv| generate_legal_moves(board_state).contains(mv));
+        let Some((from, to)) = legal else {
+            writeln!(out, "info string invalid move {}", mv).ok();
+            break;
+        };
+        make_move(board_state, from, to);
+        played += 1;
+    }
+    played
 }
 
-fn uci_loop() {
-    // Ensure stdout is line buffered for UCI protocol
-    let stdout = io::stdout();
-    let mut stdout_handle = stdout.lock();
+/// Reads UCI commands, or XBoard ones once told `xboard`, until quit or the
+/// end of the input. Replies go to `output`, shared with the search thread,
+/// so a session can be scripted in memory as well as run over stdin/stdout.
+fn uci_loop(mut input: impl BufRead, output: impl Write + Send + 'static) {
+    let out = Output::new(output);
     
     let mut board_state = BoardState::new();
-    let stdin = io::stdin();
-    let mut stdin_handle = stdin.lock();
+    // Half-moves since the start of the game, for BookDepth
+    let mut game_ply = 0u32;
     let mut buffer = String::new();
     
-    // Track protocol mode
-    let mut xboard_mode = false;
-    let mut time_remaining = 30000u64; // centiseconds
-    let mut opponent_time = 30000u64;
-    let mut moves_per_session = 40u32;
-    let mut base_time = 300u64; // seconds
-    let mut increment = 0u64; // seconds
+    // Set once the GUI turns out to speak XBoard rather than UCI
+    let mut xboard: Option<XBoard> = None;
     
-    loop {
+    // Opening book and other options that setoption changes
+    let mut settings = EngineSettings::new();
+    
+    // The search runs here so that stop, isready and quit are read meanwhile
+    let mut search_thread: Option<std::thread::JoinHandle<()>> = None;
+    let mut search_clock = Arc::new(SearchClock::new(false));
+    
+    loop {
         buffer.clear();
-        match stdin_handle.read_line(&mut buffer) {
+        match input.read_line(&mut buffer) {
             Ok(0) => break, // EOF
             Ok(_) => {
                 let line = buffer.trim();
@@ -3465,129 +4754,26 @@ fn uci_loop() {
                     continue;
                 }
                 
-                // Handle XBoard protocol
-                if xboard_mode || parts[0] == "xboard" {
-                    match parts[0] {
-                        "xboard" => {
-                            xboard_mode = true;
-                            // Don't send response, just switch mode
-                        }
-                        "protover" => {
-                            if parts.len() > 1 && parts[1] == "2" {
-                                writeln!(stdout_handle, "feature done=0").ok();
-                                writeln!(stdout_handle, "feature myname=\"Rust Chess Engine\"").ok();
-                                writeln!(stdout_handle, "feature done=1").ok();
-                                stdout_handle.flush().ok();
-                            }
-                        }
-                        "new" => {
-                            board_state = BoardState::new();
-                            // Clear transposition table
-                            if let Some(tt) = TRANSPOSITION_TABLE.get() {
-                                tt.write().unwrap().clear();
-                            }
-                        }
-                        "time" => {
-                            if parts.len() > 1 {
-                                if let Ok(t) = parts[1].parse::<u64>() {
-                                    time_remaining = t;
-                                }
-                            }
-                        }
-                        "otim" => {
-                            if parts.len() > 1 {
-                                if let Ok(t) = parts[1].parse::<u64>() {
-                                    opponent_time = t;
-                                }
-                            }
-                        }
-                        "level" => {
-                            // level <moves> <minutes> <seconds> or level <moves> <base> <inc>
-                            // Format: level 0 5 0 means 0 moves in 5 minutes 0 seconds
-                            if parts.len() >= 3 {
-                                if let (Ok(moves), Ok(base), Ok(inc)) = (
-                                    parts[1].parse::<u32>(),
-                                    parts[2].parse::<u64>(),
-                                    if parts.len() > 3 { parts[3].parse::<u64>() } else { Ok(0) }
-                                ) {
-                                    moves_per_session = if moves == 0 { 40 } else { moves }; // Default to 40 if 0
-                                    base_time = base * 60 + inc; // Convert to total seconds
-                                    increment = 0; // Increment handled separately
-                                }
-                            }
-                        }
-                        "post" => {
-                            // Show thinking - already enabled by default
-                        }
-                        "hard" => {
-                            // Use all available time
-                        }
-                        "easy" => {
-                            // Don't use all available time
-                        }
-                        "random" => {
-                            // Random mode - ignore for now
-                        }
-                        "quit" => {
-                            break;
-                        }
-                        _ => {
-                            // Try to parse as a move (e.g., "d2d4")
-                            if let Some((from, to)) = parse_xboard_move(parts[0]) {
-                                // Make the opponent's move
-                                if make_move(&mut board_state, from, to).is_some() {
-                                    // Calculate time limit from time remaining
-                                    // Use time_remaining (in centiseconds) or calculate from level
-                                    let time_limit_ms = if time_remaining > 0 {
-                                        // Use 80% of remaining time, convert centiseconds to milliseconds
-                                        Duration::from_millis((time_remaining * 8) / 10)
-                                    } else if moves_per_session > 0 {
-                                        // Calculate from level: base_time is in seconds
-                                        let time_per_move = (base_time * 1000) / moves_per_session as u64;
-                                        Duration::from_millis(time_per_move)
-                                    } else {
-                                        Duration::from_secs(5)
-                                    };
-                                    
-                                    // Find best move (use reasonable depth)
-                                    let result = find_best_move_with_time(&board_state, 4, Some(time_limit_ms));
-                                    
-                                    if let Some((from_move, to_move)) = result {
-                                        // Output move in XBoard format
-                                        let move_str = format!("{}{}", 
-                                            square_to_coordinates(from_move),
-                                            square_to_coordinates(to_move)
-                                        );
-                                        writeln!(stdout_handle, "move {}", move_str).ok();
-                                        stdout_handle.flush().ok();
-                                    } else {
-                                        // Try fallback
-                                        let moves = generate_moves(board_state.bitboards, board_state.white_to_move, &board_state);
-                                        let legal_moves: Vec<(u8, u8)> = moves.into_iter()
-                                            .filter(|&(from, to)| is_move_legal_fast(&board_state, from, to))
-                                            .collect();
-                                        
-                                        if let Some(&(from_move, to_move)) = legal_moves.first() {
-                                            let move_str = format!("{}{}", 
-                                                square_to_coordinates(from_move),
-                                                square_to_coordinates(to_move)
-                                            );
-                                            writeln!(stdout_handle, "move {}", move_str).ok();
-                                            stdout_handle.flush().ok();
-                                        }
-                                    }
-                                }
-                            }
-                        }
+                // Once told xboard, the GUI speaks CECP for the rest of the session
+                if parts[0] == "xboard" {
+                    xboard = Some(XBoard::new(out.clone()));
+                }
+                if let Some(xboard) = &mut xboard {
+                    if !xboard.command(line, &settings) {
+                        break;
                     }
                     continue;
                 }
                 
                 // Handle UCI protocol
+                let mut stdout_handle = out.lock();
                 match parts[0] {
                     "uci" => {
                         writeln!(stdout_handle, "id name Rust Chess Engine").ok();
                         writeln!(stdout_handle, "id author Chess Engine Developer").ok();
+                        for option in UCI_OPTIONS {
+                            writeln!(stdout_handle, "{}", option).ok();
+                        }
                         writeln!(stdout_handle, "uciok").ok();
                         stdout_handle.flush().ok();
                     }
@@ -3595,12 +4781,21 @@ fn uci_loop() {
                         writeln!(stdout_handle, "readyok").ok();
                         stdout_handle.flush().ok();
                     }
+                    "setoption" => {
+                        // setoption name <id> [value <x>]; names and values may contain spaces
+                        let value_idx = parts.iter().position(|&s| s == "value");
+                        let name = parts[2.min(parts.len())..value_idx.unwrap_or(parts.len())].join(" ");
+                        let value = value_idx.map(|idx| parts[idx + 1..].join(" ")).unwrap_or_default();
+                        
+                        if let Some(message) = set_option(&mut settings, &name, &value) {
+                            writeln!(stdout_handle, "info string {}", message).ok();
+                            stdout_handle.flush().ok();
+                        }
+                    }
                     "ucinewgame" => {
                         board_state = BoardState::new();
                         // Clear transposition table
-                        if let Some(tt) = TRANSPOSITION_TABLE.get() {
-                            tt.write().unwrap().clear();
-                        }
+                        get_transposition_table().clear();
                     }
                     "position" => {
                         if parts.len() < 2 {
@@ -3609,17 +4804,11 @@ fn uci_loop() {
                         
                         if parts[1] == "startpos" {
                             board_state = BoardState::new();
+                            game_ply = 0;
                             
                             // Parse moves if any
                             if parts.len() > 2 && parts[2] == "moves" {
-                                for i in 3..parts.len() {
-                                    if let Some((from, to)) = uci_to_move(parts[i]) {
-                                        if make_move(&mut board_state, from, to).is_none() {
-                                            eprintln!("Invalid move: {}", parts[i]);
-                                            break;
-                                        }
-                                    }
-                                }
+                                game_ply += play_uci_moves(&mut board_state, &parts[3..], &mut *stdout_handle);
                             }
                         } else if parts[1] == "fen" {
                             // Parse FEN string
@@ -3629,56 +4818,98 @@ fn uci_loop() {
                             if let Some(new_state) = parse_fen(&fen) {
                                 board_state = new_state;
                                 
+                                // Rebuild the game ply from the fullmove number
+                                let fullmove = fen_parts.get(5).and_then(|n| n.parse::<u32>().ok()).unwrap_or(1);
+                                game_ply = 2 * fullmove.saturating_sub(1) + !board_state.white_to_move as u32;
+                                
                                 // Parse moves if any
                                 if let Some(moves_idx) = parts.iter().position(|&s| s == "moves") {
-                                    for i in moves_idx + 1..parts.len() {
-                                        if let Some((from, to)) = uci_to_move(parts[i]) {
-                                            if make_move(&mut board_state, from, to).is_none() {
-                                                eprintln!("Invalid move: {}", parts[i]);
-                                                break;
-                                            }
-                                        }
-                                    }
+                                    game_ply += play_uci_moves(&mut board_state, &parts[moves_idx + 1..], &mut *stdout_handle);
                                 }
+                            } else {
+                                writeln!(stdout_handle, "info string invalid fen {}", fen).ok();
                             }
                         }
                     }
                     "go" => {
+                        // A new search replaces one still running
+                        drop(stdout_handle);
+                        stop_search(&mut search_thread, &search_clock);
+                        let mut stdout_handle = out.lock();
                         let go_cmd = line;
                         let params = UCISearchParams::parse_go_command(go_cmd);
                         
-                        // Use a reasonable default depth and time limit
-                        // Start with depth 3 for faster response, increase if time allows
-                        let max_depth = params.depth.unwrap_or(6);
-                        let time_limit = params.calculate_time_limit(board_state.white_to_move);
-                        
-                        // Ensure we have at least some time limit to prevent hanging
-                        // Use shorter default timeout for faster response
-                        let effective_time_limit = time_limit.or_else(|| Some(Duration::from_secs(5)));
+                        // Play from the book while the game is still shallow enough
+                        if settings.own_book && game_ply < 2 * settings.book_depth
+                            && let Some((from, to)) = settings.book.as_ref().and_then(|book| book.pick(&board_state, settings.book_best_move)) {
+                            writeln!(stdout_handle, "info string book move").ok();
+                            writeln!(stdout_handle, "bestmove {}", move_to_uci(from, to)).ok();
+                            stdout_handle.flush().ok();
+                            continue;
+                        }
                         
-                        // Run search synchronously (UCI engines typically block on go command)
-                        let result = find_best_move_with_time(&board_state, max_depth, effective_time_limit);
+                        // Use a reasonable default depth and time limit; node, mate and
+                        // timed searches go as deep as they must
+                        let overhead = MOVE_OVERHEAD.load(Ordering::Relaxed);
+                        let time = params.time_manager(board_state.white_to_move, game_ply, overhead);
+                        let unbounded = params.infinite || params.nodes.is_some() || params.mate.is_some();
+                        let max_depth = params.depth.unwrap_or(if unbounded || time.is_some() { MAX_SEARCH_DEPTH } else { 6 });
                         
-                        if let Some((from, to)) = result {
-                            writeln!(stdout_handle, "bestmove {}", move_to_uci(from, to)).ok();
+                        // A bare go gets a default time limit so it can't hang; infinite
+                        // analysis and the other limits run as long as they take
+                        let time = if unbounded || params.depth.is_some() {
+                            time
                         } else {
-                            // Try to find any legal move as fallback
-                            let moves = generate_moves(board_state.bitboards, board_state.white_to_move, &board_state);
-                            let legal_moves: Vec<(u8, u8)> = moves.into_iter()
-                                .filter(|&(from, to)| is_move_legal_fast(&board_state, from, to))
-                                .collect();
-                            
-                            if let Some(&(from, to)) = legal_moves.first() {
-                                writeln!(stdout_handle, "bestmove {}", move_to_uci(from, to)).ok();
-                            } else {
-                                writeln!(stdout_handle, "bestmove 0000").ok(); // No move found (checkmate/stalemate)
-                            }
+                            time.or_else(|| Some(TimeManager::fixed(Duration::from_secs(5))))
+                        };
+                        let mut limits = SearchLimits {
+                            time,
+                            nodes: params.nodes,
+                            mate: params.mate,
+                            searchmoves: params.searchmoves.clone(),
+                            multipv: MULTI_PV.load(Ordering::Relaxed),
+                            output: Thinking::Uci,
+                            out: out.clone(),
+                            ..SearchLimits::new(max_depth, None)
+                        };
+                        let skill = settings.skill();
+                        if let Some(skill) = skill {
+                            skill.limit(&mut limits);
                         }
                         
-                        stdout_handle.flush().ok();
+                        let board = board_state;
+                        let infinite = params.infinite;
+                        search_clock = Arc::new(SearchClock::new(params.ponder));
+                        let clock = Arc::clone(&search_clock);
+                        search_thread = std::thread::Builder::new()
+                            .stack_size(SEARCH_STACK_SIZE)
+                            .spawn(move || {
+                                let threads = THREADS.load(Ordering::Relaxed);
+                                let lines = run_search(&board, &limits, threads, &clock);
+                                let result = match skill {
+                                    Some(skill) => skill.pick(lines, &mut rand::rng()),
+                                    None => lines.into_iter().next(),
+                                };
+                                // Infinite analysis holds its move back until stopped, and
+                                // a ponder search until the opponent plays or it is stopped
+                                while (infinite || clock.pondering()) && !clock.stopped() {
+                                    std::thread::park();
+                                }
+                                print_best_move(&limits.out, &board, result);
+                            })
+                            .ok();
                     }
                     "stop" => {
-                        SEARCH_STOP.store(true, Ordering::Relaxed);
+                        // The search thread prints its bestmove before this one writes again
+                        drop(stdout_handle);
+                        stop_search(&mut search_thread, &search_clock);
+                    }
+                    "ponderhit" => {
+                        // The expected move was played: the same search goes on, now on our clock
+                        search_clock.ponderhit();
+                        if let Some(handle) = &search_thread {
+                            handle.thread().unpark();
+                        }
                     }
                     "quit" => {
                         break;
@@ -3691,18 +4922,539 @@ fn uci_loop() {
             Err(_) => break,
         }
     }
+    
+    stop_search(&mut search_thread, &search_clock);
 }
 
 //END OF UCI PROTOCOL------------------------------------------------------------------------------------
 
 
+//XBOARD PROTOCOL-----------------------------------------------------------------------------------------
+
+// Sent in reply to protover 2. Moves come as usermove, positions as setboard,
+// and the engine never has to act on its own on signals or draw offers
+const XBOARD_FEATURES: &[&str] = &[
+    "myname=\"Rust Chess Engine\"",
+    "variants=\"normal\"",
+    "ping=1",
+    "setboard=1",
+    "playother=1",
+    "usermove=1",
+    "time=1",
+    "analyze=1",
+    "reuse=1",
+    "memory=1",
+    "smp=1",
+    "san=0",
+    "draw=0",
+    "colors=0",
+    "sigint=0",
+    "sigterm=0",
+    "name=0",
+    "nps=0",
+];
+
+fn parse_xboard_move(move_str: &str) -> Option<(u8, u8)> {
+    // XBoard moves are in format like "d2d4" or "e7e8q" (with promotion)
+    if move_str.len() < 4 {
+        return None;
+    }
+    
+    let from_coords = &move_str[0..2];
+    let to_coords = &move_str[2..4];
+    
+    let from = coordinates_to_square(from_coords)?;
+    let to = coordinates_to_square(to_coords)?;
+    
+    Some((from, to))
+}
+
+// Seconds, possibly with a fraction, as in st and the level increment
+fn parse_seconds(text: &str) -> Option<Duration> {
+    text.parse::<f64>().ok().filter(|secs| *secs >= 0.0).map(Duration::from_secs_f64)
+}
+
+// The level base time: minutes, or minutes:seconds
+fn parse_base_time(text: &str) -> Option<Duration> {
+    let (minutes, seconds) = text.split_once(':').unwrap_or((text, "0"));
+    Some(Duration::from_secs(minutes.parse::<u64>().ok()? * 60 + seconds.parse::<u64>().ok()?))
+}
+
+/// A game played over the XBoard (CECP v2) protocol. The engine thinks on a
+/// worker thread, as for a UCI search, which prints its move and hands it
+/// back to be played on the board kept here.
+struct XBoard {
+    board: BoardState,
+    history: Vec<BoardState>, // The position before each move, for undo and remove
+    game_ply: u32,
+    force: bool,              // Only recording moves, playing neither side
+    engine_white: bool,       // The side the engine plays when not in force mode
+    post: bool,               // Print thinking
+    analyzing: bool,
+    time_left: u64,           // Centiseconds on the engine's clock
+    moves_per_session: u32,   // 0 for the whole game in one session
+    increment: Duration,
+    move_time: Option<Duration>, // Fixed time per move, from st
+    depth_limit: Option<u8>,     // From sd
+    search: Option<std::thread::JoinHandle<Option<(u8, u8)>>>,
+    clock: Arc<SearchClock>,
+    cancelled: Arc<AtomicBool>,  // Tells the worker not to play the move it finds
+    out: Output,
+}
+
+impl XBoard {
+    fn new(out: Output) -> Self {
+        Self {
+            board: BoardState::new(),
+            history: Vec::new(),
+            game_ply: 0,
+            force: false,
+            engine_white: false,
+            post: true,
+            analyzing: false,
+            time_left: 30000,
+            moves_per_session: 40,
+            increment: Duration::ZERO,
+            move_time: None,
+            depth_limit: None,
+            search: None,
+            clock: Arc::new(SearchClock::new(false)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            out,
+        }
+    }
+    
+    // A line for the GUI, flushed at once
+    fn say(&self, line: std::fmt::Arguments) {
+        let mut out = self.out.lock();
+        writeln!(out, "{}", line).ok();
+        out.flush().ok();
+    }
+    
+    /// Handles one line from the GUI. False once it says quit.
+    fn command(&mut self, line: &str, settings: &EngineSettings) -> bool {
+        // A move the worker has printed since the last command is on the board from now on
+        if self.search.as_ref().is_some_and(|search| search.is_finished()) {
+            self.finish_search(false);
+        }
+        
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let argument = parts.get(1).copied().unwrap_or("");
+        match parts[0] {
+            "xboard" | "accepted" | "rejected" | "otim" | "draw" | "hint" | "bk" | "hard" | "easy"
+                | "random" | "computer" | "name" | "rating" | "ics" | "." => {}
+            "protover" => {
+                let mut out = self.out.lock();
+                writeln!(out, "feature done=0").ok();
+                for feature in XBOARD_FEATURES {
+                    writeln!(out, "feature {}", feature).ok();
+                }
+                writeln!(out, "feature done=1").ok();
+                out.flush().ok();
+            }
+            "new" => {
+                self.finish_search(true);
+                self.set_position(BoardState::new(), 0);
+                self.force = false;
+                self.engine_white = false;
+                self.move_time = None;
+                self.depth_limit = None;
+                get_transposition_table().clear();
+                self.think(settings);
+            }
+            "variant" => {
+                if argument != "normal" {
+                    self.say(format_args!("Error (unsupported variant): {}", argument));
+                }
+            }
+            "force" => {
+                self.finish_search(true);
+                self.force = true;
+            }
+            "go" => {
+                self.finish_search(true);
+                self.force = false;
+                self.engine_white = self.board.white_to_move;
+                self.think(settings);
+            }
+            "playother" => {
+                self.finish_search(true);
+                self.force = false;
+                self.engine_white = !self.board.white_to_move;
+            }
+            "level" if parts.len() >= 4 => {
+                // level <moves per session> <base minutes[:seconds]> <increment seconds>
+                match (parts[1].parse::<u32>(), parse_base_time(parts[2]), parse_seconds(parts[3])) {
+                    (Ok(moves), Some(base), Some(increment)) => {
+                        self.moves_per_session = moves;
+                        self.time_left = base.as_millis() as u64 / 10;
+                        self.increment = increment;
+                        self.move_time = None;
+                    }
+                    _ => self.say(format_args!("Error (bad level): {}", line)),
+                }
+            }
+            "st" => match parse_seconds(argument) {
+                Some(time) => self.move_time = Some(time),
+                None => self.say(format_args!("Error (bad time): {}", line)),
+            },
+            "sd" => match argument.parse::<u8>() {
+                Ok(depth) => self.depth_limit = Some(depth.clamp(1, MAX_SEARCH_DEPTH)),
+                Err(_) => self.say(format_args!("Error (bad depth): {}", line)),
+            },
+            "time" => {
+                if let Ok(time) = argument.parse::<u64>() {
+                    self.time_left = time;
+                }
+            }
+            "usermove" => self.user_move(argument, settings),
+            "?" => {
+                // Move now; analysis has no move to make
+                if !self.analyzing {
+                    self.finish_search(false);
+                }
+            }
+            "ping" => {
+                // A move being thought about comes before the pong; analysis never ends by itself
+                if !self.analyzing && let Some(search) = self.search.take() && let Ok(Some(mv)) = search.join() {
+                    self.play(mv);
+                }
+                self.say(format_args!("pong {}", argument));
+            }
+            "result" => {
+                self.finish_search(true);
+                self.force = true;
+            }
+            "setboard" => {
+                self.finish_search(true);
+                let fen = parts[1..].join(" ");
+                match parse_fen(&fen) {
+                    Some(board) => {
+                        // Rebuild the game ply from the fullmove number
+                        let fullmove = parts.get(6).and_then(|n| n.parse::<u32>().ok()).unwrap_or(1);
+                        self.set_position(board, 2 * fullmove.saturating_sub(1) + !board.white_to_move as u32);
+                    }
+                    None => self.say(format_args!("tellusererror Illegal position")),
+                }
+                if self.analyzing {
+                    self.think(settings);
+                }
+            }
+            "undo" | "remove" => {
+                self.finish_search(true);
+                let plies = if parts[0] == "undo" { 1 } else { 2 };
+                for _ in 0..plies {
+                    if let Some(board) = self.history.pop() {
+                        self.board = board;
+                        self.game_ply = self.game_ply.saturating_sub(1);
+                    }
+                }
+                if self.analyzing {
+                    self.think(settings);
+                }
+            }
+            "post" => self.post = true,
+            "nopost" => self.post = false,
+            "analyze" => {
+                self.finish_search(true);
+                self.analyzing = true;
+                self.think(settings);
+            }
+            "exit" => {
+                self.finish_search(true);
+                self.analyzing = false;
+            }
+            "memory" => {
+                if let Ok(size_mb) = argument.parse::<usize>() {
+                    init_transposition_table(size_mb.clamp(1, MAX_HASH_MB));
+                }
+            }
+            "cores" => {
+                if let Ok(threads) = argument.parse::<usize>() {
+                    THREADS.store(threads.clamp(1, MAX_THREADS), Ordering::Relaxed);
+                }
+            }
+            "quit" => {
+                self.finish_search(true);
+                return false;
+            }
+            _ if parse_xboard_move(parts[0]).is_some() => self.user_move(parts[0], settings),
+            _ => self.say(format_args!("Error (unknown command): {}", parts[0])),
+        }
+        true
+    }
+    
+    fn set_position(&mut self, board: BoardState, game_ply: u32) {
+        self.board = board;
+        self.history.clear();
+        self.game_ply = game_ply;
+    }
+    
+    fn play(&mut self, (from, to): (u8, u8)) {
+        self.history.push(self.board);
+        make_move(&mut self.board, from, to);
+        self.game_ply += 1;
+    }
+    
+    /// The opponent's move, or one to record in force mode.
+    fn user_move(&mut self, text: &str, settings: &EngineSettings) {
+        // The reply to a move the worker has printed but not yet returned is
+        // checked with that move on the board
+        self.finish_search(true);
+        let legal = parse_xboard_move(text).filter(|mv| generate_legal_moves(&self.board).contains(mv));
+        let Some(mv) = legal else {
+            self.say(format_args!("Illegal move: {}", text));
+            // Carry on with whatever was stopped
+            self.think(settings);
+            return;
+        };
+        self.play(mv);
+        self.think(settings);
+    }
+    
+    /// Stops the worker, if any, and waits for it. Unless cancelled the move
+    /// it prints is played; a cancelled one may still have got its move out
+    /// first, in which case it is played too, as the GUI has seen it.
+    fn finish_search(&mut self, cancel: bool) {
+        if let Some(search) = self.search.take() {
+            if cancel {
+                self.cancelled.store(true, Ordering::Relaxed);
+            }
+            self.clock.stop();
+            if let Ok(Some(mv)) = search.join() {
+                self.play(mv);
+            }
+        }
+    }
+    
+    /// Starts analysing, or thinking about a move when it's the engine's turn.
+    fn think(&mut self, settings: &EngineSettings) {
+        if self.analyzing {
+            let limits = SearchLimits {
+                output: Thinking::XBoard,
+                out: self.out.clone(),
+                ..SearchLimits::new(MAX_SEARCH_DEPTH, None)
+            };
+            self.spawn(limits, None);
+            return;
+        }
+        if self.force || self.board.white_to_move != self.engine_white {
+            return;
+        }
+        
+        // With no move to make the game is over, and the engine says how
+        if generate_legal_moves(&self.board).is_empty() {
+            let result = if !is_check(self.board.bitboards, self.board.white_to_move) {
+                "1/2-1/2 {Stalemate}"
+            } else if self.board.white_to_move {
+                "0-1 {Black mates}"
+            } else {
+                "1-0 {White mates}"
+            };
+            self.say(format_args!("{}", result));
+            return;
+        }
+        
+        // Play from the book while the game is still shallow enough
+        if settings.own_book && self.game_ply < 2 * settings.book_depth
+            && let Some(mv) = settings.book.as_ref().and_then(|book| book.pick(&self.board, settings.book_best_move)) {
+            self.say(format_args!("move {}", move_to_uci(mv.0, mv.1)));
+            self.play(mv);
+            return;
+        }
+        
+        let overhead = MOVE_OVERHEAD.load(Ordering::Relaxed);
+        let time = match self.move_time {
+            Some(time) => TimeManager::fixed(time.saturating_sub(Duration::from_millis(overhead))),
+            None => {
+                // Moves left in this session, counting the one to play now
+                let moves_to_go = (self.moves_per_session > 0)
+                    .then(|| self.moves_per_session - (self.game_ply / 2) % self.moves_per_session);
+                let increment = self.increment.as_millis() as u64;
+                TimeManager::from_clock(self.time_left * 10, increment, moves_to_go, self.game_ply, overhead)
+            }
+        };
+        let mut limits = SearchLimits {
+            time: Some(time),
+            output: if self.post { Thinking::XBoard } else { Thinking::Silent },
+            out: self.out.clone(),
+            ..SearchLimits::new(self.depth_limit.unwrap_or(MAX_SEARCH_DEPTH), None)
+        };
+        let skill = settings.skill();
+        if let Some(skill) = skill {
+            skill.limit(&mut limits);
+        }
+        self.spawn(limits, skill);
+    }
+    
+    // Searches on a worker thread, which prints the move unless analysing
+    fn spawn(&mut self, limits: SearchLimits, skill: Option<Skill>) {
+        self.clock = Arc::new(SearchClock::new(false));
+        self.cancelled = Arc::new(AtomicBool::new(false));
+        let clock = Arc::clone(&self.clock);
+        let cancelled = Arc::clone(&self.cancelled);
+        let board = self.board;
+        let analyzing = self.analyzing;
+        self.search = std::thread::Builder::new()
+            .stack_size(SEARCH_STACK_SIZE)
+            .spawn(move || {
+                let threads = THREADS.load(Ordering::Relaxed);
+                let lines = run_search(&board, &limits, threads, &clock);
+                if analyzing {
+                    return None;
+                }
+                let result = match skill {
+                    Some(skill) => skill.pick(lines, &mut rand::rng()),
+                    None => lines.into_iter().next(),
+                };
+                let (from, to) = result.map(|result| result.best_move)
+                    .or_else(|| generate_legal_moves(&board).first().copied())?;
+                if cancelled.load(Ordering::Relaxed) {
+                    return None;
+                }
+                let mut out = limits.out.lock();
+                writeln!(out, "move {}", move_to_uci(from, to)).ok();
+                out.flush().ok();
+                Some((from, to))
+            })
+            .ok();
+    }
+}
+
+//END OF XBOARD PROTOCOL----------------------------------------------------------------------------------
+
+
+fn make_book(pgn_path: &str, book_path: &str, max_ply: usize, min_games: u32, min_score: u32) {
+    let text = match std::fs::read(pgn_path) {
+        // Older databases are often Latin-1, which only matters inside comments
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(err) => {
+            println!("can't read {}: {}", pgn_path, err);
+            return;
+        }
+    };
+    
+    let games = pgn::parse_games(&text);
+    let mut builder = book::BookBuilder::new(max_ply);
+    let moves: usize = games.iter().map(|game| builder.add_game(game)).sum();
+    println!("{} games, {} moves, {} positions", games.len(), moves, builder.positions());
+    
+    match builder.write(std::path::Path::new(book_path), min_games, min_score) {
+        Ok(entries) => println!("wrote {} entries to {}", entries, book_path),
+        Err(err) => println!("can't write {}: {}", book_path, err),
+    }
+}
+
+fn generate_endgame_tables(dir: &str, names: &[String]) {
+    let mut generator = retrograde::Generator::new();
+    for name in names {
+        let start = Instant::now();
+        let Some(table) = generator.generate(name) else {
+            println!("{}: unsupported material (up to {} pieces, e.g. KBNvK)", name, retrograde::MAX_PIECES);
+            continue;
+        };
+        
+        let path = std::path::Path::new(dir).join(format!("{}.etb", name));
+        if let Err(err) = table.save(&path) {
+            println!("{}: can't write {}: {}", name, path.display(), err);
+            continue;
+        }
+        let (wins, draws, losses, longest) = table.statistics();
+        println!("{}: {} wins, {} draws, {} losses, longest mate {} moves ({:.1}s)",
+            name, wins, draws, losses, longest, start.elapsed().as_secs_f64());
+    }
+}
+
+// Plays one game between two skill levels from the given opening and returns
+// White's score. Repetitions, the fifty-move rule, bare minor pieces and the
+// ply cap are draws; an evaluation past a rook for long enough is a win.
+fn play_skill_game(opening: &[(u8, u8)], white: Skill, black: Skill, rng: &mut impl Rng) -> f64 {
+    let mut board = BoardState::new();
+    for &(from, to) in opening {
+        make_move(&mut board, from, to);
+    }
+    // A table per side, as each would have in a game of its own
+    let tables = [Arc::new(TranspositionTable::new(16)), Arc::new(TranspositionTable::new(16))];
+    let mut seen = vec![compute_board_hash(&board)];
+    let (mut quiet_plies, mut decided_plies) = (0, 0);
+    
+    for _ in 0..SKILL_GAME_PLIES {
+        let legal = generate_legal_moves(&board);
+        if legal.is_empty() {
+            return match (is_check(board.bitboards, board.white_to_move), board.white_to_move) {
+                (false, _) => 0.5,
+                (true, white_to_move) => if white_to_move { 0.0 } else { 1.0 },
+            };
+        }
+        let hash = compute_board_hash(&board);
+        let occupied = get_all_occupied(board.bitboards);
+        let minors_only = board.bitboards[WP] | board.bitboards[BP] | board.bitboards[WR] | board.bitboards[BR]
+            | board.bitboards[WQ] | board.bitboards[BQ] == 0 && count_bits(occupied) <= 3;
+        if quiet_plies >= 100 || seen.iter().filter(|&&seen| seen == hash).count() >= 3 || minors_only {
+            return 0.5;
+        }
+        let eval = evaluate_white(&board);
+        decided_plies = if eval.abs() >= 1000 { decided_plies + 1 } else { 0 };
+        if decided_plies >= 10 {
+            return if eval > 0 { 1.0 } else { 0.0 };
+        }
+        
+        let skill = if board.white_to_move { white } else { black };
+        let mut limits = SearchLimits { tt: Arc::clone(&tables[!board.white_to_move as usize]), ..SearchLimits::new(MAX_SEARCH_DEPTH, None) };
+        skill.limit(&mut limits);
+        let lines = run_search(&board, &limits, 1, &SearchClock::new(false));
+        let (from, to) = skill.pick(lines, rng).map_or(legal[0], |line| line.best_move);
+        
+        let pawns = board.bitboards[WP] | board.bitboards[BP];
+        quiet_plies = if get_bit(pawns, from) || get_bit(occupied, to) { 0 } else { quiet_plies + 1 };
+        make_move(&mut board, from, to);
+        seen.push(compute_board_hash(&board));
+    }
+    0.5
+}
+
+// Measures the strength gaps between skill levels by self-play: each pair of
+// neighbouring levels plays `games` games from random four-ply openings, each
+// opening once with either colour. Prints the Elo of every level relative to
+// the first, chained from the pairs' scores.
+fn calibrate_skill(levels: &[i64], games: usize) {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+    let mut elo = 0.0;
+    println!("level {} elo 0", levels[0]);
+    for pair in levels.windows(2) {
+        let (weaker, stronger) = (Skill { level: pair[0] as f64 }, Skill { level: pair[1] as f64 });
+        let start = Instant::now();
+        let mut points = 0.0;
+        let openings = games.div_ceil(2);
+        for _ in 0..openings {
+            let mut board = BoardState::new();
+            let mut opening = Vec::new();
+            for _ in 0..4 {
+                let legal = generate_legal_moves(&board);
+                let (from, to) = legal[rng.random_range(0..legal.len())];
+                make_move(&mut board, from, to);
+                opening.push((from, to));
+            }
+            points += play_skill_game(&opening, stronger, weaker, &mut rng);
+            points += 1.0 - play_skill_game(&opening, weaker, stronger, &mut rng);
+        }
+        let played = openings * 2;
+        let score = (points / played as f64).clamp(0.5 / played as f64, 1.0 - 0.5 / played as f64);
+        let gap = 400.0 * (score / (1.0 - score)).log10();
+        elo += gap;
+        println!("level {} elo {:.0} (+{:.1}/{} against level {}, {:+.0}, {:.0}s)",
+            pair[1], elo, points, played, pair[0], gap, start.elapsed().as_secs_f64());
+    }
+}
+
 fn main() {
     precompute_knight_attacks();
     precompute_king_attacks();
     precompute_pawn_attacks();
     
     // Initialize thread-safe structures
-    init_transposition_table(16);
+    init_transposition_table(DEFAULT_HASH_MB);
     init_history_table();
     
     // Check if running in UCI mode (default) or test mode
@@ -3713,13 +5465,1013 @@ fn main() {
         println!("=== Chess Engine - Test Mode ===");
         test_performance();
         test_evaluation();
-        test_minimax();
+        test_unmake_move();
+        test_symmetry();
+        test_search();
         test_depth_x(6);
         test_move_generation_depth_6();
         benchmark_search();
         play_game();
+    } else if args.len() > 3 && args[1] == "makebook" {
+        // makebook <games.pgn> <book.bin> [max ply] [min games] [min score %]
+        let option = |idx: usize, default: u32| args.get(idx).and_then(|arg| arg.parse().ok()).unwrap_or(default);
+        make_book(&args[2], &args[3], option(4, 20) as usize, option(5, 2), option(6, 0));
+    } else if args.len() > 3 && args[1] == "calibrate" {
+        // calibrate <games per pair> <level>... plays each level against the next
+        let games = args[2].parse().unwrap_or(40);
+        let levels: Vec<i64> = args[3..].iter().filter_map(|level| level.parse().ok()).collect();
+        calibrate_skill(&levels, games);
+    } else if args.len() > 2 && args[1] == "generate" {
+        // generate <dir> <material>... writes <dir>/<material>.etb for each
+        generate_endgame_tables(&args[2], &args[3..]);
     } else {
         // UCI mode - default
-        uci_loop();
+        uci_loop(io::stdin().lock(), io::stdout());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn init() {
+        precompute_knight_attacks();
+        precompute_king_attacks();
+        precompute_pawn_attacks();
+        init_history_table();
+    }
+
+    #[test]
+    fn unmake_restores_a_promoted_pawn() {
+        init();
+        // The pawn went b7xb8 and became a queen, taking a rook; undoing it must
+        // remove the queen rather than a pawn that was never on b8.
+        let mut board = parse_fen("1Q2k3/8/8/8/8/8/8/4K3 b - -").unwrap();
+        let mv = Move {
+            from: 9,
+            to: 1,
+            piece: WP,
+            captured_piece: Some(BR),
+            promotion: Some(WQ),
+            castling_move: false,
+            en_passant: false,
+            previous_castling_rights: (false, false, false, false),
+            previous_en_passant_target: None,
+        };
+        unmake_move(&mut board, &mv);
+
+        let expected = parse_fen("1r2k3/1P6/8/8/8/8/8/4K3 w - -").unwrap();
+        assert_eq!(board.bitboards, expected.bitboards);
+        assert!(board.white_to_move);
+    }
+
+    #[test]
+    fn piece_square_tables_reward_advanced_pawns() {
+        init();
+        let advanced = parse_fen("r3k3/4P3/8/8/8/8/8/R3K3 w - -").unwrap();
+        let home = parse_fen("r3k3/8/8/8/8/8/4P3/R3K3 w - -").unwrap();
+        assert!(evaluate_white(&advanced) > evaluate_white(&home));
+
+        let advanced = parse_fen("r3k3/8/8/8/8/8/4p3/R3K3 w - -").unwrap();
+        let home = parse_fen("r3k3/4p3/8/8/8/8/8/R3K3 w - -").unwrap();
+        assert!(evaluate_white(&advanced) < evaluate_white(&home));
+    }
+
+    #[test]
+    fn pawns_attack_towards_the_enemy() {
+        init();
+        // Reference perft counts; pawn captures first appear at the third ply
+        let start = BoardState::new();
+        assert_eq!(perft(&start, 3), 8902);
+        assert_eq!(perft(&start, 4), 197281);
+
+        let board = parse_fen("4k3/8/8/3p4/4P3/8/8/4K3 w - -").unwrap();
+        assert!(generate_legal_moves(&board).contains(&(36, 27)));
+        assert!(parse_fen("4k3/3P4/8/8/8/8/8/4K3 b - -").unwrap().black_king_in_check);
+        assert!(parse_fen("4k3/8/8/8/8/8/5p2/4K3 w - -").unwrap().white_king_in_check);
+    }
+
+    #[test]
+    fn pawns_promote_to_a_queen_and_unmake_cleanly() {
+        init();
+        let board = parse_fen("4k3/1P6/8/8/8/8/6p1/4K3 w - -").unwrap();
+        let mut state = SearchState::new(board);
+        assert!(state.make_move(9, 1));
+        assert!(get_bit(state.board.bitboards[WQ], 1));
+        assert_eq!(state.board.bitboards[WP], 0);
+        assert!(state.make_move(54, 62));
+        assert!(get_bit(state.board.bitboards[BQ], 62));
+        assert_eq!(state.board.bitboards[BP], 0);
+
+        state.unmake_move();
+        state.unmake_move();
+        assert_eq!(state.board.bitboards, board.bitboards);
+    }
+
+    /// Swaps colours and mirrors ranks of a FEN, so the result is the same
+    /// position seen from the other side of the board.
+    fn mirror_fen(fen: &str) -> String {
+        let parts: Vec<&str> = fen.split_whitespace().collect();
+        let swap_case = |c: char| if c.is_ascii_uppercase() { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() };
+        let board: Vec<String> = parts[0].split('/').rev().map(|rank| rank.chars().map(swap_case).collect()).collect();
+        let side = if parts[1] == "w" { "b" } else { "w" };
+        let castling: String = if parts[2] == "-" {
+            "-".to_string()
+        } else {
+            let swapped: Vec<char> = parts[2].chars().map(swap_case).collect();
+            "KQkq".chars().filter(|c| swapped.contains(c)).collect()
+        };
+        format!("{} {} {} -", board.join("/"), side, castling)
+    }
+
+    const POSITIONS: [&str; 6] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -",
+        "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq -",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq -",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - -",
+        "4k3/8/8/3q4/8/2N5/8/4K3 w - -",
+        "6k1/5ppp/8/8/8/8/1Q3PPP/6K1 b - -",
+    ];
+
+    #[test]
+    fn start_position_is_balanced() {
+        init();
+        let board = BoardState::new();
+        assert_eq!(evaluate_white(&board), 0);
+        assert_eq!(evaluate(&board), 0);
+    }
+
+    #[test]
+    fn evaluation_is_relative_to_side_to_move() {
+        init();
+        for fen in POSITIONS {
+            let board = parse_fen(fen).unwrap();
+            let mut other_side = board;
+            other_side.white_to_move = !other_side.white_to_move;
+            assert_eq!(evaluate(&board), -evaluate(&other_side), "{}", fen);
+
+            let white_view = if board.white_to_move { evaluate(&board) } else { -evaluate(&board) };
+            assert_eq!(white_view, evaluate_white(&board), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn mirrored_positions_evaluate_to_negated_score() {
+        init();
+        for fen in POSITIONS {
+            let board = parse_fen(fen).unwrap();
+            let mirrored = parse_fen(&mirror_fen(fen)).unwrap();
+            assert_eq!(evaluate_white(&board), -evaluate_white(&mirrored), "{}", fen);
+            assert_eq!(evaluate(&board), evaluate(&mirrored), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn mirror_matches_hand_mirrored_fen() {
+        init();
+        for fen in SYMMETRY_TEST_FENS {
+            let board = parse_fen(fen).unwrap();
+            assert_eq!(board.mirror(), parse_fen(&mirror_fen(fen)).unwrap(), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn mirrored_positions_are_searched_identically() {
+        init();
+        for board in symmetry_test_positions(4) {
+            if let Err(message) = check_mirror_symmetry(&board, 2, 2) {
+                print_board(&board);
+                panic!("{}", message);
+            }
+        }
+    }
+
+    #[test]
+    fn quiescence_scores_from_side_to_move() {
+        init();
+        // White to move wins the undefended queen, black to move in the mirror does the same
+        let fen = "4k3/8/8/3q4/8/2N5/4P3/4K3 w - -";
+        let mut board = SearchState::new(parse_fen(fen).unwrap());
+        let mut mirrored = SearchState::new(parse_fen(&mirror_fen(fen)).unwrap());
+
+        let score = quiescence_search_enhanced(&mut board, -100_000, 100_000);
+        let mirrored_score = quiescence_search_enhanced(&mut mirrored, -100_000, 100_000);
+        assert!(score > evaluate(&board.board), "capturing the queen should beat standing pat");
+        assert!(score > 0);
+        assert_eq!(score, mirrored_score);
+    }
+
+    #[test]
+    fn search_driver_finds_the_mate() {
+        init();
+        let board = parse_fen("6k1/5ppp/8/8/8/8/5PPP/1R4K1 w - -").unwrap();
+        assert_eq!(find_best_move_with_time(&board, 4, None), uci_to_move("b1b8"));
+        
+        let mirrored = parse_fen(&mirror_fen("6k1/5ppp/8/8/8/8/5PPP/1R4K1 w - -")).unwrap();
+        assert_eq!(find_best_move_with_time(&mirrored, 4, None), uci_to_move("b8b1"));
+    }
+    
+    #[test]
+    fn aspiration_failures_are_searched_again() {
+        init();
+        // Winning the queen is far outside a window centred on a losing score
+        let board = parse_fen("4k3/8/8/3q4/8/2N5/4P3/4K3 w - -").unwrap();
+        let root_moves: Vec<(u8, u8)> = generate_legal_moves(&board);
+        let mut search_state = SearchState::new(board);
+        
+        let tt = TranspositionTable::new(1);
+        let (full_score, full_move) = aspiration_search(&mut search_state, &root_moves, 4, None, &tt);
+        for previous_score in [-1000, full_score, 1000] {
+            let tt = TranspositionTable::new(0);
+            let (score, mv) = aspiration_search(&mut search_state, &root_moves, 4, Some(previous_score), &tt);
+            assert_eq!((score, mv), (full_score, full_move), "window around {}", previous_score);
+        }
+        assert_eq!(full_move, uci_to_move("c3d5").unwrap());
+        assert_eq!(search_state.board, board);
+    }
+    
+    #[test]
+    fn mate_scores_are_reported_in_moves() {
+        assert_eq!(uci_score(35), "cp 35");
+        assert_eq!(uci_score(MATE - 1), "mate 1");
+        assert_eq!(uci_score(MATE - 3), "mate 2");
+        assert_eq!(uci_score(-MATE + 2), "mate -1");
+        assert_eq!(uci_score(-TB_WIN_SCORE), format!("cp {}", -TB_WIN_SCORE));
+        
+        // TT entries hold the distance from the stored node
+        for score in [MATE - 5, -MATE + 8, 120] {
+            assert_eq!(score_from_tt(score_to_tt(score, 3), 3), score);
+        }
+        assert_eq!(score_from_tt(score_to_tt(MATE - 5, 3), 1), MATE - 3);
+    }
+    
+    #[test]
+    fn forced_mates_keep_their_distance_through_the_tt() {
+        init();
+        // Rb8+ Re8 Rbxe8 is mate in two, whatever the depth
+        let board = parse_fen("6k1/4rppp/8/8/8/8/5PPP/1R2R1K1 w - -").unwrap();
+        for depth in [4, 6] {
+            let result = search_position(&board, depth, None).unwrap();
+            assert_eq!(uci_score(result.score), "mate 2", "depth {}", depth);
+            assert_eq!(result.best_move, uci_to_move("b1b8").unwrap());
+        }
+        
+        // The losing side sees the same mate against it
+        let mut defending = board;
+        make_move(&mut defending, 57, 1);
+        let result = search_position(&defending, 4, None).unwrap();
+        assert_eq!(uci_score(result.score), "mate -1");
+    }
+    
+    #[test]
+    fn tt_slots_reject_torn_entries() {
+        let tt = TranspositionTable::new(1);
+        let (hash, other) = (0x1234_5678_9abc_def0, 0x0fed_cba9_8765_4321);
+        tt.store(hash, 7, -MATE + 9, 1, (52, 36), 2);
+        let entry = tt.load(hash).unwrap();
+        assert_eq!((entry.depth, entry.flag, entry.best_move), (7, 1, (52, 36)));
+        assert_eq!(score_from_tt(entry.score, 2), -MATE + 9);
+        assert!(tt.load(other).is_none());
+        
+        // Half of one store over half of another verifies as neither
+        let slot = tt.bucket(hash).slots.iter().find(|slot| slot.data.load(Ordering::Relaxed) != 0).unwrap();
+        let data = TTEntry { depth: 3, score: 40, flag: 0, best_move: (12, 28) }.pack(0);
+        slot.data.store(data, Ordering::Relaxed);
+        assert!(tt.load(hash).is_none());
+        
+        // Threads hammering the same slots only ever read back whole entries
+        let tt = TranspositionTable::new(1);
+        std::thread::scope(|scope| {
+            for thread in 0..4u64 {
+                let tt = &tt;
+                scope.spawn(move || {
+                    for i in 0..20_000u64 {
+                        let hash = (i % 64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ thread;
+                        tt.store(hash, (hash % 50) as i32, (hash % 1000) as i32, 0, ((hash % 64) as u8, 0), 0);
+                        if let Some(entry) = tt.load(hash) {
+                            assert_eq!(entry.score, (hash % 1000) as i32);
+                            assert_eq!(entry.best_move.0, (hash % 64) as u8);
+                        }
+                    }
+                });
+            }
+        });
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn tt_buckets_age_out_old_searches() {
+        for size_mb in [1, 3, 16] {
+            let tt = TranspositionTable::new(size_mb);
+            assert!(tt.buckets.len().is_power_of_two());
+            assert!(tt.buckets.len() * std::mem::size_of::<TTBucket>() <= size_mb * 1024 * 1024);
+        }
+        assert_eq!(std::mem::size_of::<TTBucket>(), 64);
+        
+        // Positions sharing a bucket all fit until it is full
+        let tt = TranspositionTable::new(1);
+        let stride = tt.mask as u64 + 1;
+        for i in 0..4 {
+            tt.store(1 + i * stride, 10 - i as i32, 0, 0, (0, 0), 0);
+        }
+        assert!((0..4).all(|i| tt.load(1 + i * stride).is_some()));
+        
+        // Within a search the shallowest entry makes way
+        tt.store(1 + 4 * stride, 9, 0, 0, (0, 0), 0);
+        assert!(tt.load(1 + 3 * stride).is_none());
+        
+        // After a few searches even the deepest old entry is worth less than a new one
+        for _ in 0..2 {
+            tt.new_search();
+        }
+        assert_eq!(tt.hashfull(), 0);
+        tt.store(1 + 5 * stride, 1, 0, 0, (0, 0), 0);
+        assert!(tt.load(1 + 5 * stride).is_some());
+        assert!(tt.load(1 + 2 * stride).is_none());
+        assert!(tt.hashfull() <= 4);
+    }
+    
+    // Searches in tests get a table of their own, as they run side by side
+    fn private_limits(depth: u8, time: Option<Duration>) -> SearchLimits {
+        SearchLimits { tt: Arc::new(TranspositionTable::new(1)), ..SearchLimits::new(depth, time) }
+    }
+    
+    #[test]
+    fn ponder_time_is_not_counted() {
+        init();
+        // While pondering the time limit doesn't apply
+        let clock = SearchClock::new(true);
+        let result = run_search(&BoardState::new(), &private_limits(3, Some(Duration::from_millis(1))), 1, &clock).remove(0);
+        assert_eq!(result.depth, 3);
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        
+        // After ponderhit only the time since counts
+        std::thread::sleep(Duration::from_millis(30));
+        clock.ponderhit();
+        assert!(!clock.pondering());
+        assert!(clock.elapsed() < Duration::from_millis(30));
+        assert!(clock.start.elapsed() >= Duration::from_millis(30));
+    }
+    
+    #[test]
+    fn multipv_ranks_distinct_root_moves() {
+        init();
+        // Rb8+ mates in two; the other lines are ordinary moves
+        let board = parse_fen("6k1/4rppp/8/8/8/8/5PPP/1R2R1K1 w - -").unwrap();
+        let lines = search_lines(&board, 4, None, 3);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].best_move, uci_to_move("b1b8").unwrap());
+        assert_eq!(uci_score(lines[0].score), "mate 2");
+        assert!(lines.windows(2).all(|pair| pair[0].score >= pair[1].score));
+        for (index, line) in lines.iter().enumerate() {
+            assert!(!lines[..index].iter().any(|other| other.best_move == line.best_move));
+            assert_eq!(line.pv.first(), Some(&line.best_move));
+        }
+        
+        // No more lines than legal moves
+        let board = parse_fen("7k/8/8/8/8/8/6PP/7K w - -").unwrap();
+        assert_eq!(search_lines(&board, 3, None, 10).len(), generate_legal_moves(&board).len());
+    }
+    
+    #[test]
+    fn go_limits_are_parsed_and_kept() {
+        init();
+        let params = UCISearchParams::parse_go_command("go searchmoves a2a3 h2h4 nodes 5000 mate 3 wtime 100");
+        assert_eq!(params.searchmoves, [uci_to_move("a2a3").unwrap(), uci_to_move("h2h4").unwrap()]);
+        assert_eq!((params.nodes, params.mate, params.wtime), (Some(5000), Some(3), Some(100)));
+        
+        // Only the listed moves are played
+        let board = BoardState::new();
+        let limits = SearchLimits { searchmoves: params.searchmoves.clone(), multipv: 4, ..private_limits(3, None) };
+        let lines = run_search(&board, &limits, 1, &SearchClock::new(false));
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| params.searchmoves.contains(&line.best_move)));
+        
+        // The node limit ends an unbounded search early, and holds with helper threads
+        let buffer = SharedBuffer::default();
+        let limits = SearchLimits {
+            nodes: Some(5000),
+            output: Thinking::Uci,
+            out: Output::new(buffer.clone()),
+            ..private_limits(MAX_SEARCH_DEPTH, None)
+        };
+        let result = run_search(&board, &limits, 4, &SearchClock::new(false)).remove(0);
+        assert!(result.depth < 6, "{}", result.depth);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let searched = output.lines()
+            .filter_map(|line| line.split_whitespace().skip_while(|&field| field != "nodes").nth(1))
+            .map(|nodes| nodes.parse::<u64>().unwrap());
+        assert!(searched.max().is_some_and(|nodes| nodes <= 5000), "{}", output);
+        
+        // As does a mate search finding its mate
+        let board = parse_fen("6k1/4rppp/8/8/8/8/5PPP/1R2R1K1 w - -").unwrap();
+        let limits = SearchLimits { mate: Some(2), ..private_limits(MAX_SEARCH_DEPTH, None) };
+        let result = run_search(&board, &limits, 1, &SearchClock::new(false)).remove(0);
+        assert_eq!(uci_score(result.score), "mate 2");
+    }
+    
+    #[test]
+    fn time_manager_budgets_the_clock() {
+        // Sudden death: a slice of the clock, with room to overrun it
+        let time = TimeManager::from_clock(60_000, 0, None, 0, 10);
+        assert!(time.optimum > Duration::from_millis(500) && time.optimum < Duration::from_secs(3), "{:?}", time);
+        assert!(time.maximum > time.optimum && time.maximum < Duration::from_secs(60));
+        
+        // The increment is spent, later moves get more, and the last move before
+        // the time control may use most of what is left
+        assert!(TimeManager::from_clock(60_000, 2_000, None, 0, 10).optimum > time.optimum);
+        assert!(TimeManager::from_clock(60_000, 0, None, 120, 10).optimum > time.optimum);
+        let last = TimeManager::from_clock(10_000, 0, Some(1), 0, 10);
+        assert!(last.maximum < Duration::from_secs(10) && last.optimum > Duration::from_secs(5));
+        
+        // Nearly out of time, every move is quick
+        let low = TimeManager::from_clock(500, 100, None, 60, 10);
+        assert!(low.maximum < Duration::from_millis(200), "{:?}", low);
+        let flagging = TimeManager::from_clock(5, 0, None, 60, 10);
+        assert!(flagging.maximum <= Duration::from_millis(5));
+        
+        // movetime is kept to, less the overhead
+        let params = UCISearchParams::parse_go_command("go movetime 1000");
+        assert_eq!(params.time_manager(true, 0, 50).unwrap().maximum, Duration::from_millis(950));
+        assert!(UCISearchParams::parse_go_command("go infinite wtime 1000").time_manager(true, 0, 10).is_none());
+    }
+    
+    #[test]
+    fn time_manager_follows_the_best_move() {
+        let (e4, d4) = (uci_to_move("e2e4").unwrap(), uci_to_move("d2d4").unwrap());
+        let base = TimeManager::from_clock(60_000, 0, None, 0, 10);
+        
+        // A best move that keeps changing, or a falling score, earns more time
+        let mut unsettled = base.clone();
+        for (index, &mv) in [e4, d4, e4, d4].iter().enumerate() {
+            unsettled.update(mv, 20 - index as i32 * 40);
+        }
+        assert!(unsettled.target() > base.optimum);
+        assert!(!unsettled.should_stop(base.optimum));
+        
+        // One that holds lets the move go early
+        let mut settled = base.clone();
+        for _ in 0..6 {
+            settled.update(e4, 30);
+        }
+        assert!(settled.target() < base.optimum);
+        assert!(settled.should_stop(base.optimum));
+        assert!(unsettled.target() <= base.maximum);
+    }
+    
+    #[test]
+    fn time_limits_cut_iterations_short() {
+        init();
+        // Without the in-search clock this depth would take far longer
+        let board = parse_fen(POSITIONS[2]).unwrap();
+        let clock = SearchClock::new(false);
+        let limit = Duration::from_millis(150);
+        let lines = run_search(&board, &private_limits(MAX_SEARCH_DEPTH, Some(limit)), 1, &clock);
+        assert!(clock.start.elapsed() < limit + Duration::from_millis(250), "{:?}", clock.start.elapsed());
+        
+        // What comes back is the last iteration that finished
+        let result = &lines[0];
+        assert!(result.depth >= 1 && result.depth < MAX_SEARCH_DEPTH);
+        assert!(generate_legal_moves(&board).contains(&result.best_move));
+    }
+    
+    #[test]
+    fn limits_apply_from_the_first_iteration() {
+        init();
+        // Stopped before it starts, or after a single node, a search still has a legal move
+        let board = parse_fen(POSITIONS[2]).unwrap();
+        let clock = SearchClock::new(false);
+        clock.stop();
+        let lines = run_search(&board, &private_limits(MAX_SEARCH_DEPTH, None), 1, &clock);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].depth, 0);
+        assert!(generate_legal_moves(&board).contains(&lines[0].best_move));
+        
+        let limits = SearchLimits { nodes: Some(1), ..private_limits(MAX_SEARCH_DEPTH, None) };
+        let result = run_search(&board, &limits, 1, &SearchClock::new(false)).remove(0);
+        assert_eq!(result.depth, 0);
+        assert!(generate_legal_moves(&board).contains(&result.best_move));
+    }
+    
+    #[test]
+    fn endgame_tables_report_mates_at_the_root() {
+        init();
+        let table = retrograde::Generator::new().generate("KQvK").unwrap();
+        retrograde::install(vec![table]);
+        let board = parse_fen("k7/8/1K6/8/8/8/8/7Q w - -").unwrap();
+        let Some((_, retrograde::Dtm::Win(plies))) = retrograde::best_move(&board) else {
+            panic!("KQvK is a win");
+        };
+        
+        let buffer = SharedBuffer::default();
+        let limits = SearchLimits { output: Thinking::Uci, out: Output::new(buffer.clone()), ..private_limits(4, None) };
+        let result = run_search(&board, &limits, 1, &SearchClock::new(false)).remove(0);
+        retrograde::install(Vec::new());
+        
+        // Mate in moves, counted as tablebase hits
+        let mate = format!("mate {}", plies.div_ceil(2));
+        assert_eq!(uci_score(result.score), mate);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains(&format!("score {} tbhits ", mate)), "{}", output);
+    }
+    
+    #[test]
+    fn helper_threads_leave_the_main_result_sound() {
+        init();
+        let board = parse_fen("6k1/4rppp/8/8/8/8/5PPP/1R2R1K1 w - -").unwrap();
+        let result = run_search(&board, &private_limits(6, None), 4, &SearchClock::new(false)).into_iter().next().unwrap();
+        assert_eq!(uci_score(result.score), "mate 2");
+        assert_eq!(result.best_move, uci_to_move("b1b8").unwrap());
+        
+        // A helper told to stop gives up without a result
+        let stop: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(true)));
+        let mut search_state = SearchState::new(BoardState::new());
+        search_state.stop = Some(stop);
+        let tt = TranspositionTable::new(1);
+        let root_moves = generate_legal_moves(&search_state.board);
+        aspiration_search(&mut search_state, &root_moves, 6, None, &tt);
+        assert!(search_state.stopped);
+        assert!(search_state.nodes < 10_000, "{}", search_state.nodes);
+        assert_eq!(search_state.board, BoardState::new());
+    }
+    
+    #[test]
+    fn principal_variation_is_a_legal_line() {
+        init();
+        for fen in POSITIONS {
+            let board = parse_fen(fen).unwrap();
+            let result = search_position(&board, 4, None).unwrap();
+            assert_eq!(result.pv.first(), Some(&result.best_move), "{}", fen);
+            assert!(result.pv.len() >= 2, "{}: {:?}", fen, result.pv);
+            assert_eq!(result.ponder_move(), Some(result.pv[1]));
+            
+            let mut line = board;
+            for &(from, to) in &result.pv {
+                assert!(generate_legal_moves(&line).contains(&(from, to)), "{}: {}", fen, pv_to_uci(&result.pv));
+                make_move(&mut line, from, to);
+            }
+        }
+    }
+    
+    #[test]
+    fn nodes_and_selective_depth_are_counted() {
+        init();
+        let board = parse_fen("4k3/8/8/3q4/8/2N5/4P3/4K3 w - -").unwrap();
+        let mut search_state = SearchState::new(board);
+        
+        // A quiet stand-pat is one node at ply zero
+        let mut quiet = SearchState::new(parse_fen("4k3/8/8/8/8/8/4P3/4K3 w - -").unwrap());
+        quiescence_search_enhanced(&mut quiet, -100_000, 100_000);
+        assert_eq!((quiet.nodes, quiet.seldepth), (1, 0));
+        
+        // Captures extend past the nominal depth
+        let root_moves = generate_legal_moves(&board);
+        let tt = TranspositionTable::new(1);
+        search_root(&mut search_state, &root_moves, 2, -100_000, 100_000, &tt);
+        assert!(search_state.nodes > root_moves.len() as u64);
+        assert!(search_state.seldepth > 2);
+        assert!(tt.hashfull() <= 1000);
+    }
+    
+    #[test]
+    fn known_endgames_are_recognised() {
+        init();
+        let cases = [
+            ("8/8/4k3/8/2K5/8/3R4/8 w - -", Some(KnownEndgame::Kxk)),
+            ("8/8/8/4k3/8/8/8/KBN5 w - -", Some(KnownEndgame::Kbnk)),
+            ("8/8/3k4/8/8/3K4/3P4/8 w - -", Some(KnownEndgame::Kpk)),
+            ("8/8/8/8/5k2/8/2p5/K3R3 b - -", Some(KnownEndgame::Krkp)),
+            ("8/8/4k3/8/2K5/8/3N4/8 w - -", None),
+            ("4k3/8/2b5/3p2p1/3P2P1/4B3/8/4K3 w - -", None),
+        ];
+        for (fen, expected) in cases {
+            let counts = material_counts(&parse_fen(fen).unwrap());
+            let mirrored_counts = material_counts(&parse_fen(&mirror_fen(fen)).unwrap());
+            assert_eq!(classify_endgame(&counts, true), expected, "{}", fen);
+            assert_eq!(classify_endgame(&mirrored_counts, false), expected, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn bare_king_stalemate_agrees_with_move_generation() {
+        init();
+        let fens = [
+            "k7/2Q5/1K6/8/8/8/8/8 b - -",
+            "k7/2Q5/1K6/8/8/8/8/8 w - -",
+            "k7/1Q6/1K6/8/8/8/8/8 b - -",
+            "k7/8/1K6/8/8/8/8/2R5 b - -",
+            "7k/5K2/6Q1/8/8/8/8/8 b - -",
+            "7k/8/5K2/8/8/8/8/6R1 b - -",
+            "8/8/8/8/8/1k6/2q5/K7 w - -",
+            "8/8/8/3k4/8/3K4/3Q4/8 b - -",
+            "k7/8/1KP5/8/8/8/8/8 b - -",
+            "k7/P7/1K6/8/8/8/8/8 b - -",
+        ];
+        for fen in fens {
+            let board = parse_fen(fen).unwrap();
+            let white_king = board.bitboards[WP..=WQ].iter().all(|&pieces| pieces == 0);
+            let stalemate = board.white_to_move == white_king
+                && generate_legal_moves(&board).is_empty()
+                && !board.is_current_king_in_check();
+            assert_eq!(bare_king_is_stalemated(&board, white_king), stalemate, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn mop_up_prefers_the_defending_king_on_the_edge() {
+        init();
+        let centre = parse_fen("8/8/8/3k4/8/3K4/3Q4/8 w - -").unwrap();
+        let edge = parse_fen("3k4/8/3K4/8/8/8/3Q4/8 w - -").unwrap();
+        assert!(evaluate_white(&edge) > evaluate_white(&centre));
+        assert!(evaluate_white(&centre) > KNOWN_WIN);
+        
+        // Bishop and knight drive towards the corner of the bishop's colour
+        let wrong_corner = parse_fen("k7/8/1K6/8/8/8/8/4BN2 w - -").unwrap();
+        let right_corner = parse_fen("7k/8/6K1/8/8/8/8/4BN2 w - -").unwrap();
+        assert!(evaluate_white(&right_corner) > evaluate_white(&wrong_corner));
+    }
+
+    #[test]
+    fn drawn_material_is_scaled_down() {
+        init();
+        // The bishop colour mask and the per-square test agree
+        assert!((0..64).all(|square| get_bit(LIGHT_SQUARES, square) == is_light_square(square)));
+        // Lone knight, wrong rook pawn and stalemate are all dead draws
+        assert_eq!(evaluate_white(&parse_fen("8/8/4k3/8/2K5/8/3N4/8 w - -").unwrap()), 0);
+        assert_eq!(evaluate_white(&parse_fen("7k/8/6KP/8/8/8/8/5B2 w - -").unwrap()), 0);
+        assert_eq!(evaluate_white(&parse_fen("k7/2Q5/1K6/8/8/8/8/8 b - -").unwrap()), 0);
+        
+        // Opposite-coloured bishops halve an extra pawn
+        let fen = "4k3/8/2b5/3p2p1/3P2PP/4B3/8/4K3 w - -";
+        let board = parse_fen(fen).unwrap();
+        assert_eq!(evaluate_white(&board), evaluate_material_and_position(&board) / 2);
+        
+        // A rook each still leans towards the draw, queens on the board don't
+        let board = parse_fen("r3k3/8/2b5/3p2p1/3P2PP/4B3/8/R3K3 w - -").unwrap();
+        assert_eq!(evaluate_white(&board), evaluate_material_and_position(&board) * 48 / 64);
+        let board = parse_fen("r2qk3/8/2b5/3p2p1/3P2PP/4B3/8/R2QK3 w - -").unwrap();
+        assert_eq!(evaluate_white(&board), evaluate_material_and_position(&board));
+        
+        // Rule of the square: the pawn runs through
+        assert!(evaluate_white(&parse_fen("8/8/8/P7/8/8/8/K6k w - -").unwrap()) > KNOWN_WIN);
+        assert!(evaluate_white(&parse_fen("8/8/3k4/P7/8/8/8/K7 w - -").unwrap()) < KNOWN_WIN);
+    }
+
+    #[test]
+    fn options_are_advertised_and_applied() {
+        let advertised: Vec<String> = UCI_OPTIONS.iter().map(|option| option.to_string()).collect();
+        assert!(advertised.contains(&format!("option name Hash type spin default {} min 1 max {}", DEFAULT_HASH_MB, MAX_HASH_MB)));
+        assert!(advertised.contains(&"option name Clear Hash type button".to_string()));
+        assert!(advertised.contains(&"option name Ponder type check default false".to_string()));
+        assert!(advertised.contains(&"option name BookFile type string default <empty>".to_string()));
+        
+        assert!(advertised.contains(&"option name BookMove type combo default Weighted var Best var Weighted".to_string()));
+        
+        // The options kept in globals take effect in the next search; the
+        // sessions run meanwhile by other tests wait, and the defaults are put back
+        let _globals = SEARCH_GLOBALS.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let before = get_transposition_table();
+        let output = session("setoption name Hash value 2\nsetoption name Threads value 3\n\
+            setoption name MultiPV value 1000\nsetoption name Move Overhead value 25\ngo depth 1\n", true);
+        assert!(!Arc::ptr_eq(&before, &get_transposition_table()));
+        assert!(get_transposition_table().buckets.len() * std::mem::size_of::<TTBucket>() <= 2 * 1024 * 1024);
+        assert_eq!(THREADS.load(Ordering::Relaxed), 3);
+        assert_eq!(MULTI_PV.load(Ordering::Relaxed), MAX_MULTI_PV);
+        assert_eq!(MOVE_OVERHEAD.load(Ordering::Relaxed), 25);
+        // Every legal move gets its line when more are asked for than there are
+        assert!(output.iter().any(|line| line.starts_with("info depth 1 ") && line.contains(" multipv 20 ")), "{:?}", output);
+        session(&format!("setoption name Hash value {}\nsetoption name Threads value 1\n\
+            setoption name MultiPV value 1\nsetoption name Move Overhead value 10\n", DEFAULT_HASH_MB), false);
+        
+        let mut settings = EngineSettings::new();
+        assert_eq!(set_option(&mut settings, "BookDepth", "0"), None);
+        assert_eq!(settings.book_depth, 1);
+        assert_eq!(set_option(&mut settings, "ownbook", "TRUE"), None);
+        assert!(settings.own_book);
+        
+        assert_eq!(set_option(&mut settings, "BookMove", "best"), None);
+        assert!(settings.book_best_move);
+        assert_eq!(set_option(&mut settings, "BookMove", "Weighted"), None);
+        assert!(!settings.book_best_move);
+        
+        assert!(set_option(&mut settings, "OwnBook", "yes").is_some());
+        assert!(set_option(&mut settings, "BookMove", "random").is_some());
+        assert!(set_option(&mut settings, "BookDepth", "deep").is_some());
+        assert!(set_option(&mut settings, "Contempt", "10").is_some());
+        assert!(settings.own_book);
+    }
+
+    #[test]
+    fn skill_weakens_play_steadily() {
+        use rand::SeedableRng;
+        assert!(Skill::new(MAX_SKILL_LEVEL, false, MIN_ELO).is_none());
+        assert!(Skill::new(MAX_SKILL_LEVEL, true, MAX_ELO).is_some());
+        for (level, elo) in SKILL_ELO {
+            assert_eq!(Skill::new(MAX_SKILL_LEVEL, true, elo).unwrap().level, level);
+        }
+        
+        // Depth, nodes and the chance of a worse move all follow the Elo
+        let skills: Vec<Skill> = (MIN_ELO..=MAX_ELO).step_by(10)
+            .map(|elo| Skill::new(MAX_SKILL_LEVEL, true, elo).unwrap())
+            .collect();
+        for pair in skills.windows(2) {
+            assert!(pair[0].level <= pair[1].level);
+            assert!(pair[0].depth() <= pair[1].depth());
+            assert!(pair[0].nodes() <= pair[1].nodes());
+        }
+        
+        let line = |from, score| SearchResult { best_move: (from, 0), score, depth: 1, pv: vec![(from, 0)] };
+        let lines = vec![line(1, 50), line(2, 40), line(3, 30), line(4, -900)];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+        let mut picked = std::collections::HashSet::new();
+        for level in [0, 19] {
+            let skill = Skill::new(level, false, MIN_ELO).unwrap();
+            for _ in 0..200 {
+                picked.insert((level, skill.pick(lines.clone(), &mut rng).unwrap().best_move));
+            }
+        }
+        // The weakest level varies its moves; the strongest never gives away a queen
+        assert!(picked.iter().filter(|(level, _)| *level == 0).count() > 1);
+        assert!(!picked.contains(&(19, (4, 0))));
+    }
+
+    #[test]
+    fn xboard_tracks_the_game_in_force_mode() {
+        let settings = EngineSettings::new();
+        let mut xboard = XBoard::new(Output::new(io::sink()));
+        for line in ["xboard", "protover 2", "force", "usermove e2e4", "e7e5", "usermove e1e3", "level 40 2:30 1.5", "sd 5", "st 2"] {
+            assert!(xboard.command(line, &settings));
+        }
+        assert!(xboard.search.is_none());
+        assert_eq!(xboard.game_ply, 2);
+        assert_eq!((xboard.moves_per_session, xboard.time_left, xboard.increment), (40, 15000, Duration::from_millis(1500)));
+        assert_eq!((xboard.depth_limit, xboard.move_time), (Some(5), Some(Duration::from_secs(2))));
+        
+        xboard.command("remove", &settings);
+        assert_eq!(compute_board_hash(&xboard.board), compute_board_hash(&BoardState::new()));
+        xboard.command("setboard 4k3/8/8/8/8/8/8/4K2R b K - 0 30", &settings);
+        assert_eq!(xboard.game_ply, 59);
+        assert!(xboard.history.is_empty());
+        assert!(!xboard.command("quit", &settings));
+        
+        assert_eq!(xboard_score(MATE - 3), 100002);
+        assert_eq!(xboard_score(-(MATE - 2)), -100001);
+        assert_eq!(xboard_score(35), 35);
+    }
+
+    // Stdout for a scripted session, read back once it is over
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+    
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    
+    // Held for writing by a test changing the search options kept in globals,
+    // and for reading by the sessions that search with them
+    static SEARCH_GLOBALS: RwLock<()> = RwLock::new(());
+    
+    fn run_session(script: &str) -> Vec<String> {
+        let _globals = SEARCH_GLOBALS.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        session(script, false)
+    }
+    
+    // A script that only ends once a bestmove is out, so the search it
+    // started isn't stopped by the end of the input
+    struct UntilBestmove {
+        script: io::Cursor<Vec<u8>>,
+        output: SharedBuffer,
+    }
+    
+    impl io::Read for UntilBestmove {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.script.read(buf)?;
+            while read == 0 && !String::from_utf8_lossy(&self.output.0.lock().unwrap()).contains("bestmove ") {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok(read)
+        }
+    }
+    
+    // Feeds the script to the protocol loop; the end of it acts as quit
+    fn session(script: &str, wait_for_bestmove: bool) -> Vec<String> {
+        init();
+        let buffer = SharedBuffer::default();
+        if wait_for_bestmove {
+            let input = UntilBestmove { script: io::Cursor::new(script.as_bytes().to_vec()), output: buffer.clone() };
+            uci_loop(io::BufReader::new(input), buffer.clone());
+        } else {
+            uci_loop(script.as_bytes(), buffer.clone());
+        }
+        let bytes = buffer.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap().lines().map(str::to_string).collect()
+    }
+    
+    fn best_moves(output: &[String]) -> Vec<&str> {
+        output.iter().filter_map(|line| line.strip_prefix("bestmove ")).collect()
+    }
+    
+    #[test]
+    fn uci_handshake() {
+        let output = run_session("uci\nisready\nquit\nisready\n");
+        assert_eq!(output[0], "id name Rust Chess Engine");
+        assert!(output[1].starts_with("id author "));
+        let options = &output[2..output.len() - 2];
+        assert_eq!(options.len(), UCI_OPTIONS.len());
+        assert!(options.iter().all(|line| line.starts_with("option name ")));
+        // Nothing is answered after quit
+        assert_eq!(output[output.len() - 2..], ["uciok", "readyok"]);
+    }
+    
+    #[test]
+    fn uci_position_with_moves() {
+        let output = run_session("position startpos moves e2e4 e7e5 g1f3\ngo depth 2\n");
+        let mut board = BoardState::new();
+        for mv in ["e2e4", "e7e5", "g1f3"] {
+            let (from, to) = uci_to_move(mv).unwrap();
+            make_move(&mut board, from, to);
+        }
+        let best = best_moves(&output);
+        assert_eq!(best.len(), 1);
+        let reply = uci_to_move(best[0].split_whitespace().next().unwrap()).unwrap();
+        assert!(generate_legal_moves(&board).contains(&reply), "{}", best[0]);
+        
+        // From a FEN too, with the fullmove number and moves after it
+        let output = run_session("position fen 4k3/8/8/8/8/8/4P3/4K3 w - - 0 40 moves e1d1 e8d8\ngo depth 1\n");
+        let reply = uci_to_move(best_moves(&output)[0]).unwrap();
+        let board = parse_fen("3k4/8/8/8/8/8/4P3/3K4 w - - 0 41").unwrap();
+        assert!(generate_legal_moves(&board).contains(&reply));
+    }
+    
+    #[test]
+    fn uci_go_and_stop() {
+        // Stop ends an infinite search with one bestmove, before the next reply
+        let output = run_session("position startpos\ngo infinite\nstop\nisready\n");
+        let best = best_moves(&output);
+        assert_eq!(best.len(), 1);
+        // However early the stop, the move is legal
+        let reply = uci_to_move(best[0].split_whitespace().next().unwrap()).unwrap();
+        assert!(generate_legal_moves(&BoardState::new()).contains(&reply), "{}", best[0]);
+        // Info lines only come from finished iterations
+        assert!(output.iter().filter(|line| line.starts_with("info depth ")).all(|line| line.contains(" pv ")), "{:?}", output);
+        let best = output.iter().position(|line| line.starts_with("bestmove ")).unwrap();
+        assert_eq!(output.last().unwrap(), "readyok");
+        assert!(best < output.len() - 1);
+        
+        // A second go replaces the first, each getting its bestmove
+        let output = run_session("go infinite\ngo depth 1\n");
+        assert_eq!(best_moves(&output).len(), 2);
+    }
+    
+    #[test]
+    fn uci_new_game_resets_the_position() {
+        let output = run_session("position startpos moves e2e4\nucinewgame\nisready\ngo depth 1\n");
+        assert!(output.contains(&"readyok".to_string()));
+        let reply = uci_to_move(best_moves(&output)[0]).unwrap();
+        assert!(generate_legal_moves(&BoardState::new()).contains(&reply));
+    }
+    
+    #[test]
+    fn uci_malformed_input_is_survived() {
+        let script = "\n   \nfoo bar\nsetoption name Nope value 3\nsetoption name OwnBook value maybe\n\
+            position\nposition fen garbage\nposition startpos moves e2e4 e2e4 d7d5\ngo depth x movetime\nisready\n";
+        let output = run_session(script);
+        for expected in ["info string no option \"Nope\"", "info string invalid fen garbage", "info string invalid move e2e4", "readyok"] {
+            assert!(output.iter().any(|line| line == expected), "{} missing from {:?}", expected, output);
+        }
+        // Unknown commands are ignored, and the moves before the bad one stand
+        assert!(!output.iter().any(|line| line.contains("foo")));
+        let mut board = BoardState::new();
+        make_move(&mut board, 52, 36);
+        let reply = uci_to_move(best_moves(&output)[0]).unwrap();
+        assert!(generate_legal_moves(&board).contains(&reply));
+    }
+    
+    #[test]
+    fn xboard_session() {
+        let output = run_session("xboard\nprotover 2\nforce\nusermove e2e4\nusermove e2e4\nfoo\nnew\nsd 2\nusermove d2d4\nping 1\nquit\n");
+        assert_eq!(output.first().unwrap(), "feature done=0");
+        assert!(output.contains(&"feature done=1".to_string()));
+        assert!(output.contains(&"Illegal move: e2e4".to_string()));
+        assert!(output.contains(&"Error (unknown command): foo".to_string()));
+        
+        // The engine plays black after new, and its move comes before the pong
+        let engine_move = output.iter().position(|line| line.starts_with("move ")).unwrap();
+        assert_eq!(output.last().unwrap(), "pong 1");
+        assert!(engine_move < output.len() - 1);
+        let mut board = BoardState::new();
+        make_move(&mut board, 51, 35);
+        let reply = uci_to_move(output[engine_move].strip_prefix("move ").unwrap()).unwrap();
+        assert!(generate_legal_moves(&board).contains(&reply));
+        // Thinking lines are ply score time nodes pv
+        assert!(output.iter().any(|line| line.split_whitespace().take(4).all(|field| field.parse::<i64>().is_ok())));
+    }
+
+    // Output that holds the worker up just after it prints its move, as a
+    // GUI may answer before the search thread has returned
+    struct SlowAfterMove {
+        buffer: SharedBuffer,
+        moves: std::sync::mpsc::Sender<String>,
+    }
+    
+    impl Write for SlowAfterMove {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut bytes = self.buffer.0.lock().unwrap();
+            bytes.extend_from_slice(buf);
+            let text = String::from_utf8_lossy(&bytes);
+            if buf.ends_with(b"\n") && let Some(mv) = text.lines().last().and_then(|line| line.strip_prefix("move ")) {
+                self.moves.send(mv.to_string()).ok();
+                drop(bytes);
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            Ok(buf.len())
+        }
+        
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn xboard_reply_right_after_the_engine_move_is_accepted() {
+        init();
+        let settings = EngineSettings::new();
+        let buffer = SharedBuffer::default();
+        let (sender, moves) = std::sync::mpsc::channel();
+        let mut xboard = XBoard::new(Output::new(SlowAfterMove { buffer: buffer.clone(), moves: sender }));
+        for line in ["new", "sd 1", "usermove e2e4"] {
+            xboard.command(line, &settings);
+        }
+        
+        let engine_move = moves.recv_timeout(Duration::from_secs(30)).unwrap();
+        let mut board = BoardState::new();
+        make_move(&mut board, 52, 36);
+        let (from, to) = parse_xboard_move(&engine_move).unwrap();
+        make_move(&mut board, from, to);
+        let (from, to) = generate_legal_moves(&board)[0];
+        xboard.command(&format!("usermove {}", move_to_uci(from, to)), &settings);
+        
+        assert_eq!(xboard.game_ply, 3);
+        make_move(&mut board, from, to);
+        assert_eq!(compute_board_hash(&xboard.board), compute_board_hash(&board));
+        xboard.command("quit", &settings);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(!output.contains("Illegal move"), "{}", output);
+    }
+
+    #[test]
+    fn move_picker_hands_out_each_legal_move_once() {
+        init();
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "4k3/1P6/8/8/8/8/6p1/4K2R b K - 0 1",
+            "4k3/8/8/8/1b6/8/3P4/4K2R w K - 0 1", // In check
+        ];
+        for fen in fens {
+            let board = parse_fen(fen).unwrap();
+            let mut legal = generate_legal_moves(&board);
+            legal.sort();
+            let tt_move = legal[legal.len() / 2];
+            // A stale killer from another position is skipped
+            add_killer_move(3, 0, 63);
+            add_killer_move(3, legal[0].0, legal[0].1);
+            
+            for tt_move in [None, Some(tt_move), Some((0, 63))] {
+                let mut picker = MovePicker::new(&board, tt_move, 3);
+                let mut picked = Vec::new();
+                while let Some(mv) = picker.next(&board) {
+                    picked.push(mv);
+                }
+                if let Some(tt_move) = tt_move.filter(|mv| legal.contains(mv)) {
+                    assert_eq!(picked[0], tt_move, "{}", fen);
+                }
+                picked.sort();
+                assert_eq!(picked, legal, "{}", fen);
+            }
+        }
+    }
+    
+    #[test]
+    fn exchanges_are_evaluated_to_the_end() {
+        init();
+        let see = |fen: &str, mv: &str| {
+            let (from, to) = uci_to_move(mv).unwrap();
+            static_exchange(&parse_fen(fen).unwrap(), from, to)
+        };
+        // An undefended pawn, a defended one, and a rook taking a defended pawn
+        assert_eq!(see("4k3/8/8/3p4/8/8/8/3RK3 w - -", "d1d5"), 100);
+        assert_eq!(see("4k3/8/2p5/3p4/8/8/8/3RK3 w - -", "d1d5"), -400);
+        // The rook behind the first joins in once it has taken
+        assert_eq!(see("3rk3/8/8/3p4/8/8/3R4/3RK3 w - -", "d2d5"), 100);
+        // A knight for a pawn, and a king that may only take the last piece
+        assert_eq!(see("4k3/2p5/3p4/8/4N3/8/8/4K3 w - -", "e4d6"), -200);
+        assert_eq!(see("4k3/8/8/8/8/8/3p4/2B1K3 w - -", "c1d2"), 100);
+        assert_eq!(see("4k3/8/8/8/8/1n6/3p4/4K3 w - -", "e1d2"), 100 - 20_000);
+    }
+}
@@ -0,0 +1,230 @@
+//! Reading games from PGN files.
+//!
+//! Only what the book builder needs: the result and the main line of each
+//! game, with comments, variations and annotations skipped. Moves are resolved
+//! from SAN against the legal moves of the position they are played in.
+
+use crate::{
+    BoardState, generate_legal_moves, get_piece_at_square, coordinates_to_square, BK, BP, WB, WK, WN,
+    WP, WQ, WR,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw,
+    Unknown,
+}
+
+impl GameResult {
+    fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "1-0" => Some(Self::WhiteWins),
+            "0-1" => Some(Self::BlackWins),
+            "1/2-1/2" => Some(Self::Draw),
+            "*" => Some(Self::Unknown),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Game {
+    pub result: GameResult,
+    pub moves: Vec<String>,
+}
+
+/// Splits PGN text into games. A game's result comes from its `Result` tag,
+/// or from the termination marker when the tag is missing.
+pub fn parse_games(text: &str) -> Vec<Game> {
+    let mut games = Vec::new();
+    let mut result = GameResult::Unknown;
+    let mut moves = Vec::new();
+    let mut in_movetext = false;
+
+    let mut chars = text.chars();
+    let mut token = String::new();
+    let mut variation_depth = 0;
+
+    // Moves the current token into the game being read. Returns true for the
+    // termination marker that ends the game.
+    let finish_token = |token: &mut String, moves: &mut Vec<String>, result: &mut GameResult, variation_depth: i32| {
+        if token.is_empty() {
+            return false;
+        }
+        let word = std::mem::take(token);
+        if variation_depth > 0 || word.starts_with('$') {
+            return false;
+        }
+        if let Some(marker) = GameResult::from_token(&word) {
+            if *result == GameResult::Unknown {
+                *result = marker;
+            }
+            return true;
+        }
+        // Move numbers, possibly glued to the move as in "12.e4" or "12...Nf6"
+        let san = word.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+        if !san.is_empty() {
+            moves.push(san.to_string());
+        }
+        false
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if variation_depth == 0 => {
+                finish_token(&mut token, &mut moves, &mut result, variation_depth);
+                // A tag after movetext starts the next game
+                if in_movetext {
+                    games.push(Game { result, moves: std::mem::take(&mut moves) });
+                    result = GameResult::Unknown;
+                    in_movetext = false;
+                }
+                let tag: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                if let Some(value) = tag.strip_prefix("Result ") {
+                    result = GameResult::from_token(value.trim().trim_matches('"')).unwrap_or(GameResult::Unknown);
+                }
+            }
+            '{' => {
+                finish_token(&mut token, &mut moves, &mut result, variation_depth);
+                chars.by_ref().take_while(|&c| c != '}').for_each(drop);
+            }
+            ';' => {
+                finish_token(&mut token, &mut moves, &mut result, variation_depth);
+                chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            }
+            '(' => {
+                finish_token(&mut token, &mut moves, &mut result, variation_depth);
+                variation_depth += 1;
+            }
+            ')' => {
+                finish_token(&mut token, &mut moves, &mut result, variation_depth);
+                variation_depth = (variation_depth - 1).max(0);
+            }
+            c if c.is_whitespace() => {
+                if finish_token(&mut token, &mut moves, &mut result, variation_depth) {
+                    games.push(Game { result, moves: std::mem::take(&mut moves) });
+                    result = GameResult::Unknown;
+                    in_movetext = false;
+                }
+            }
+            c => {
+                in_movetext = true;
+                token.push(c);
+            }
+        }
+    }
+    let ended = finish_token(&mut token, &mut moves, &mut result, variation_depth);
+    if in_movetext || ended {
+        games.push(Game { result, moves });
+    }
+
+    games
+}
+
+/// The legal move a SAN string like `Nbd7`, `exd5` or `O-O` stands for.
+pub fn resolve_san(board: &BoardState, san: &str) -> Option<(u8, u8)> {
+    let san = san.trim_end_matches(['+', '#', '!', '?']);
+    let legal_moves = generate_legal_moves(board);
+    let (king, back_rank) = if board.white_to_move { (WK, 7) } else { (BK, 0) };
+
+    let castle_to = match san {
+        "O-O" | "0-0" => Some(6),
+        "O-O-O" | "0-0-0" => Some(2),
+        _ => None,
+    };
+    if let Some(file) = castle_to {
+        let (from, to) = (back_rank * 8 + 4, back_rank * 8 + file);
+        let is_king = get_piece_at_square(&board.bitboards, from) == Some(king);
+        return (is_king && legal_moves.contains(&(from, to))).then_some((from, to));
+    }
+
+    // Promotions are always to a queen on this board
+    let (san, promotion) = match san.split_once('=') {
+        Some((rest, piece)) => (rest, Some(piece)),
+        None => (san, None),
+    };
+    if promotion.is_some_and(|piece| piece != "Q") {
+        return None;
+    }
+
+    let piece = match san.chars().next()? {
+        'N' => WN,
+        'B' => WB,
+        'R' => WR,
+        'Q' => WQ,
+        'K' => WK,
+        _ => WP,
+    };
+    let piece = if board.white_to_move { piece } else { piece + BP };
+    let body: String = san.chars().skip((piece % 6 != WP) as usize).filter(|&c| c != 'x').collect();
+    if body.len() < 2 {
+        return None;
+    }
+    let to = coordinates_to_square(&body[body.len() - 2..])?;
+
+    // Whatever is left between the piece and the destination disambiguates
+    let hint = &body[..body.len() - 2];
+    let hint_file = hint.chars().find(|c| ('a'..='h').contains(c)).map(|c| c as u8 - b'a');
+    let hint_rank = hint.chars().find(|c| ('1'..='8').contains(c)).map(|c| 7 - (c as u8 - b'1'));
+
+    let mut candidates = legal_moves.into_iter().filter(|&(from, move_to)| {
+        move_to == to
+            && get_piece_at_square(&board.bitboards, from) == Some(piece)
+            && hint_file.is_none_or(|file| from % 8 == file)
+            && hint_rank.is_none_or(|row| from / 8 == row)
+    });
+    let found = candidates.next()?;
+    candidates.next().is_none().then_some(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_move, parse_fen, uci_to_move};
+
+    #[test]
+    fn games_are_split_and_cleaned_up() {
+        let text = r#"[Event "One"]
+[Result "1-0"]
+
+1. e4 e5 {a comment} 2. Nf3 (2. Bc4 Nf6) Nc6 $1 3.Bb5 ; rest of line
+a6 1-0
+
+[Event "Two"]
+1. d4 d5 *
+"#;
+        let games = parse_games(text);
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].result, GameResult::WhiteWins);
+        assert_eq!(games[0].moves, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6"]);
+        assert_eq!(games[1].result, GameResult::Unknown);
+        assert_eq!(games[1].moves, ["d4", "d5"]);
+    }
+
+    #[test]
+    fn san_moves_are_resolved() {
+        crate::tests::init();
+        let mut board = BoardState::new();
+        for (san, uci) in [("e4", "e2e4"), ("e5", "e7e5"), ("Nf3", "g1f3"), ("Nc6", "b8c6"), ("Bc4", "f1c4"), ("Nf6", "g8f6"), ("O-O", "e1g1")] {
+            let mv = resolve_san(&board, san);
+            assert_eq!(mv, uci_to_move(uci), "{}", san);
+            let (from, to) = mv.unwrap();
+            make_move(&mut board, from, to);
+        }
+        assert_eq!(resolve_san(&board, "Nxe4"), uci_to_move("f6e4"));
+        assert_eq!(resolve_san(&board, "Ke7"), uci_to_move("e8e7"));
+        assert_eq!(resolve_san(&board, "Qe2"), None);
+
+        // Both knights reach d2, so the file decides
+        let board = parse_fen("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1").unwrap();
+        assert_eq!(resolve_san(&board, "Nd2"), None);
+        assert_eq!(resolve_san(&board, "Nbd2"), uci_to_move("b1d2"));
+        assert_eq!(resolve_san(&board, "Nfd2+"), uci_to_move("f1d2"));
+
+        let board = parse_fen("8/4P2k/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        assert_eq!(resolve_san(&board, "e8=Q"), uci_to_move("e7e8"));
+        assert_eq!(resolve_san(&board, "e8=N"), None);
+    }
+}
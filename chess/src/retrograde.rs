@@ -0,0 +1,827 @@
+//! Retrograde generation of distance-to-mate tables for small endings.
+//!
+//! A table covers one material signature of up to four pieces, such as `KQvK`
+//! or `KBNvK`, and stores the distance to mate of every position with either
+//! side to move. Tables are built backwards from the mates with un-moves over
+//! `BoardState`, saved in a run-length encoded `.etb` file, and probed by the
+//! search once loaded from the `EndgameTablePath` directory.
+//!
+//! Promotions are always to a queen, matching the move generator.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    BoardState, KING_ATTACKS, KNIGHT_ATTACKS, count_bits, generate_legal_moves, get_all_occupied,
+    get_bishop_attacks, get_lsb, get_queen_attacks, get_rook_attacks, make_move, material_counts,
+    set_bit, clear_bit, syzygy, BK, BP, WK, WP,
+};
+
+pub const MAX_PIECES: usize = 4;
+
+const FILE_MAGIC: &[u8; 4] = b"ETB1";
+
+/// Game-theoretic value from the side to move's point of view, in plies.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dtm {
+    Win(u32),
+    Loss(u32),
+    Draw,
+}
+
+impl Dtm {
+    // The value one ply earlier, seen by the side that made the move
+    fn before_move(self) -> Dtm {
+        match self {
+            Dtm::Win(plies) => Dtm::Loss(plies + 1),
+            Dtm::Loss(plies) => Dtm::Win(plies + 1),
+            Dtm::Draw => Dtm::Draw,
+        }
+    }
+
+    // Shortest wins first, then draws, then the longest losses
+    fn rank(self) -> (u8, i64) {
+        match self {
+            Dtm::Win(plies) => (2, -(plies as i64)),
+            Dtm::Draw => (1, 0),
+            Dtm::Loss(plies) => (0, plies as i64),
+        }
+    }
+
+    // Stored as a byte: 0 is a draw, 1..=127 a win and 128.. a loss, in moves
+    fn to_byte(self) -> u8 {
+        match self {
+            Dtm::Win(plies) => plies.div_ceil(2).min(127) as u8,
+            Dtm::Loss(plies) => 128 + (plies / 2).min(127) as u8,
+            Dtm::Draw => 0,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Dtm {
+        match byte {
+            0 => Dtm::Draw,
+            1..=127 => Dtm::Win(2 * byte as u32 - 1),
+            _ => Dtm::Loss(2 * (byte - 128) as u32),
+        }
+    }
+}
+
+//LAYOUT---------------------------------------------------------------------------------------------------
+
+// Board symmetries, applied to square numbers
+const TRANSFORMS: [fn(u8) -> u8; 8] = [
+    |s| s,
+    |s| s ^ 7,
+    |s| s ^ 56,
+    |s| s ^ 63,
+    |s| ((s >> 3) | (s << 3)) & 63,
+    |s| (((s >> 3) | (s << 3)) & 63) ^ 7,
+    |s| (((s >> 3) | (s << 3)) & 63) ^ 56,
+    |s| (((s >> 3) | (s << 3)) & 63) ^ 63,
+];
+
+// Where positions place their pieces in the table. The white king is folded
+// into the a1-d1-d4 triangle, or onto files a-d when there are pawns, and the
+// index of a position is the smallest one over the symmetries doing so.
+#[derive(Clone, Debug)]
+struct Layout {
+    name: String,
+    pieces: Vec<usize>, // Non-king pieces, grouped by type
+    has_pawns: bool,
+}
+
+impl Layout {
+    fn from_name(name: &str) -> Option<Self> {
+        let (white, black) = name.split_once('v')?;
+        let mut pieces = Vec::new();
+        for (side, letters) in [white, black].iter().enumerate() {
+            if letters.chars().filter(|&c| c == 'K').count() != 1 {
+                return None;
+            }
+            for c in letters.chars().filter(|&c| c != 'K') {
+                pieces.push("PNBRQ".find(c)? + 6 * side);
+            }
+        }
+        if pieces.len() + 2 > MAX_PIECES {
+            return None;
+        }
+        pieces.sort_unstable();
+
+        Some(Self {
+            name: name.to_string(),
+            has_pawns: pieces.iter().any(|&piece| piece == WP || piece == BP),
+            pieces,
+        })
+    }
+
+    fn king_slots(&self) -> usize {
+        if self.has_pawns { 32 } else { 10 }
+    }
+
+    fn size(&self) -> usize {
+        self.king_slots() * 64usize.pow(self.pieces.len() as u32 + 1) * 2
+    }
+
+    fn king_slot(&self, square: u8) -> Option<usize> {
+        let (rank, file) = (7 - square / 8, square % 8);
+        if file > 3 {
+            return None;
+        }
+        if self.has_pawns {
+            return Some((rank * 4 + file) as usize);
+        }
+        // a1, b1, c1, d1, b2, c2, d2, c3, d3, d4
+        (rank <= file).then(|| [0, 4, 7, 9][rank as usize] + (file - rank) as usize)
+    }
+
+    fn slot_square(&self, slot: usize) -> u8 {
+        (0..64).find(|&square| self.king_slot(square) == Some(slot)).unwrap()
+    }
+
+    fn index(&self, board: &BoardState) -> usize {
+        let transforms = if self.has_pawns { &TRANSFORMS[..2] } else { &TRANSFORMS[..] };
+        let white_king = get_lsb(board.bitboards[WK]).unwrap();
+        let black_king = get_lsb(board.bitboards[BK]).unwrap();
+
+        let mut best = usize::MAX;
+        for transform in transforms {
+            let Some(slot) = self.king_slot(transform(white_king)) else {
+                continue;
+            };
+            let mut index = slot * 64 + transform(black_king) as usize;
+
+            // Identical pieces are placed in ascending square order
+            let mut i = 0;
+            while i < self.pieces.len() {
+                let piece = self.pieces[i];
+                let mut squares: Vec<u8> = (0..64)
+                    .filter(|&square| crate::get_bit(board.bitboards[piece], square))
+                    .map(transform)
+                    .collect();
+                squares.sort_unstable();
+                for square in squares {
+                    index = index * 64 + square as usize;
+                    i += 1;
+                }
+            }
+
+            best = best.min(index * 2 + !board.white_to_move as usize);
+        }
+        best
+    }
+
+    // The position stored at an index, or None for illegal positions and
+    // indices that aren't the canonical one of their position
+    fn decode(&self, index: usize) -> Option<BoardState> {
+        let white_to_move = index & 1 == 0;
+        let mut rest = index >> 1;
+
+        let mut squares = vec![0u8; self.pieces.len()];
+        for square in squares.iter_mut().rev() {
+            *square = (rest % 64) as u8;
+            rest /= 64;
+        }
+        let black_king = (rest % 64) as u8;
+        let white_king = self.slot_square(rest / 64);
+
+        if black_king == white_king {
+            return None;
+        }
+        let mut bitboards = [0u64; 12];
+        set_bit(&mut bitboards[WK], white_king);
+        set_bit(&mut bitboards[BK], black_king);
+        for (&piece, &square) in self.pieces.iter().zip(squares.iter()) {
+            let pawn_on_back_rank = (piece == WP || piece == BP) && !(8..56).contains(&square);
+            if get_all_occupied(bitboards) & (1 << square) != 0 || pawn_on_back_rank {
+                return None;
+            }
+            set_bit(&mut bitboards[piece], square);
+        }
+
+        let mut board = BoardState {
+            bitboards,
+            white_to_move,
+            white_kingside_castle: false,
+            white_queenside_castle: false,
+            black_kingside_castle: false,
+            black_queenside_castle: false,
+            white_king_in_check: false,
+            black_king_in_check: false,
+            en_passant_target: None,
+        };
+        board.update_check_status();
+
+        // The side that just moved can't be left in check
+        let waiting_in_check = if white_to_move { board.black_king_in_check } else { board.white_king_in_check };
+        if waiting_in_check || self.index(&board) != index {
+            return None;
+        }
+        Some(board)
+    }
+}
+
+// Positions one move before this one, with the moved piece taken back. Captures
+// and promotions are never un-made since they change the material.
+fn unmoves(board: &BoardState) -> Vec<BoardState> {
+    let white_moved = !board.white_to_move;
+    let occupied = get_all_occupied(board.bitboards);
+    let first_piece = if white_moved { WP } else { BP };
+    let mut previous_positions = Vec::new();
+
+    for piece in first_piece..first_piece + 6 {
+        let mut bitboard = board.bitboards[piece];
+        while let Some(to) = get_lsb(bitboard) {
+            bitboard &= bitboard - 1;
+
+            let origins = match piece - first_piece {
+                0 => pawn_origins(to, white_moved, occupied),
+                1 => KNIGHT_ATTACKS.get().unwrap()[to as usize],
+                2 => get_bishop_attacks(to, occupied),
+                3 => get_rook_attacks(to, occupied),
+                4 => get_queen_attacks(to, occupied),
+                _ => KING_ATTACKS.get().unwrap()[to as usize],
+            } & !occupied;
+
+            let mut origins = origins;
+            while let Some(from) = get_lsb(origins) {
+                origins &= origins - 1;
+
+                let mut previous = *board;
+                clear_bit(&mut previous.bitboards[piece], to);
+                set_bit(&mut previous.bitboards[piece], from);
+                previous.white_to_move = white_moved;
+                previous.update_check_status();
+
+                let waiting_in_check = if white_moved { previous.black_king_in_check } else { previous.white_king_in_check };
+                if !waiting_in_check {
+                    previous_positions.push(previous);
+                }
+            }
+        }
+    }
+
+    previous_positions
+}
+
+fn pawn_origins(to: u8, white: bool, occupied: u64) -> u64 {
+    let mut origins = 0;
+    if white {
+        // White pawns move towards a8 = 0
+        if to + 8 < 56 {
+            set_bit(&mut origins, to + 8);
+        }
+        if to / 8 == 4 && occupied & (1 << (to + 8)) == 0 {
+            set_bit(&mut origins, to + 16);
+        }
+    } else {
+        if to >= 16 {
+            set_bit(&mut origins, to - 8);
+        }
+        if to / 8 == 3 && occupied & (1 << (to - 8)) == 0 {
+            set_bit(&mut origins, to - 16);
+        }
+    }
+    origins
+}
+
+//END OF LAYOUT--------------------------------------------------------------------------------------------
+
+
+//TABLES---------------------------------------------------------------------------------------------------
+
+pub struct EndgameTable {
+    layout: Layout,
+    values: Vec<u8>,
+}
+
+impl EndgameTable {
+    pub fn name(&self) -> &str {
+        &self.layout.name
+    }
+
+    /// Value of a position with exactly this table's material.
+    pub fn probe(&self, board: &BoardState) -> Dtm {
+        Dtm::from_byte(self.values[self.layout.index(board)])
+    }
+
+    /// Wins, draws and losses for the side to move, and the longest mate in moves.
+    pub fn statistics(&self) -> (usize, usize, usize, u32) {
+        let (mut wins, mut draws, mut losses, mut longest) = (0, 0, 0, 0);
+        for index in 0..self.values.len() {
+            if self.layout.decode(index).is_none() {
+                continue;
+            }
+            match Dtm::from_byte(self.values[index]) {
+                Dtm::Win(plies) => {
+                    wins += 1;
+                    longest = longest.max(plies.div_ceil(2));
+                }
+                Dtm::Draw => draws += 1,
+                Dtm::Loss(_) => losses += 1,
+            }
+        }
+        (wins, draws, losses, longest)
+    }
+
+    /// Writes the table as the name followed by run-length encoded values.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = Vec::new();
+        out.extend_from_slice(FILE_MAGIC);
+        out.push(self.layout.name.len() as u8);
+        out.extend_from_slice(self.layout.name.as_bytes());
+        out.extend_from_slice(&(self.values.len() as u32).to_le_bytes());
+
+        let mut i = 0;
+        while i < self.values.len() {
+            let value = self.values[i];
+            let run = self.values[i..].iter().take_while(|&&v| v == value).count();
+            out.push(value);
+            write_varint(&mut out, run as u64);
+            i += run;
+        }
+
+        std::fs::File::create(path)?.write_all(&out)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut bytes = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.get(..4) != Some(FILE_MAGIC) {
+            return Err(invalid("not an endgame table"));
+        }
+
+        let name_len = *bytes.get(4).ok_or_else(|| invalid("truncated header"))? as usize;
+        let name = bytes.get(5..5 + name_len)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or_else(|| invalid("bad table name"))?;
+        let layout = Layout::from_name(name).ok_or_else(|| invalid("unsupported material"))?;
+
+        let mut offset = 5 + name_len;
+        let count = bytes.get(offset..offset + 4)
+            .map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("truncated header"))?;
+        offset += 4;
+        if count != layout.size() {
+            return Err(invalid("wrong table size"));
+        }
+
+        let mut values = Vec::with_capacity(count);
+        while offset < bytes.len() {
+            let value = bytes[offset];
+            offset += 1;
+            let run = read_varint(&bytes, &mut offset).ok_or_else(|| invalid("truncated data"))?;
+            values.extend(std::iter::repeat_n(value, run as usize));
+        }
+        if values.len() != count {
+            return Err(invalid("wrong number of values"));
+        }
+
+        Ok(Self { layout, values })
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn material_name(board: &BoardState) -> String {
+    format!("{}v{}", syzygy::side_code(board, true), syzygy::side_code(board, false))
+}
+
+fn mirrored_name(name: &str) -> String {
+    let (white, black) = name.split_once('v').unwrap_or((name, ""));
+    format!("{}v{}", black, white)
+}
+
+// Value of a position from a set of tables, trying the colour-swapped table
+// when the position has the stronger side as Black
+fn probe_tables(tables: &HashMap<String, Arc<EndgameTable>>, board: &BoardState) -> Option<Dtm> {
+    if count_bits(get_all_occupied(board.bitboards)) == 2 {
+        return Some(Dtm::Draw);
+    }
+    let name = material_name(board);
+    if let Some(table) = tables.get(&name) {
+        return Some(table.probe(board));
+    }
+    tables.get(&mirrored_name(&name)).map(|table| table.probe(&board.mirror()))
+}
+
+//END OF TABLES--------------------------------------------------------------------------------------------
+
+
+//GENERATION-----------------------------------------------------------------------------------------------
+
+/// Builds tables along with the smaller ones their captures and promotions
+/// lead to, keeping everything generated so far.
+#[derive(Default)]
+pub struct Generator {
+    tables: HashMap<String, Arc<EndgameTable>>,
+}
+
+impl Generator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Generates the table for a material signature like `KBNvK`, or returns
+    /// None when the signature isn't supported.
+    pub fn generate(&mut self, name: &str) -> Option<Arc<EndgameTable>> {
+        if let Some(table) = self.tables.get(name) {
+            return Some(table.clone());
+        }
+        let layout = Layout::from_name(name)?;
+
+        for dependency in dependencies(&layout) {
+            if dependency.len() > 3 && !self.tables.contains_key(&mirrored_name(&dependency)) {
+                self.generate(&dependency)?;
+            }
+        }
+
+        let table = Arc::new(self.build(layout));
+        self.tables.insert(name.to_string(), table.clone());
+        Some(table)
+    }
+
+    fn build(&self, layout: Layout) -> EndgameTable {
+        const UNRESOLVED: i16 = i16::MAX;
+        let size = layout.size();
+        let target_counts = {
+            let mut counts = [0u32; 12];
+            counts[WK] = 1;
+            counts[BK] = 1;
+            for &piece in &layout.pieces {
+                counts[piece] += 1;
+            }
+            counts
+        };
+
+        // Resolved values: plies to mate, positive for wins and zero or
+        // negative for losses
+        let mut values = vec![UNRESOLVED; size];
+        // Distinct successors inside the table not yet known to be won for
+        // the opponent
+        let mut remaining = vec![0u8; size];
+        // Best result reachable by captures and promotions out of the table
+        let mut best_exit: Vec<Option<Dtm>> = vec![None; size];
+        // Positions waiting to be resolved, by number of plies
+        let mut buckets: Vec<Vec<(usize, bool)>> = Vec::new();
+        let schedule = |buckets: &mut Vec<Vec<(usize, bool)>>, plies: usize, index: usize, win: bool| {
+            if buckets.len() <= plies {
+                buckets.resize_with(plies + 1, Vec::new);
+            }
+            buckets[plies].push((index, win));
+        };
+
+        for index in 0..size {
+            let Some(board) = layout.decode(index) else {
+                continue;
+            };
+            let moves = generate_legal_moves(&board);
+            if moves.is_empty() {
+                if board.is_current_king_in_check() {
+                    schedule(&mut buckets, 0, index, false);
+                }
+                continue;
+            }
+
+            let mut successors = Vec::new();
+            let mut exit: Option<Dtm> = None;
+            for (from, to) in moves {
+                let mut next = board;
+                make_move(&mut next, from, to);
+                if material_counts(&next) == target_counts {
+                    successors.push(layout.index(&next));
+                } else {
+                    let value = probe_tables(&self.tables, &next).unwrap_or(Dtm::Draw).before_move();
+                    if exit.is_none_or(|best| value.rank() > best.rank()) {
+                        exit = Some(value);
+                    }
+                }
+            }
+            successors.sort_unstable();
+            successors.dedup();
+            remaining[index] = successors.len() as u8;
+            best_exit[index] = exit;
+
+            match exit {
+                Some(Dtm::Win(plies)) => schedule(&mut buckets, plies as usize, index, true),
+                Some(Dtm::Loss(plies)) if successors.is_empty() => schedule(&mut buckets, plies as usize, index, false),
+                _ => {}
+            }
+        }
+
+        // Work outwards from the mates one ply at a time, so the first value
+        // a position receives is its shortest win or longest loss
+        let mut plies = 0;
+        while plies < buckets.len() {
+            let bucket = std::mem::take(&mut buckets[plies]);
+            for (index, win) in bucket {
+                if values[index] != UNRESOLVED {
+                    continue;
+                }
+                values[index] = if win { plies as i16 } else { -(plies as i16) };
+
+                let board = layout.decode(index).unwrap();
+                let mut predecessors: Vec<usize> = unmoves(&board).iter().map(|previous| layout.index(previous)).collect();
+                predecessors.sort_unstable();
+                predecessors.dedup();
+
+                for previous in predecessors {
+                    if values[previous] != UNRESOLVED {
+                        continue;
+                    }
+                    if !win {
+                        // Moving into a lost position wins
+                        schedule(&mut buckets, plies + 1, previous, true);
+                        continue;
+                    }
+
+                    remaining[previous] -= 1;
+                    if remaining[previous] == 0 {
+                        // Every move inside the table loses; the exits decide
+                        match best_exit[previous] {
+                            None => schedule(&mut buckets, plies + 1, previous, false),
+                            Some(Dtm::Loss(exit_plies)) => {
+                                schedule(&mut buckets, (plies + 1).max(exit_plies as usize), previous, false)
+                            }
+                            Some(_) => {}
+                        }
+                    }
+                }
+            }
+            plies += 1;
+        }
+
+        let values = values.iter()
+            .map(|&value| match value {
+                UNRESOLVED => Dtm::Draw,
+                1.. => Dtm::Win(value as u32),
+                _ => Dtm::Loss(-value as u32),
+            }.to_byte())
+            .collect();
+
+        EndgameTable { layout, values }
+    }
+}
+
+// Material signatures reachable by one capture or promotion
+fn dependencies(layout: &Layout) -> Vec<String> {
+    let mut names = Vec::new();
+    for i in 0..layout.pieces.len() {
+        let mut pieces = layout.pieces.clone();
+        let removed = pieces.remove(i);
+        names.push(signature(&pieces));
+
+        if removed == WP || removed == BP {
+            pieces.push(removed + 4);
+            names.push(signature(&pieces));
+        }
+    }
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+fn signature(pieces: &[usize]) -> String {
+    let side = |base: usize| {
+        let mut code = String::from("K");
+        for (piece, letter) in [(4, 'Q'), (3, 'R'), (2, 'B'), (1, 'N'), (0, 'P')] {
+            for _ in pieces.iter().filter(|&&p| p == base + piece) {
+                code.push(letter);
+            }
+        }
+        code
+    };
+    format!("{}v{}", side(0), side(6))
+}
+
+//END OF GENERATION----------------------------------------------------------------------------------------
+
+
+//PROBING--------------------------------------------------------------------------------------------------
+
+static TABLES: RwLock<Option<HashMap<String, Arc<EndgameTable>>>> = RwLock::new(None);
+
+// Pieces in the largest loaded table, zero when none are loaded
+static LOADED_PIECES: AtomicUsize = AtomicUsize::new(0);
+
+// One bit per KPvK position: set when the side with the pawn wins
+struct Bitbase {
+    layout: Layout,
+    bits: Vec<u64>,
+}
+
+static KPK_BITBASE: RwLock<Option<Bitbase>> = RwLock::new(None);
+
+impl Bitbase {
+    fn from_table(table: &EndgameTable) -> Self {
+        let mut bits = vec![0u64; table.values.len().div_ceil(64)];
+        for (index, &value) in table.values.iter().enumerate() {
+            let white_to_move = index & 1 == 0;
+            let pawn_side_wins = match Dtm::from_byte(value) {
+                Dtm::Win(_) => white_to_move,
+                Dtm::Loss(_) => !white_to_move,
+                Dtm::Draw => false,
+            };
+            if pawn_side_wins {
+                bits[index / 64] |= 1 << (index % 64);
+            }
+        }
+        Self { layout: table.layout.clone(), bits }
+    }
+}
+
+/// Makes the tables available to the search, replacing any loaded before.
+pub fn install(tables: Vec<Arc<EndgameTable>>) {
+    let mut max_pieces = 0;
+    let mut map = HashMap::new();
+    let mut bitbase = None;
+
+    for table in tables {
+        max_pieces = max_pieces.max(table.layout.pieces.len() + 2);
+        if table.name() == "KPvK" {
+            bitbase = Some(Bitbase::from_table(&table));
+        }
+        map.insert(table.name().to_string(), table);
+    }
+
+    *TABLES.write().unwrap() = Some(map);
+    *KPK_BITBASE.write().unwrap() = bitbase;
+    LOADED_PIECES.store(max_pieces, Ordering::Relaxed);
+}
+
+/// Loads every `.etb` file in a directory. Returns the number of tables loaded;
+/// an empty path or `<empty>` unloads them.
+pub fn load_dir(path: &str) -> usize {
+    let mut tables = Vec::new();
+    if !path.is_empty() && path != "<empty>" && let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "etb") && let Ok(table) = EndgameTable::load(&path) {
+                tables.push(Arc::new(table));
+            }
+        }
+    }
+    let count = tables.len();
+    install(tables);
+    count
+}
+
+/// Distance to mate of a position covered by the loaded tables.
+pub fn probe(board: &BoardState) -> Option<Dtm> {
+    if count_bits(get_all_occupied(board.bitboards)) as usize > LOADED_PIECES.load(Ordering::Relaxed) {
+        return None;
+    }
+    let guard = TABLES.read().unwrap();
+    let tables = guard.as_ref()?;
+    if count_bits(get_all_occupied(board.bitboards)) == 2 {
+        return None;
+    }
+    let dtm = probe_tables(tables, board)?;
+    syzygy::TB_HITS.fetch_add(1, Ordering::Relaxed);
+    Some(dtm)
+}
+
+/// Best move by the loaded tables: the fastest mate, a draw, or the longest
+/// resistance. None unless every move can be probed.
+pub fn best_move(board: &BoardState) -> Option<((u8, u8), Dtm)> {
+    probe(board)?;
+    let mut best: Option<((u8, u8), Dtm)> = None;
+    for (from, to) in generate_legal_moves(board) {
+        let mut next = *board;
+        make_move(&mut next, from, to);
+        let value = if count_bits(get_all_occupied(next.bitboards)) == 2 {
+            Dtm::Draw
+        } else {
+            probe(&next)?.before_move()
+        };
+        if best.is_none_or(|(_, best_value)| value.rank() > best_value.rank()) {
+            best = Some(((from, to), value));
+        }
+    }
+    best
+}
+
+/// Whether White wins a KPvK position with the white pawn, or None when the
+/// bitbase isn't loaded.
+pub fn probe_kpk_bitbase(board: &BoardState) -> Option<bool> {
+    let guard = KPK_BITBASE.read().unwrap();
+    let bitbase = guard.as_ref()?;
+    let index = bitbase.layout.index(board);
+    Some(bitbase.bits[index / 64] & (1 << (index % 64)) != 0)
+}
+
+//END OF PROBING-------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_fen;
+
+    #[test]
+    fn longest_mates_match_known_values() {
+        crate::tests::init();
+        let mut generator = Generator::new();
+
+        // Ten moves for the queen and sixteen for the rook, White to move
+        let (_, _, _, longest) = generator.generate("KQvK").unwrap().statistics();
+        assert_eq!(longest, 10);
+        let (_, _, _, longest) = generator.generate("KRvK").unwrap().statistics();
+        assert_eq!(longest, 16);
+
+        // A lone minor piece never wins
+        let (wins, _, losses, _) = generator.generate("KNvK").unwrap().statistics();
+        assert_eq!((wins, losses), (0, 0));
+    }
+
+    #[test]
+    fn king_and_pawn_results() {
+        crate::tests::init();
+        let mut generator = Generator::new();
+        let table = generator.generate("KPvK").unwrap();
+
+        // King on the sixth rank in front of its pawn wins whoever moves
+        let board = parse_fen("4k3/8/4K3/4P3/8/8/8/8 w - -").unwrap();
+        assert!(matches!(table.probe(&board), Dtm::Win(_)));
+        let board = parse_fen("4k3/8/4K3/4P3/8/8/8/8 b - -").unwrap();
+        assert!(matches!(table.probe(&board), Dtm::Loss(_)));
+
+        // Black to move is stalemated, White to move wins
+        let board = parse_fen("4k3/4P3/4K3/8/8/8/8/8 b - -").unwrap();
+        assert_eq!(table.probe(&board), Dtm::Draw);
+        let board = parse_fen("4k3/4P3/4K3/8/8/8/8/8 w - -").unwrap();
+        assert!(matches!(table.probe(&board), Dtm::Win(_)));
+
+        // The table is found for either colour
+        install(vec![table.clone()]);
+        let board = parse_fen("8/8/8/8/4p3/4k3/8/4K3 w - -").unwrap();
+        assert!(matches!(probe(&board), Some(Dtm::Loss(_))));
+        assert_eq!(probe_kpk_bitbase(&parse_fen("4k3/8/4K3/4P3/8/8/8/8 b - -").unwrap()), Some(true));
+        install(Vec::new());
+    }
+
+    #[test]
+    fn tables_survive_a_round_trip_through_a_file() {
+        crate::tests::init();
+        let table = Generator::new().generate("KRvK").unwrap();
+        let path = std::env::temp_dir().join(format!("chess-{}-KRvK.etb", std::process::id()));
+        table.save(&path).unwrap();
+        let loaded = EndgameTable::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.name(), "KRvK");
+        assert!(loaded.values == table.values);
+    }
+
+    // Whenever the evaluation claims a known win in a quiet position, one
+    // without captures to resolve first, the table must agree
+    #[test]
+    fn endgame_evaluators_only_claim_real_wins() {
+        crate::tests::init();
+        let mut generator = Generator::new();
+        for name in ["KPvK", "KQvK", "KRvK"] {
+            let table = generator.generate(name).unwrap();
+            for index in 0..table.values.len() {
+                let Some(board) = table.layout.decode(index) else {
+                    continue;
+                };
+                let occupied = get_all_occupied(board.bitboards);
+                let has_capture = generate_legal_moves(&board).iter().any(|&(_, to)| occupied & (1 << to) != 0);
+                if has_capture {
+                    continue;
+                }
+
+                let score = crate::evaluate(&board);
+                let value = table.probe(&board);
+                if score >= crate::KNOWN_WIN {
+                    assert!(matches!(value, Dtm::Win(_)), "{} claims a win: {:?}", name, board);
+                } else if score <= -crate::KNOWN_WIN {
+                    assert!(matches!(value, Dtm::Loss(_)), "{} claims a loss: {:?}", name, board);
+                }
+            }
+        }
+    }
+}
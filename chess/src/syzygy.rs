@@ -0,0 +1,1774 @@
+//! Syzygy tablebase probing.
+//!
+//! WDL (`.rtbw`) and DTZ (`.rtbz`) files are looked up in the directories set
+//! through the `SyzygyPath` option and loaded on first use. The decoder follows
+//! the layout written by the original generator: pieces are split into groups
+//! that are indexed together, and the values are stored as canonical Huffman
+//! codes over recursive-pairing symbols, split into blocks with a sparse index.
+//!
+//! Squares inside this module use the generator's numbering (a1 = 0, h8 = 63),
+//! which is the board's numbering with the ranks flipped.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{OnceLock, RwLock};
+
+use crate::{
+    BoardState, count_bits, generate_legal_moves, get_all_occupied, get_bit, get_piece_at_square,
+    make_move, square_distance, BP, WP,
+};
+
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+// Largest tables the indexing scheme supports
+const TB_PIECES: usize = 7;
+
+// Per-table flags stored in front of the size information
+const FLAG_STM: u8 = 1;
+const FLAG_MAPPED: u8 = 2;
+const FLAG_WIN_PLIES: u8 = 4;
+const FLAG_LOSS_PLIES: u8 = 8;
+const FLAG_WIDE: u8 = 16;
+const FLAG_SINGLE_VALUE: u8 = 128;
+
+/// Number of successful probes since the counter was last reset, reported as
+/// `tbhits` in the UCI info lines. Probes of the generated tables count too.
+pub static TB_HITS: AtomicU64 = AtomicU64::new(0);
+
+// Piece count of the largest table found, zero when none are loaded. Checked
+// before anything else so probing costs nothing without tablebases.
+static MAX_PIECES: AtomicUsize = AtomicUsize::new(0);
+
+static TABLEBASES: RwLock<Option<Tablebases>> = RwLock::new(None);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Wdl {
+    Loss = -2,
+    BlessedLoss = -1, // Loss, but drawn under the fifty-move rule
+    Draw = 0,
+    CursedWin = 1,    // Win, but drawn under the fifty-move rule
+    Win = 2,
+}
+
+impl Wdl {
+    fn from_value(value: i32) -> Self {
+        match value {
+            ..=-2 => Wdl::Loss,
+            -1 => Wdl::BlessedLoss,
+            0 => Wdl::Draw,
+            1 => Wdl::CursedWin,
+            _ => Wdl::Win,
+        }
+    }
+}
+
+impl std::ops::Neg for Wdl {
+    type Output = Wdl;
+
+    fn neg(self) -> Wdl {
+        Wdl::from_value(-(self as i32))
+    }
+}
+
+//INDEXING TABLES------------------------------------------------------------------------------------------
+
+struct Indexes {
+    map_pawns: [usize; 64],
+    map_b1h1h7: [usize; 64],
+    map_a1d1d4: [usize; 64],
+    map_kk: [[usize; 64]; 10],
+    binomial: [[u64; 64]; 6],
+    lead_pawn_idx: [[u64; 64]; 6],
+    lead_pawns_size: [[u64; 4]; 6],
+}
+
+static INDEXES: OnceLock<Indexes> = OnceLock::new();
+
+#[inline]
+fn rank_of(square: usize) -> i32 {
+    (square >> 3) as i32
+}
+
+#[inline]
+fn file_of(square: usize) -> i32 {
+    (square & 7) as i32
+}
+
+// Positive above the a1-h8 diagonal, negative below, zero on it
+#[inline]
+fn off_a1h8(square: usize) -> i32 {
+    rank_of(square) - file_of(square)
+}
+
+fn indexes() -> &'static Indexes {
+    INDEXES.get_or_init(|| {
+        let mut ix = Indexes {
+            map_pawns: [0; 64],
+            map_b1h1h7: [0; 64],
+            map_a1d1d4: [0; 64],
+            map_kk: [[0; 64]; 10],
+            binomial: [[0; 64]; 6],
+            lead_pawn_idx: [[0; 64]; 6],
+            lead_pawns_size: [[0; 4]; 6],
+        };
+
+        // Squares below the a1-h8 diagonal map to 0..27
+        let mut code = 0;
+        for square in 0..64 {
+            if off_a1h8(square) < 0 {
+                ix.map_b1h1h7[square] = code;
+                code += 1;
+            }
+        }
+
+        // The a1-d1-d4 triangle maps to 0..9, with the diagonal squares last
+        let mut diagonal = Vec::new();
+        code = 0;
+        for square in 0..28 {
+            if off_a1h8(square) < 0 && file_of(square) <= 3 {
+                ix.map_a1d1d4[square] = code;
+                code += 1;
+            } else if off_a1h8(square) == 0 && file_of(square) <= 3 {
+                diagonal.push(square);
+            }
+        }
+        for square in diagonal {
+            ix.map_a1d1d4[square] = code;
+            code += 1;
+        }
+
+        // The 462 legal placements of two kings with the first one in the
+        // a1-d1-d4 triangle. When the first king is on the diagonal the second
+        // one may not be above it, and pairs both on the diagonal come last.
+        let mut both_on_diagonal = Vec::new();
+        code = 0;
+        for idx in 0..10 {
+            for first in 0..28 {
+                if ix.map_a1d1d4[first] != idx || (idx == 0 && first != 1) {
+                    continue;
+                }
+                for second in 0..64 {
+                    let adjacent = square_distance(first as u8, second as u8) <= 1;
+                    if adjacent || (off_a1h8(first) == 0 && off_a1h8(second) > 0) {
+                        continue;
+                    } else if off_a1h8(first) == 0 && off_a1h8(second) == 0 {
+                        both_on_diagonal.push((idx, second));
+                    } else {
+                        ix.map_kk[idx][second] = code;
+                        code += 1;
+                    }
+                }
+            }
+        }
+        for (idx, second) in both_on_diagonal {
+            ix.map_kk[idx][second] = code;
+            code += 1;
+        }
+
+        // binomial[k][n]: ways to choose k elements out of n
+        ix.binomial[0][0] = 1;
+        for n in 1..64 {
+            for k in 0..6.min(n + 1) {
+                ix.binomial[k][n] = if k > 0 { ix.binomial[k - 1][n - 1] } else { 0 }
+                    + if k < n { ix.binomial[k][n - 1] } else { 0 };
+            }
+        }
+
+        // map_pawns counts the squares left for the other pawns when the
+        // leading pawn stands on a square; the leading pawn is the one with the
+        // highest value, nearest the edge and lowest among equal files
+        let mut available = 47;
+        for lead_pawns in 1..=5 {
+            for file in 0..4 {
+                let mut idx = 0;
+                for rank in 1..7 {
+                    let square = rank * 8 + file;
+                    if lead_pawns == 1 {
+                        ix.map_pawns[square] = available;
+                        ix.map_pawns[square ^ 7] = available.saturating_sub(1);
+                        available = available.saturating_sub(2);
+                    }
+                    ix.lead_pawn_idx[lead_pawns][square] = idx;
+                    idx += ix.binomial[lead_pawns - 1][ix.map_pawns[square]];
+                }
+                ix.lead_pawns_size[lead_pawns][file] = idx;
+            }
+        }
+
+        ix
+    })
+}
+
+//END OF INDEXING TABLES-----------------------------------------------------------------------------------
+
+
+//TABLE LAYOUT---------------------------------------------------------------------------------------------
+
+// Material of a table as parsed from its name, with the first side as White
+#[derive(Clone, Debug)]
+struct TableInfo {
+    piece_count: usize,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    pawn_count: [usize; 2], // Leading colour first
+    symmetric: bool,
+}
+
+impl TableInfo {
+    fn from_name(name: &str) -> Option<Self> {
+        let (white, black) = name.split_once('v')?;
+        let mut counts = [[0usize; 6]; 2];
+        for (side, pieces) in [white, black].iter().enumerate() {
+            for c in pieces.chars() {
+                let piece = "PNBRQK".find(c)?;
+                counts[side][piece] += 1;
+            }
+        }
+        if counts[0][5] != 1 || counts[1][5] != 1 {
+            return None;
+        }
+
+        let piece_count = white.len() + black.len();
+        if piece_count > TB_PIECES {
+            return None;
+        }
+
+        // The side with fewer pawns leads, as that compresses better
+        let (white_pawns, black_pawns) = (counts[0][0], counts[1][0]);
+        let white_leads = black_pawns == 0 || (white_pawns > 0 && black_pawns >= white_pawns);
+
+        Some(Self {
+            piece_count,
+            has_pawns: white_pawns + black_pawns > 0,
+            has_unique_pieces: counts.iter().any(|side| side[..5].contains(&1)),
+            pawn_count: if white_leads { [white_pawns, black_pawns] } else { [black_pawns, white_pawns] },
+            symmetric: white == black,
+        })
+    }
+}
+
+// One sub-table: a side to move (WDL only) and a leading pawn file
+#[derive(Clone, Default)]
+struct PairsData {
+    flags: u8,
+    pieces: [u8; TB_PIECES],
+    group_len: [usize; TB_PIECES + 1],
+    group_idx: [u64; TB_PIECES + 1],
+    block_size: u64,
+    span: u64,
+    sparse_index_size: u64,
+    num_blocks: u64,
+    block_length_size: u64,
+    min_sym_len: u8,
+    base64: Vec<u64>,
+    symlen: Vec<u8>,
+    // Offsets into the file
+    lowest_sym: usize,
+    btree: usize,
+    sparse_index: usize,
+    block_length: usize,
+    data: usize,
+    map_idx: [usize; 4],
+}
+
+struct Table {
+    info: TableInfo,
+    dtz: bool,
+    bytes: Vec<u8>,
+    items: [[PairsData; 4]; 2], // [side][file]
+    map: usize,
+}
+
+#[inline]
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+#[inline]
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// Big-endian reads for the Huffman stream, which may run past the last block
+#[inline]
+fn read_be_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = bytes.get(offset + i).copied().unwrap_or(0);
+    }
+    u32::from_be_bytes(buf)
+}
+
+impl Table {
+    fn new(name: &str, bytes: Vec<u8>, dtz: bool) -> Option<Self> {
+        let info = TableInfo::from_name(name)?;
+        let magic = if dtz { DTZ_MAGIC } else { WDL_MAGIC };
+        if bytes.get(..4)? != magic {
+            return None;
+        }
+
+        let mut table = Self {
+            info,
+            dtz,
+            bytes,
+            items: Default::default(),
+            map: 0,
+        };
+        table.parse()?;
+        Some(table)
+    }
+
+    fn sides(&self) -> usize {
+        if !self.dtz && !self.info.symmetric { 2 } else { 1 }
+    }
+
+    fn files(&self) -> usize {
+        if self.info.has_pawns { 4 } else { 1 }
+    }
+
+    fn parse(&mut self) -> Option<()> {
+        let sides = self.sides();
+        let files = self.files();
+        let both_pawns = self.info.has_pawns && self.info.pawn_count[1] > 0;
+
+        // Skip the magic and the flags byte
+        let mut offset = 5;
+
+        for file in 0..files {
+            let order_byte = *self.bytes.get(offset)?;
+            let pawn_order_byte = if both_pawns { *self.bytes.get(offset + 1)? } else { 0xFF };
+            let order = [
+                [(order_byte & 0xF) as usize, (pawn_order_byte & 0xF) as usize],
+                [(order_byte >> 4) as usize, (pawn_order_byte >> 4) as usize],
+            ];
+            offset += 1 + both_pawns as usize;
+
+            for k in 0..self.info.piece_count {
+                let byte = *self.bytes.get(offset)?;
+                for side in 0..sides {
+                    self.items[side][file].pieces[k] = if side == 1 { byte >> 4 } else { byte & 0xF };
+                }
+                offset += 1;
+            }
+
+            for (side, side_order) in order.iter().enumerate().take(sides) {
+                set_groups(&self.info, &mut self.items[side][file], side_order, file);
+            }
+        }
+
+        offset += offset & 1;
+
+        for file in 0..files {
+            for side in 0..sides {
+                offset = set_sizes(&mut self.items[side][file], &self.bytes, offset)?;
+            }
+        }
+
+        if self.dtz {
+            offset = self.set_dtz_map(offset)?;
+        }
+
+        for file in 0..files {
+            for side in 0..sides {
+                let d = &mut self.items[side][file];
+                d.sparse_index = offset;
+                offset += d.sparse_index_size as usize * 6;
+            }
+        }
+
+        for file in 0..files {
+            for side in 0..sides {
+                let d = &mut self.items[side][file];
+                d.block_length = offset;
+                offset += d.block_length_size as usize * 2;
+            }
+        }
+
+        for file in 0..files {
+            for side in 0..sides {
+                offset = (offset + 0x3F) & !0x3F;
+                let d = &mut self.items[side][file];
+                d.data = offset;
+                offset += (d.num_blocks * d.block_size) as usize;
+            }
+        }
+
+        (offset <= self.bytes.len()).then_some(())
+    }
+
+    // DTZ tables may store their values through a per-file map, with a separate
+    // section for each kind of result
+    fn set_dtz_map(&mut self, mut offset: usize) -> Option<usize> {
+        self.map = offset;
+
+        for file in 0..self.files() {
+            let flags = self.items[0][file].flags;
+            if flags & FLAG_MAPPED == 0 {
+                continue;
+            }
+            if flags & FLAG_WIDE != 0 {
+                offset += offset & 1;
+                for i in 0..4 {
+                    self.items[0][file].map_idx[i] = (offset - self.map) / 2 + 1;
+                    offset += 2 * read_u16(&self.bytes, offset)? as usize + 2;
+                }
+            } else {
+                for i in 0..4 {
+                    self.items[0][file].map_idx[i] = offset - self.map + 1;
+                    offset += *self.bytes.get(offset)? as usize + 1;
+                }
+            }
+        }
+
+        Some(offset + (offset & 1))
+    }
+
+    fn left_child(&self, d: &PairsData, sym: usize) -> usize {
+        let node = d.btree + 3 * sym;
+        (((self.bytes[node + 1] & 0xF) as usize) << 8) | self.bytes[node] as usize
+    }
+
+    fn right_child(&self, d: &PairsData, sym: usize) -> usize {
+        let node = d.btree + 3 * sym;
+        ((self.bytes[node + 2] as usize) << 4) | (self.bytes[node + 1] >> 4) as usize
+    }
+
+    // Value stored at an index of a sub-table
+    fn decompress(&self, d: &PairsData, idx: u64) -> Option<i32> {
+        if d.flags & FLAG_SINGLE_VALUE != 0 {
+            return Some(d.min_sym_len as i32);
+        }
+
+        // The sparse index points into the block list every `span` values,
+        // at the middle of each span. Walk from there to the right block.
+        let k = (idx / d.span) as usize;
+        let mut block = read_u32(&self.bytes, d.sparse_index + 6 * k)? as usize;
+        let mut offset = read_u16(&self.bytes, d.sparse_index + 6 * k + 4)? as i64;
+        offset += (idx % d.span) as i64 - (d.span / 2) as i64;
+
+        let block_length = |block: usize| read_u16(&self.bytes, d.block_length + 2 * block).map(|len| len as i64);
+        while offset < 0 {
+            block = block.checked_sub(1)?;
+            offset += block_length(block)? + 1;
+        }
+        while offset > block_length(block)? {
+            offset -= block_length(block)? + 1;
+            block += 1;
+        }
+
+        // Decode symbols until the one covering our offset. Each symbol expands
+        // to symlen + 1 values.
+        let mut ptr = d.data + block * d.block_size as usize;
+        let mut buf = ((read_be_u32(&self.bytes, ptr) as u64) << 32) | read_be_u32(&self.bytes, ptr + 4) as u64;
+        ptr += 8;
+        let mut buf_size = 64;
+        let min_sym_len = d.min_sym_len as u32;
+
+        let mut sym;
+        loop {
+            let mut len = 0;
+            while len + 1 < d.base64.len() && buf < d.base64[len] {
+                len += 1;
+            }
+
+            sym = ((buf - d.base64[len]) >> (64 - len as u32 - min_sym_len)) as usize;
+            sym += read_u16(&self.bytes, d.lowest_sym + 2 * len)? as usize;
+
+            let expanded = *d.symlen.get(sym)? as i64 + 1;
+            if offset < expanded {
+                break;
+            }
+            offset -= expanded;
+
+            let bits = len as u32 + min_sym_len;
+            buf = buf.checked_shl(bits).unwrap_or(0);
+            buf_size -= bits as i32;
+            if buf_size <= 32 {
+                buf_size += 32;
+                buf |= (read_be_u32(&self.bytes, ptr) as u64) << (64 - buf_size);
+                ptr += 4;
+            }
+        }
+
+        // Expand the pair tree down to the single value at our offset
+        while d.symlen[sym] != 0 {
+            let left = self.left_child(d, sym);
+            let left_len = d.symlen[left] as i64 + 1;
+            if offset < left_len {
+                sym = left;
+            } else {
+                offset -= left_len;
+                sym = self.right_child(d, sym);
+            }
+        }
+
+        Some(self.left_child(d, sym) as i32)
+    }
+
+    fn map_score(&self, file: usize, value: i32, wdl: Wdl) -> i32 {
+        if !self.dtz {
+            return value - 2;
+        }
+
+        let d = &self.items[0][file];
+        let mut value = value;
+        if d.flags & FLAG_MAPPED != 0 {
+            let section = match wdl {
+                Wdl::Loss => 1,
+                Wdl::BlessedLoss => 3,
+                Wdl::CursedWin => 2,
+                Wdl::Draw | Wdl::Win => 0,
+            };
+            let index = d.map_idx[section] + value as usize;
+            value = if d.flags & FLAG_WIDE != 0 {
+                read_u16(&self.bytes, self.map + 2 * index).unwrap_or(0) as i32
+            } else {
+                self.bytes.get(self.map + index).copied().unwrap_or(0) as i32
+            };
+        }
+
+        // Values may be stored in moves rather than plies
+        if (wdl == Wdl::Win && d.flags & FLAG_WIN_PLIES == 0)
+            || (wdl == Wdl::Loss && d.flags & FLAG_LOSS_PLIES == 0)
+            || wdl == Wdl::CursedWin
+            || wdl == Wdl::BlessedLoss {
+            value *= 2;
+        }
+
+        value + 1
+    }
+
+    // Looks up a position. `flipped` is set when the table's first side is
+    // Black on the board. Returns None when a DTZ table only stores the other
+    // side to move.
+    fn probe(&self, board: &BoardState, flipped: bool, wdl: Wdl) -> Option<i32> {
+        let ix = indexes();
+
+        // Symmetric tables only store White to move, and every table stores the
+        // stronger side as White: swap colours and flip ranks when needed
+        let flip = flipped || (self.info.symmetric && !board.white_to_move);
+        let flip_color = if flip { 8 } else { 0 };
+        let flip_squares = if flip { 56 } else { 0 };
+        let stm = flip as usize ^ !board.white_to_move as usize;
+
+        let mut squares = [0usize; TB_PIECES];
+        let mut pieces = [0u8; TB_PIECES];
+        let mut size = 0;
+        let mut lead_pawns = 0;
+        let mut lead_pawn_bitboard = 0;
+        let mut file = 0;
+
+        // Pawn tables are split by the file of the leading pawn
+        if self.info.has_pawns {
+            let lead_piece = self.items[0][0].pieces[0] ^ flip_color;
+            lead_pawn_bitboard = board.bitboards[if lead_piece == 1 { WP } else { BP }];
+            for square in 0..64u8 {
+                if get_bit(lead_pawn_bitboard, square) {
+                    squares[size] = board_to_tb_square(square) ^ flip_squares;
+                    pieces[size] = lead_piece ^ flip_color;
+                    size += 1;
+                }
+            }
+            lead_pawns = size;
+
+            let lead = (0..lead_pawns).rev()
+                .max_by_key(|&i| ix.map_pawns[squares[i]])
+                .unwrap();
+            squares.swap(0, lead);
+            file = file_of(squares[0]).min(7 - file_of(squares[0])) as usize;
+        }
+
+        if self.dtz {
+            let flags = self.items[0][file].flags;
+            let both_sides_stored = self.info.symmetric && !self.info.has_pawns;
+            if (flags & FLAG_STM) as usize != stm && !both_sides_stored {
+                return None;
+            }
+        }
+
+        let occupied = get_all_occupied(board.bitboards) & !lead_pawn_bitboard;
+        for square in 0..64u8 {
+            if get_bit(occupied, square) {
+                let piece = get_piece_at_square(&board.bitboards, square).unwrap();
+                squares[size] = board_to_tb_square(square) ^ flip_squares;
+                pieces[size] = piece_code(piece) ^ flip_color;
+                size += 1;
+            }
+        }
+
+        let d = &self.items[if self.dtz { 0 } else { stm % self.sides() }][file];
+        let idx = encode(&self.info, d, &mut squares[..size], &mut pieces[..size], lead_pawns);
+        let value = self.decompress(d, idx)?;
+        Some(self.map_score(file, value, wdl))
+    }
+}
+
+// Board squares count from a8, the tables from a1
+#[inline]
+fn board_to_tb_square(square: u8) -> usize {
+    (square ^ 56) as usize
+}
+
+// Piece codes used by the generator: 1..6 for white pawn to king, +8 for black
+#[inline]
+fn piece_code(piece: usize) -> u8 {
+    if piece < 6 { piece as u8 + 1 } else { piece as u8 + 3 }
+}
+
+// Pieces of one type and colour form a group that is indexed together. The
+// first group holds the leading pawns, or without pawns the first three unique
+// pieces (or the two kings). `order` gives the position of the leading group and
+// of the other side's pawns in the final index.
+fn set_groups(info: &TableInfo, d: &mut PairsData, order: &[usize; 2], file: usize) {
+    let ix = indexes();
+
+    let mut n = 0;
+    let mut first_len: i32 = if info.has_pawns { 0 } else if info.has_unique_pieces { 3 } else { 2 };
+    d.group_len[n] = 1;
+
+    for i in 1..info.piece_count {
+        first_len -= 1;
+        if first_len > 0 || d.pieces[i] == d.pieces[i - 1] {
+            d.group_len[n] += 1;
+        } else {
+            n += 1;
+            d.group_len[n] = 1;
+        }
+    }
+    n += 1;
+    d.group_len[n] = 0;
+
+    let both_pawns = info.has_pawns && info.pawn_count[1] > 0;
+    let mut next = if both_pawns { 2 } else { 1 };
+    let mut free_squares = 64 - d.group_len[0] - if both_pawns { d.group_len[1] } else { 0 };
+    let mut idx: u64 = 1;
+
+    let mut k = 0;
+    while next < n || k == order[0] || k == order[1] {
+        if k == order[0] {
+            d.group_idx[0] = idx;
+            idx *= if info.has_pawns {
+                ix.lead_pawns_size[d.group_len[0]][file]
+            } else if info.has_unique_pieces {
+                31332
+            } else {
+                462
+            };
+        } else if k == order[1] {
+            d.group_idx[1] = idx;
+            idx *= ix.binomial[d.group_len[1]][48 - d.group_len[0]];
+        } else {
+            d.group_idx[next] = idx;
+            idx *= ix.binomial[d.group_len[next]][free_squares];
+            free_squares -= d.group_len[next];
+            next += 1;
+        }
+        k += 1;
+    }
+
+    d.group_idx[n] = idx;
+}
+
+// Reads the Huffman code description of a sub-table and returns the offset
+// just past it
+fn set_sizes(d: &mut PairsData, bytes: &[u8], mut offset: usize) -> Option<usize> {
+    d.flags = *bytes.get(offset)?;
+    offset += 1;
+
+    if d.flags & FLAG_SINGLE_VALUE != 0 {
+        // The only value of the table is stored in place of the symbol length
+        d.min_sym_len = *bytes.get(offset)?;
+        return Some(offset + 1);
+    }
+
+    let groups = d.group_len.iter().position(|&len| len == 0)?;
+    let tb_size = d.group_idx[groups];
+
+    d.block_size = 1u64 << *bytes.get(offset)?;
+    d.span = 1u64 << *bytes.get(offset + 1)?;
+    d.sparse_index_size = tb_size.div_ceil(d.span);
+    let padding = *bytes.get(offset + 2)? as u64;
+    d.num_blocks = read_u32(bytes, offset + 3)? as u64;
+    d.block_length_size = d.num_blocks + padding;
+    let max_sym_len = *bytes.get(offset + 7)?;
+    d.min_sym_len = *bytes.get(offset + 8)?;
+    offset += 9;
+    d.lowest_sym = offset;
+
+    if max_sym_len < d.min_sym_len || d.min_sym_len == 0 || max_sym_len > 32 {
+        return None;
+    }
+
+    // Canonical Huffman: longer codes have lower numeric values. base64[len]
+    // is the smallest code of that length, left-aligned to 64 bits.
+    let lengths = (max_sym_len - d.min_sym_len) as usize + 1;
+    d.base64 = vec![0; lengths];
+    for i in (0..lengths - 1).rev() {
+        let lowest = read_u16(bytes, d.lowest_sym + 2 * i)? as u64;
+        let next_lowest = read_u16(bytes, d.lowest_sym + 2 * (i + 1))? as u64;
+        d.base64[i] = (d.base64[i + 1] + lowest).wrapping_sub(next_lowest) / 2;
+    }
+    for (i, base) in d.base64.iter_mut().enumerate() {
+        *base = base.checked_shl(64 - i as u32 - d.min_sym_len as u32).unwrap_or(0);
+    }
+    offset += lengths * 2;
+
+    let symbols = read_u16(bytes, offset)? as usize;
+    offset += 2;
+    d.btree = offset;
+    if bytes.len() < d.btree + 3 * symbols {
+        return None;
+    }
+
+    // Every symbol is a pair of smaller ones, down to the leaves that hold values
+    d.symlen = vec![0; symbols];
+    let mut visited = vec![false; symbols];
+    for sym in 0..symbols {
+        if !visited[sym] {
+            d.symlen[sym] = set_symlen(d, bytes, sym, &mut visited)?;
+        }
+    }
+
+    Some(offset + symbols * 3 + (symbols & 1))
+}
+
+fn set_symlen(d: &mut PairsData, bytes: &[u8], sym: usize, visited: &mut [bool]) -> Option<u8> {
+    visited[sym] = true;
+    let node = d.btree + 3 * sym;
+    let right = ((bytes[node + 2] as usize) << 4) | (bytes[node + 1] >> 4) as usize;
+    if right == 0xFFF {
+        return Some(0);
+    }
+    let left = (((bytes[node + 1] & 0xF) as usize) << 8) | bytes[node] as usize;
+
+    for child in [left, right] {
+        if !*visited.get(child)? {
+            d.symlen[child] = set_symlen(d, bytes, child, visited)?;
+        }
+    }
+
+    Some(d.symlen[left].wrapping_add(d.symlen[right]).wrapping_add(1))
+}
+
+// Maps a position to its index in a sub-table. `squares` and `pieces` start
+// with the leading pawns (leading one first) followed by the other pieces in
+// any order.
+fn encode(info: &TableInfo, d: &PairsData, squares: &mut [usize], pieces: &mut [u8], lead_pawns: usize) -> u64 {
+    let ix = indexes();
+    let size = squares.len();
+
+    // Put the pieces in the order the table was built with
+    for i in lead_pawns..size.saturating_sub(1) {
+        for j in i + 1..size {
+            if d.pieces[i] == pieces[j] {
+                pieces.swap(i, j);
+                squares.swap(i, j);
+                break;
+            }
+        }
+    }
+
+    // Mirror so the leading piece is on files a-d
+    if file_of(squares[0]) > 3 {
+        for square in squares.iter_mut() {
+            *square ^= 7;
+        }
+    }
+
+    let mut idx;
+    if info.has_pawns {
+        idx = ix.lead_pawn_idx[lead_pawns][squares[0]];
+        squares[1..lead_pawns].sort_by_key(|&square| ix.map_pawns[square]);
+        for (i, &square) in squares.iter().enumerate().take(lead_pawns).skip(1) {
+            idx += ix.binomial[i][ix.map_pawns[square]];
+        }
+    } else {
+        // Without pawns also mirror to ranks 1-4, then below the a1-h8 diagonal
+        if rank_of(squares[0]) > 3 {
+            for square in squares.iter_mut() {
+                *square ^= 56;
+            }
+        }
+        for i in 0..d.group_len[0] {
+            if off_a1h8(squares[i]) == 0 {
+                continue;
+            }
+            if off_a1h8(squares[i]) > 0 {
+                for square in squares[i..].iter_mut() {
+                    *square = ((*square >> 3) | (*square << 3)) & 63;
+                }
+            }
+            break;
+        }
+
+        if info.has_unique_pieces {
+            let adjust1 = (squares[1] > squares[0]) as usize;
+            let adjust2 = (squares[2] > squares[0]) as usize + (squares[2] > squares[1]) as usize;
+
+            idx = if off_a1h8(squares[0]) != 0 {
+                ((ix.map_a1d1d4[squares[0]] * 63 + squares[1] - adjust1) * 62 + squares[2] - adjust2) as u64
+            } else if off_a1h8(squares[1]) != 0 {
+                ((6 * 63 + rank_of(squares[0]) as usize * 28 + ix.map_b1h1h7[squares[1]]) * 62
+                    + squares[2] - adjust2) as u64
+            } else if off_a1h8(squares[2]) != 0 {
+                (6 * 63 * 62 + 4 * 28 * 62
+                    + rank_of(squares[0]) as usize * 7 * 28
+                    + (rank_of(squares[1]) as usize - adjust1) * 28
+                    + ix.map_b1h1h7[squares[2]]) as u64
+            } else {
+                (6 * 63 * 62 + 4 * 28 * 62 + 4 * 7 * 28
+                    + rank_of(squares[0]) as usize * 7 * 6
+                    + (rank_of(squares[1]) as usize - adjust1) * 6
+                    + (rank_of(squares[2]) as usize - adjust2)) as u64
+            };
+        } else {
+            idx = ix.map_kk[ix.map_a1d1d4[squares[0]]][squares[1]] as u64;
+        }
+    }
+
+    // The remaining groups, each as a sorted combination of the squares not
+    // taken by earlier groups
+    idx *= d.group_idx[0];
+    let mut group_start = d.group_len[0];
+    let mut remaining_pawns = info.has_pawns && info.pawn_count[1] > 0;
+    let mut next = 1;
+
+    while d.group_len[next] != 0 {
+        let group_end = group_start + d.group_len[next];
+        squares[group_start..group_end].sort_unstable();
+
+        let mut n = 0;
+        for i in 0..d.group_len[next] {
+            let square = squares[group_start + i];
+            let adjust = squares[..group_start].iter().filter(|&&s| square > s).count();
+            n += ix.binomial[i + 1][square - adjust - if remaining_pawns { 8 } else { 0 }];
+        }
+
+        remaining_pawns = false;
+        idx += n * d.group_idx[next];
+        group_start = group_end;
+        next += 1;
+    }
+
+    idx
+}
+
+//END OF TABLE LAYOUT--------------------------------------------------------------------------------------
+
+
+//TABLE REGISTRY-------------------------------------------------------------------------------------------
+
+// Tables are keyed by name and whether they hold DTZ rather than WDL
+type TableKey = (String, bool);
+
+// A table file found by the scan, read on first use. The map of files never
+// changes once scanned, so probing from many threads takes no lock
+struct TableFile {
+    path: PathBuf,
+    table: OnceLock<Option<Table>>,
+}
+
+struct Tablebases {
+    files: HashMap<TableKey, TableFile>,
+    max_pieces: usize,
+}
+
+impl Tablebases {
+    // Scans the given directories (separated like `PATH`) for table files
+    fn open(path: &str) -> Self {
+        let mut files = HashMap::new();
+        let mut max_pieces = 0;
+
+        if !path.is_empty() && path != "<empty>" {
+            for dir in std::env::split_paths(path) {
+                let Ok(entries) = std::fs::read_dir(&dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    let Some((name, dtz)) = table_name(&path) else {
+                        continue;
+                    };
+                    if let Some(info) = TableInfo::from_name(&name) {
+                        if !dtz {
+                            max_pieces = max_pieces.max(info.piece_count);
+                        }
+                        files.insert((name, dtz), TableFile { path, table: OnceLock::new() });
+                    }
+                }
+            }
+        }
+
+        Self { files, max_pieces }
+    }
+
+    fn wdl_count(&self) -> usize {
+        self.files.keys().filter(|(_, dtz)| !dtz).count()
+    }
+
+    fn table(&self, name: &str, dtz: bool) -> Option<&Table> {
+        let file = self.files.get(&(name.to_string(), dtz))?;
+        file.table
+            .get_or_init(|| {
+                let bytes = std::fs::read(&file.path).ok()?;
+                Table::new(name, bytes, dtz)
+            })
+            .as_ref()
+    }
+
+    // Table name for the board and whether its first side is Black
+    fn lookup(&self, board: &BoardState, dtz: bool) -> Option<(String, bool)> {
+        let white = side_code(board, true);
+        let black = side_code(board, false);
+        let name = format!("{}v{}", white, black);
+        if self.files.contains_key(&(name.clone(), dtz)) {
+            return Some((name, false));
+        }
+        let name = format!("{}v{}", black, white);
+        self.files.contains_key(&(name.clone(), dtz)).then_some((name, true))
+    }
+}
+
+// Pieces of one side in table naming order
+pub(crate) fn side_code(board: &BoardState, white: bool) -> String {
+    let base = if white { 0 } else { 6 };
+    let mut code = String::new();
+    for (piece, letter) in [(5, 'K'), (4, 'Q'), (3, 'R'), (2, 'B'), (1, 'N'), (0, 'P')] {
+        for _ in 0..count_bits(board.bitboards[base + piece]) {
+            code.push(letter);
+        }
+    }
+    code
+}
+
+/// Scans the given directories (separated like `PATH`) for tablebase files,
+/// replacing any previously configured set. Returns the number of WDL tables
+/// found; an empty path or `<empty>` disables probing.
+pub fn init(path: &str) -> usize {
+    let tablebases = Tablebases::open(path);
+    let count = tablebases.wdl_count();
+    MAX_PIECES.store(tablebases.max_pieces, Ordering::Relaxed);
+    *TABLEBASES.write().unwrap() = Some(tablebases);
+    count
+}
+
+fn table_name(path: &Path) -> Option<(String, bool)> {
+    let dtz = match path.extension()?.to_str()? {
+        "rtbw" => false,
+        "rtbz" => true,
+        _ => return None,
+    };
+    Some((path.file_stem()?.to_str()?.to_string(), dtz))
+}
+
+/// Largest number of pieces covered by the loaded tables, zero without tables.
+pub fn max_pieces() -> usize {
+    MAX_PIECES.load(Ordering::Relaxed)
+}
+
+//END OF TABLE REGISTRY------------------------------------------------------------------------------------
+
+
+//PROBING--------------------------------------------------------------------------------------------------
+
+fn probeable(board: &BoardState, max_pieces: usize) -> bool {
+    let pieces = count_bits(get_all_occupied(board.bitboards)) as usize;
+    pieces <= max_pieces
+        && !board.white_kingside_castle
+        && !board.white_queenside_castle
+        && !board.black_kingside_castle
+        && !board.black_queenside_castle
+}
+
+fn probe_wdl_table(tablebases: &Tablebases, board: &BoardState) -> Option<Wdl> {
+    if count_bits(get_all_occupied(board.bitboards)) == 2 {
+        return Some(Wdl::Draw);
+    }
+    let (name, flipped) = tablebases.lookup(board, false)?;
+    let table = tablebases.table(&name, false)?;
+    table.probe(board, flipped, Wdl::Draw).map(Wdl::from_value)
+}
+
+// Result of the capture search done around every table lookup
+struct Searched {
+    wdl: Wdl,
+    // Set when the best move resets the fifty-move counter, in which case the
+    // DTZ table may hold a "don't care" value for the position
+    zeroing_best: bool,
+}
+
+fn is_zeroing(board: &BoardState, from: u8, to: u8, include_pawn_moves: bool) -> bool {
+    let pawns = if board.white_to_move { board.bitboards[WP] } else { board.bitboards[BP] };
+    let pawn_move = get_bit(pawns, from);
+    // En passant lands on an empty square but is still a capture
+    let capture = get_piece_at_square(&board.bitboards, to).is_some()
+        || (pawn_move && board.en_passant_target == Some(to));
+    capture || (include_pawn_moves && pawn_move)
+}
+
+// The tables don't store positions where a capture (or, for DTZ, a winning
+// pawn move) is best, so try those moves first and combine the results
+fn search(tablebases: &Tablebases, board: &BoardState, check_zeroing_moves: bool) -> Option<Searched> {
+    let moves = generate_legal_moves(board);
+    let mut best = Wdl::Loss;
+    let mut zeroing_count = 0;
+
+    for &(from, to) in &moves {
+        if !is_zeroing(board, from, to, check_zeroing_moves) {
+            continue;
+        }
+        zeroing_count += 1;
+
+        let mut next = *board;
+        make_move(&mut next, from, to)?;
+        let value = -search(tablebases, &next, false)?.wdl;
+
+        if value > best {
+            best = value;
+            if value >= Wdl::Win {
+                return Some(Searched { wdl: value, zeroing_best: true });
+            }
+        }
+    }
+
+    // With every legal move already searched the table value isn't needed,
+    // and would be wrong for mates and stalemates
+    let no_more_moves = zeroing_count > 0 && zeroing_count == moves.len();
+    let value = if no_more_moves {
+        best
+    } else if moves.is_empty() {
+        if board.is_current_king_in_check() { Wdl::Loss } else { Wdl::Draw }
+    } else {
+        probe_wdl_table(tablebases, board)?
+    };
+
+    if best >= value {
+        return Some(Searched { wdl: best, zeroing_best: best > Wdl::Draw || no_more_moves });
+    }
+    Some(Searched { wdl: value, zeroing_best: false })
+}
+
+// DTZ of a position whose best move resets the fifty-move counter
+fn dtz_before_zeroing(wdl: Wdl) -> i32 {
+    match wdl {
+        Wdl::Win => 1,
+        Wdl::CursedWin => 101,
+        Wdl::BlessedLoss => -101,
+        Wdl::Loss => -1,
+        Wdl::Draw => 0,
+    }
+}
+
+fn probe_dtz(tablebases: &Tablebases, board: &BoardState) -> Option<i32> {
+    let searched = search(tablebases, board, true)?;
+    if searched.wdl == Wdl::Draw {
+        return Some(0);
+    }
+    if searched.zeroing_best {
+        return Some(dtz_before_zeroing(searched.wdl));
+    }
+
+    let (name, flipped) = tablebases.lookup(board, true)?;
+    let table = tablebases.table(&name, true)?;
+
+    if let Some(dtz) = table.probe(board, flipped, searched.wdl) {
+        let cursed = matches!(searched.wdl, Wdl::CursedWin | Wdl::BlessedLoss);
+        let dtz = dtz + if cursed { 100 } else { 0 };
+        return Some(if searched.wdl > Wdl::Draw { dtz } else { -dtz });
+    }
+
+    // The table only stores the other side to move: search one ply and take
+    // the best DTZ among the moves that keep the result
+    let sign = if searched.wdl > Wdl::Draw { 1 } else { -1 };
+    let mut min_dtz = i32::MAX;
+    for (from, to) in generate_legal_moves(board) {
+        let zeroing = is_zeroing(board, from, to, true);
+        let mut next = *board;
+        make_move(&mut next, from, to)?;
+
+        let mut dtz = if zeroing {
+            -dtz_before_zeroing(search(tablebases, &next, false)?.wdl)
+        } else {
+            -probe_dtz(tablebases, &next)?
+        };
+
+        // A mate is always the shortest way to zero
+        if dtz == 1 && next.is_current_king_in_check() && generate_legal_moves(&next).is_empty() {
+            min_dtz = 1;
+        }
+        if !zeroing {
+            dtz += dtz.signum();
+        }
+        if dtz < min_dtz && dtz.signum() == sign {
+            min_dtz = dtz;
+        }
+    }
+
+    Some(if min_dtz == i32::MAX { -1 } else { min_dtz })
+}
+
+impl Tablebases {
+    fn probe_wdl(&self, board: &BoardState) -> Option<Wdl> {
+        if !probeable(board, self.max_pieces) {
+            return None;
+        }
+        let wdl = search(self, board, false)?.wdl;
+        TB_HITS.fetch_add(1, Ordering::Relaxed);
+        Some(wdl)
+    }
+
+    fn probe_root(&self, board: &BoardState) -> Option<Vec<((u8, u8), i32)>> {
+        if !probeable(board, self.max_pieces) {
+            return None;
+        }
+
+        let mut ranked = Vec::new();
+        for (from, to) in generate_legal_moves(board) {
+            let zeroing = is_zeroing(board, from, to, true);
+            let mut next = *board;
+            make_move(&mut next, from, to)?;
+
+            let mut dtz = if zeroing {
+                dtz_before_zeroing(-search(self, &next, false)?.wdl)
+            } else {
+                let dtz = -probe_dtz(self, &next)?;
+                dtz + dtz.signum()
+            };
+            if dtz == 2 && next.is_current_king_in_check() && generate_legal_moves(&next).is_empty() {
+                dtz = 1;
+            }
+            ranked.push(((from, to), dtz));
+        }
+
+        TB_HITS.fetch_add(ranked.len() as u64, Ordering::Relaxed);
+        ranked.sort_by_key(|&(_, dtz)| match dtz {
+            1.. => (0, dtz),
+            0 => (1, 0),
+            _ => (2, dtz),
+        });
+        Some(ranked)
+    }
+}
+
+/// Win/draw/loss for the side to move, or None when the position isn't
+/// covered by the loaded tables.
+pub fn probe_wdl(board: &BoardState) -> Option<Wdl> {
+    if !probeable(board, max_pieces()) {
+        return None;
+    }
+    TABLEBASES.read().unwrap().as_ref()?.probe_wdl(board)
+}
+
+/// Ranks the root moves by tablebase result, best first, with the DTZ of each
+/// move counted from the root. Wins are ordered by shortest DTZ and losses by
+/// longest, so playing the first move always makes progress.
+pub fn probe_root(board: &BoardState) -> Option<Vec<((u8, u8), i32)>> {
+    if !probeable(board, max_pieces()) {
+        return None;
+    }
+    TABLEBASES.read().unwrap().as_ref()?.probe_root(board)
+}
+
+//END OF PROBING-------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_fen;
+    use crate::retrograde::{Dtm, Generator};
+
+    #[test]
+    fn index_tables_cover_the_expected_ranges() {
+        let ix = indexes();
+
+        // Two kings have 462 placements up to symmetry, each with its own code
+        let mut codes: Vec<usize> = Vec::new();
+        for idx in 0..10 {
+            for first in 0..28 {
+                if ix.map_a1d1d4[first] != idx || (idx == 0 && first != 1) {
+                    continue;
+                }
+                for second in 0..64 {
+                    let legal = square_distance(first as u8, second as u8) > 1
+                        && !(off_a1h8(first) == 0 && off_a1h8(second) > 0);
+                    if legal {
+                        codes.push(ix.map_kk[idx][second]);
+                    }
+                }
+            }
+        }
+        codes.sort_unstable();
+        assert_eq!(codes, (0..462).collect::<Vec<_>>());
+
+        assert_eq!(ix.binomial[2][62], 62 * 61 / 2);
+        assert_eq!(ix.lead_pawns_size[1], [6, 6, 6, 6]);
+        assert_eq!(ix.map_pawns[8], 47);
+    }
+
+    #[test]
+    fn table_names_follow_the_material() {
+        let board = parse_fen("8/8/4k3/8/2K5/8/3R4/8 w - -").unwrap();
+        assert_eq!(side_code(&board, true), "KR");
+        assert_eq!(side_code(&board, false), "K");
+
+        let info = TableInfo::from_name("KRPvKP").unwrap();
+        assert_eq!(info.piece_count, 5);
+        assert!(info.has_pawns && info.has_unique_pieces && !info.symmetric);
+        assert_eq!(info.pawn_count, [1, 1]);
+        assert!(TableInfo::from_name("KQvQ").is_none());
+    }
+
+    // Every legal KRvK placement must map into the table and agree with its
+    // eight board symmetries
+    #[test]
+    fn encoding_is_symmetric_and_in_range() {
+        let info = TableInfo::from_name("KRvK").unwrap();
+        let mut d = PairsData::default();
+        d.pieces[..3].copy_from_slice(&[6, 4, 14]);
+        set_groups(&info, &mut d, &[0, 0xF], 0);
+        assert_eq!(d.group_len[..2], [3, 0]);
+
+        let transforms: [fn(usize) -> usize; 8] = [
+            |s| s,
+            |s| s ^ 7,
+            |s| s ^ 56,
+            |s| s ^ 63,
+            |s| ((s >> 3) | (s << 3)) & 63,
+            |s| (((s >> 3) | (s << 3)) & 63) ^ 7,
+            |s| (((s >> 3) | (s << 3)) & 63) ^ 56,
+            |s| (((s >> 3) | (s << 3)) & 63) ^ 63,
+        ];
+
+        for king in 0..64 {
+            for rook in 0..64 {
+                for enemy in 0..64 {
+                    if rook == king || rook == enemy || square_distance(king as u8, enemy as u8) <= 1 {
+                        continue;
+                    }
+                    let indices: Vec<u64> = transforms.iter()
+                        .map(|t| {
+                            let mut squares = [t(enemy), t(rook), t(king)];
+                            let mut pieces = [14, 4, 6];
+                            encode(&info, &d, &mut squares, &mut pieces, 0)
+                        })
+                        .collect();
+                    assert!(indices[0] < d.group_idx[1]);
+                    assert!(indices.iter().all(|&idx| idx == indices[0]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn en_passant_resets_the_fifty_move_counter() {
+        crate::tests::init();
+        let board = parse_fen("4k3/8/8/3pP3/8/8/8/4K3 w - d6").unwrap();
+        // e5xd6 en passant, against a plain push of the same pawn
+        assert!(is_zeroing(&board, 28, 19, false));
+        assert!(!is_zeroing(&board, 28, 20, false));
+        assert!(is_zeroing(&board, 28, 20, true));
+        assert!(!is_zeroing(&board, 60, 59, true));
+    }
+
+    // A three-piece table with its values taken from the retrograde generator.
+    // Per leading pawn file (a single one without pawns) it holds WDL with
+    // White and with Black to move, then DTZ in signed plies the same way.
+    // Indices no position maps to keep a draw.
+    struct Fixture {
+        info: TableInfo,
+        groups: Vec<PairsData>,
+        wdl: Vec<[Vec<u8>; 2]>,
+        dtz: Vec<[Vec<i32>; 2]>,
+    }
+
+    // Placements of White's other piece, the white king and the black king,
+    // one for each index: the leading pawn on files a-d, or without pawns the
+    // white king in the a1-d1-d4 triangle
+    fn placements(pawns: bool) -> impl Iterator<Item = [u8; 3]> {
+        (0..64u32 * 64 * 64)
+            .map(|i| [(i / 4096) as u8, (i / 64 % 64) as u8, (i % 64) as u8])
+            .filter(move |&[other, king, enemy]| {
+                let folded = if pawns {
+                    (8..56).contains(&other) && other % 8 < 4
+                } else {
+                    let tb_king = board_to_tb_square(king);
+                    file_of(tb_king) <= 3 && off_a1h8(tb_king) <= 0
+                };
+                folded && other != king && other != enemy && square_distance(king, enemy) > 1
+            })
+    }
+
+    // None when the side that just moved is left in check
+    fn fixture_board(piece: usize, [other, king, enemy]: [u8; 3], white_to_move: bool) -> Option<BoardState> {
+        let mut bitboards = [0u64; 12];
+        bitboards[crate::WK] = 1 << king;
+        bitboards[piece] = 1 << other;
+        bitboards[crate::BK] = 1 << enemy;
+        let mut board = BoardState {
+            bitboards,
+            white_to_move,
+            white_kingside_castle: false,
+            white_queenside_castle: false,
+            black_kingside_castle: false,
+            black_queenside_castle: false,
+            white_king_in_check: false,
+            black_king_in_check: false,
+            en_passant_target: None,
+        };
+        board.update_check_status();
+        let waiting_in_check = if white_to_move { board.black_king_in_check } else { board.white_king_in_check };
+        (!waiting_in_check).then_some(board)
+    }
+
+    // Leading pawn file and index of a position with White as the first side,
+    // collecting the squares in the same order as Table::probe
+    fn fixture_index(info: &TableInfo, groups: &[PairsData], board: &BoardState) -> (usize, usize) {
+        let mut squares = Vec::new();
+        let mut pieces = Vec::new();
+        for square in 0..64u8 {
+            if let Some(piece) = get_piece_at_square(&board.bitboards, square) {
+                let at = if piece == WP { 0 } else { squares.len() };
+                squares.insert(at, board_to_tb_square(square));
+                pieces.insert(at, piece_code(piece));
+            }
+        }
+        let file = if info.has_pawns { file_of(squares[0]).min(7 - file_of(squares[0])) as usize } else { 0 };
+        let idx = encode(info, &groups[file], &mut squares, &mut pieces, info.has_pawns as usize);
+        (file, idx as usize)
+    }
+
+    fn signed_plies(dtm: Dtm) -> i32 {
+        match dtm {
+            Dtm::Win(plies) => plies as i32,
+            // Being mated counts as a DTZ of -1, as the probing code expects
+            Dtm::Loss(plies) => -(plies.max(1) as i32),
+            Dtm::Draw => 0,
+        }
+    }
+
+    // DTZ of every position of a one-pawn table, worked out level by level
+    // from the generator's results. The winner needs one ply with a winning
+    // zeroing move or a mate, else one more than its quickest losing reply;
+    // the loser needs one more than its slowest move. Indexed by `pawn_state`.
+    fn pawn_dtz(name: &str, generator: &mut Generator) -> Vec<i32> {
+        let table = generator.generate(name).unwrap();
+        let mut dtm = |board: &BoardState| {
+            if count_bits(get_all_occupied(board.bitboards)) == 2 {
+                return Dtm::Draw;
+            }
+            let name = format!("{}v{}", side_code(board, true), side_code(board, false));
+            generator.generate(&name).unwrap().probe(board)
+        };
+
+        // Winning side, whether one ply does, and the replies staying in the table
+        let mut positions: Vec<(usize, bool, bool, Vec<usize>)> = Vec::new();
+        for placement in placements(true) {
+            for white_to_move in [true, false] {
+                let Some(board) = fixture_board(WP, placement, white_to_move) else {
+                    continue;
+                };
+                let winning = match table.probe(&board) {
+                    Dtm::Win(_) => true,
+                    Dtm::Loss(_) => false,
+                    Dtm::Draw => continue,
+                };
+                let mut at_once = false;
+                let mut replies = Vec::new();
+                for (from, to) in generate_legal_moves(&board) {
+                    let mut next = board;
+                    make_move(&mut next, from, to);
+                    let mated = next.is_current_king_in_check() && generate_legal_moves(&next).is_empty();
+                    if is_zeroing(&board, from, to, true) || mated {
+                        at_once |= winning && matches!(dtm(&next), Dtm::Loss(_));
+                    } else {
+                        replies.push(pawn_state(&next));
+                    }
+                }
+                positions.push((pawn_state(&board), winning, at_once, replies));
+            }
+        }
+
+        let mut dtz = vec![0; 2 * 64 * 64 * 64];
+        for level in 1.. {
+            let mut found = false;
+            for (state, winning, at_once, replies) in &positions {
+                if dtz[*state] != 0 {
+                    continue;
+                }
+                let value = if *winning {
+                    let reached = replies.iter().any(|&reply| dtz[reply] == 1 - level);
+                    (if level == 1 { *at_once } else { reached }).then_some(level)
+                } else if replies.iter().all(|&reply| dtz[reply] > 0) {
+                    let slowest = replies.iter().map(|&reply| dtz[reply]).max().unwrap_or(0);
+                    (slowest + 1 == level).then_some(-level)
+                } else {
+                    None
+                };
+                if let Some(value) = value {
+                    dtz[*state] = value;
+                    found = true;
+                }
+            }
+            if !found {
+                break;
+            }
+        }
+        assert!(positions.iter().all(|&(state, ..)| dtz[state] != 0));
+        dtz
+    }
+
+    // Side to move and squares of a one-pawn position, mirrored so the pawn
+    // is on files a-d
+    fn pawn_state(board: &BoardState) -> usize {
+        let square = |bitboard: u64| bitboard.trailing_zeros() as usize;
+        let pawn = square(board.bitboards[WP]);
+        let mirror = if pawn % 8 > 3 { 7 } else { 0 };
+        let stm = board.white_to_move as usize;
+        (((stm * 64 + (pawn ^ mirror)) * 64 + (square(board.bitboards[crate::WK]) ^ mirror)) * 64)
+            + (square(board.bitboards[crate::BK]) ^ mirror)
+    }
+
+    fn fixture(name: &str, generator: &mut Generator) -> Fixture {
+        let info = TableInfo::from_name(name).unwrap();
+        let piece = "PNBRQ".find(name.as_bytes()[1] as char).unwrap();
+        let pieces = if info.has_pawns { [1, 6, 14] } else { [6, piece as u8 + 1, 14] };
+        let groups: Vec<PairsData> = (0..if info.has_pawns { 4 } else { 1 })
+            .map(|file| {
+                let mut d = PairsData::default();
+                d.pieces[..3].copy_from_slice(&pieces);
+                set_groups(&info, &mut d, &[0, 0xF], file);
+                d
+            })
+            .collect();
+
+        let size = |d: &PairsData| d.group_idx[d.group_len.iter().position(|&len| len == 0).unwrap()] as usize;
+        let mut fixture = Fixture {
+            wdl: groups.iter().map(|d| [vec![2; size(d)], vec![2; size(d)]]).collect(),
+            dtz: groups.iter().map(|d| [vec![0; size(d)], vec![0; size(d)]]).collect(),
+            info,
+            groups,
+        };
+
+        let table = generator.generate(name).unwrap();
+        let pawn_dtz = fixture.info.has_pawns.then(|| pawn_dtz(name, generator));
+        for placement in placements(fixture.info.has_pawns) {
+            for white_to_move in [true, false] {
+                let Some(board) = fixture_board(piece, placement, white_to_move) else {
+                    continue;
+                };
+                let (file, idx) = fixture_index(&fixture.info, &fixture.groups, &board);
+                let dtm = table.probe(&board);
+                let side = !white_to_move as usize;
+                fixture.wdl[file][side][idx] = (signed_plies(dtm).signum() + 1) as u8 * 2;
+                // Without pawns the winner never zeroes before the mate
+                fixture.dtz[file][side][idx] = match &pawn_dtz {
+                    Some(dtz) => dtz[pawn_state(&board)],
+                    None => signed_plies(dtm),
+                };
+            }
+        }
+        fixture
+    }
+
+    // DTZ sub-table flags by leading pawn file, so pawn tables store both sides
+    // to move and every kind of value map
+    const DTZ_LAYOUTS: [u8; 4] = [0, FLAG_STM | FLAG_MAPPED, FLAG_MAPPED | FLAG_WIDE, FLAG_STM | FLAG_MAPPED | FLAG_WIDE];
+
+    // One sub-table coded the way the generator does: frequent neighbours are
+    // merged into pair symbols, and the symbols get a canonical Huffman code.
+    // Returns the size information, the sparse index, the block lengths and
+    // the data blocks.
+    fn fixture_sub_table(flags: u8, values: &[u8]) -> [Vec<u8>; 4] {
+        if values.iter().all(|&value| value == values[0]) {
+            return [vec![flags | FLAG_SINGLE_VALUE, values[0]], Vec::new(), Vec::new(), Vec::new()];
+        }
+
+        // The first symbols are the values themselves
+        let mut children: Vec<Option<(usize, usize)>> = vec![None; *values.iter().max().unwrap() as usize + 1];
+        let mut expands_to = vec![1; children.len()];
+        let mut stream: Vec<usize> = values.iter().map(|&value| value as usize).collect();
+        for _ in 0..64 {
+            let mut counts = std::collections::BTreeMap::new();
+            for pair in stream.windows(2) {
+                if expands_to[pair[0]] + expands_to[pair[1]] <= 256 {
+                    *counts.entry((pair[0], pair[1])).or_insert(0) += 1;
+                }
+            }
+            let Some((&pair, &count)) = counts.iter().max_by_key(|&(_, &count)| count) else {
+                break;
+            };
+            if count < 8 {
+                break;
+            }
+            let sym = children.len();
+            children.push(Some(pair));
+            expands_to.push(expands_to[pair.0] + expands_to[pair.1]);
+
+            let mut merged = Vec::with_capacity(stream.len());
+            let mut i = 0;
+            while i < stream.len() {
+                if i + 1 < stream.len() && (stream[i], stream[i + 1]) == pair {
+                    merged.push(sym);
+                    i += 2;
+                } else {
+                    merged.push(stream[i]);
+                    i += 1;
+                }
+            }
+            stream = merged;
+        }
+
+        // Huffman code lengths, merging the two lightest (then smallest) trees
+        let symbols = children.len();
+        let mut weights = vec![0u64; symbols];
+        for &sym in &stream {
+            weights[sym] += 1;
+        }
+        let mut code_len = vec![0usize; symbols];
+        let mut trees: Vec<(u64, Vec<usize>)> = (0..symbols).map(|sym| (weights[sym], vec![sym])).collect();
+        while trees.len() > 1 {
+            trees.sort_by_key(|(weight, members)| std::cmp::Reverse((*weight, members.len())));
+            let (light, first) = trees.pop().unwrap();
+            let (heavy, second) = trees.pop().unwrap();
+            for &sym in first.iter().chain(&second) {
+                code_len[sym] += 1;
+            }
+            trees.push((light + heavy, [first, second].concat()));
+        }
+
+        // Symbols are numbered from the longest codes, and each length's codes
+        // count up from a base the shorter codes sit above
+        let min_len = *code_len.iter().min().unwrap();
+        let max_len = *code_len.iter().max().unwrap();
+        let mut order: Vec<usize> = (0..symbols).collect();
+        order.sort_by_key(|&sym| std::cmp::Reverse(code_len[sym]));
+        let mut number = vec![0; symbols];
+        for (n, &sym) in order.iter().enumerate() {
+            number[sym] = n;
+        }
+        let with_len = |len: usize| code_len.iter().filter(|&&l| l == len).count();
+        let mut lowest = vec![0; max_len + 1];
+        let mut base = vec![0u64; max_len + 1];
+        for len in (min_len..max_len).rev() {
+            lowest[len] = lowest[len + 1] + with_len(len + 1);
+            base[len] = (base[len + 1] + with_len(len + 1) as u64) / 2;
+        }
+
+        // Whole symbols per block, most significant bit first
+        let block_size = 64;
+        let mut blocks: Vec<(Vec<u8>, usize)> = Vec::new();
+        let mut bits = block_size * 8;
+        for &sym in &stream {
+            let len = code_len[sym];
+            let code = base[len] + (number[sym] - lowest[len]) as u64;
+            if bits + len > block_size * 8 || blocks.last().unwrap().1 + expands_to[sym] > 1 << 16 {
+                blocks.push((vec![0; block_size], 0));
+                bits = 0;
+            }
+            let (data, count) = blocks.last_mut().unwrap();
+            for bit in 0..len {
+                if code >> (len - 1 - bit) & 1 != 0 {
+                    data[(bits + bit) / 8] |= 0x80 >> ((bits + bit) % 8);
+                }
+            }
+            bits += len;
+            *count += expands_to[sym];
+        }
+
+        let span = 256usize;
+        let mut sizes = vec![flags, block_size.trailing_zeros() as u8, span.trailing_zeros() as u8, 0];
+        sizes.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+        sizes.extend_from_slice(&[max_len as u8, min_len as u8]);
+        for &lowest in &lowest[min_len..] {
+            sizes.extend_from_slice(&(lowest as u16).to_le_bytes());
+        }
+        sizes.extend_from_slice(&(symbols as u16).to_le_bytes());
+        for &sym in &order {
+            // A leaf has its value on the left and 0xFFF on the right
+            let (left, right) = children[sym].map_or((sym, 0xFFF), |(left, right)| (number[left], number[right]));
+            sizes.extend_from_slice(&[left as u8, (left >> 8 | right << 4) as u8, (right >> 4) as u8]);
+        }
+        sizes.resize(sizes.len() + (symbols & 1), 0);
+
+        // Each span is indexed at its middle value
+        let starts: Vec<usize> = blocks.iter()
+            .scan(0, |start, (_, count)| {
+                *start += count;
+                Some(*start - count)
+            })
+            .collect();
+        let mut sparse_index = Vec::new();
+        for k in 0..values.len().div_ceil(span) {
+            let middle = k * span + span / 2;
+            let block = starts.partition_point(|&start| start <= middle.min(values.len() - 1)) - 1;
+            sparse_index.extend_from_slice(&(block as u32).to_le_bytes());
+            sparse_index.extend_from_slice(&((middle - starts[block]) as u16).to_le_bytes());
+        }
+
+        let block_lengths = blocks.iter().flat_map(|(_, count)| (*count as u16 - 1).to_le_bytes()).collect();
+        let data = blocks.into_iter().flat_map(|(data, _)| data).collect();
+        [sizes, sparse_index, block_lengths, data]
+    }
+
+    // Writes the sub-tables, by leading pawn file and then side, in the order
+    // Table::parse reads them
+    fn write_fixture(path: &Path, magic: [u8; 4], fixture: &Fixture, sub_tables: &[Vec<[Vec<u8>; 4]>], map: &[u8]) {
+        let mut bytes = magic.to_vec();
+        bytes.push(0);
+        for d in &fixture.groups {
+            bytes.push(0); // Order of the groups
+            bytes.extend(d.pieces[..3].iter().map(|&piece| piece | piece << 4));
+        }
+        bytes.resize(bytes.len() + (bytes.len() & 1), 0);
+        for part in 0..3 {
+            for sub_table in sub_tables.iter().flatten() {
+                bytes.extend_from_slice(&sub_table[part]);
+            }
+            if part == 0 && magic == DTZ_MAGIC {
+                bytes.extend_from_slice(map);
+                bytes.resize(bytes.len() + (bytes.len() & 1), 0);
+            }
+        }
+        for sub_table in sub_tables.iter().flatten() {
+            bytes.resize((bytes.len() + 0x3F) & !0x3F, 0);
+            bytes.extend_from_slice(&sub_table[3]);
+        }
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn write_wdl_fixture(path: &Path, fixture: &Fixture) {
+        let sub_tables: Vec<Vec<[Vec<u8>; 4]>> = fixture.wdl.iter()
+            .map(|sides| sides.iter().map(|values| fixture_sub_table(0, values)).collect())
+            .collect();
+        write_fixture(path, WDL_MAGIC, fixture, &sub_tables, &[]);
+    }
+
+    // DTZ tables store one side to move per file, in plies less one or through
+    // a map from each result's symbols to those values
+    fn write_dtz_fixture(path: &Path, fixture: &Fixture) {
+        let mut map = Vec::new();
+        let mut sub_tables = Vec::new();
+        for (file, sides) in fixture.dtz.iter().enumerate() {
+            let flags = DTZ_LAYOUTS[file] | FLAG_WIN_PLIES | FLAG_LOSS_PLIES;
+            let dtz = &sides[(flags & FLAG_STM) as usize];
+            let stored: Vec<u8> = if flags & FLAG_MAPPED == 0 {
+                dtz.iter().map(|&plies| (plies.abs() - 1).max(0) as u8).collect()
+            } else {
+                let listed = |sign: i32| {
+                    let mut list: Vec<i32> = dtz.iter().filter(|&&plies| plies.signum() == sign).map(|&plies| plies.abs() - 1).collect();
+                    list.sort_unstable();
+                    list.dedup();
+                    list
+                };
+                let (wins, losses) = (listed(1), listed(-1));
+                // Cursed wins and blessed losses come last and are empty here
+                for section in [&wins, &losses, &Vec::new(), &Vec::new()] {
+                    if flags & FLAG_WIDE != 0 {
+                        map.resize(map.len() + (map.len() & 1), 0);
+                        map.extend_from_slice(&(section.len() as u16).to_le_bytes());
+                        map.extend(section.iter().flat_map(|&value| (value as u16).to_le_bytes()));
+                    } else {
+                        map.push(section.len() as u8);
+                        map.extend(section.iter().map(|&value| value as u8));
+                    }
+                }
+                dtz.iter()
+                    .map(|&plies| match plies.signum() {
+                        1 => wins.binary_search(&(plies - 1)).unwrap() as u8,
+                        -1 => losses.binary_search(&(-plies - 1)).unwrap() as u8,
+                        _ => 0,
+                    })
+                    .collect()
+            };
+            sub_tables.push(vec![fixture_sub_table(flags, &stored)]);
+        }
+        write_fixture(path, DTZ_MAGIC, fixture, &sub_tables, &map);
+    }
+
+    // Probes tables written from the retrograde results, so the whole decoding
+    // path runs without shipping binary files
+    #[test]
+    fn probes_match_known_results() {
+        crate::tests::init();
+        let dir = std::env::temp_dir().join(format!("syzygy-fixtures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut generator = Generator::new();
+        let mut pawn_fixture = None;
+        for name in ["KQvK", "KRvK", "KNvK", "KPvK"] {
+            let fixture = fixture(name, &mut generator);
+            write_wdl_fixture(&dir.join(format!("{}.rtbw", name)), &fixture);
+            write_dtz_fixture(&dir.join(format!("{}.rtbz", name)), &fixture);
+            pawn_fixture = Some(fixture);
+        }
+        let pawn_fixture = pawn_fixture.unwrap();
+
+        // A private instance, so other tests don't see these tables
+        let tablebases = Tablebases::open(dir.to_str().unwrap());
+        assert_eq!(tablebases.wdl_count(), 4);
+        assert_eq!(tablebases.max_pieces, 3);
+
+        let cases = [
+            ("8/8/4k3/8/2K5/8/3Q4/8 w - -", Wdl::Win),
+            ("8/8/4k3/8/2K5/8/3Q4/8 b - -", Wdl::Loss),
+            ("8/8/8/8/8/8/4kQ2/K7 b - -", Wdl::Draw), // The queen hangs
+            ("8/8/4k3/8/2K5/8/3R4/8 b - -", Wdl::Loss),
+            ("8/8/4k3/8/2K5/8/3N4/8 w - -", Wdl::Draw),
+            ("4k3/8/4K3/4P3/8/8/8/8 w - -", Wdl::Win), // King on the sixth ahead of its pawn
+            ("4k3/8/4K3/4P3/8/8/8/8 b - -", Wdl::Loss),
+            ("k7/8/8/8/8/8/P7/K7 w - -", Wdl::Draw), // Rook pawn against the corner
+            ("7K/8/8/8/P7/8/8/7k b - -", Wdl::Loss), // Outside the pawn's square
+        ];
+        for (fen, expected) in cases {
+            let board = parse_fen(fen).unwrap();
+            assert_eq!(tablebases.probe_wdl(&board), Some(expected), "{}", fen);
+            assert_eq!(tablebases.probe_wdl(&board.mirror()), Some(expected), "{}", fen);
+        }
+
+        // Mate in one is a DTZ of one, and the root picks it
+        let board = parse_fen("k7/8/1K6/8/8/8/8/7Q w - -").unwrap();
+        let ranked = tablebases.probe_root(&board).unwrap();
+        assert_eq!(ranked[0], ((63, 7), 1));
+
+        // Every other root move of a won rook ending keeps the win further away
+        let board = parse_fen("8/8/4k3/8/2K5/8/3R4/8 w - -").unwrap();
+        let ranked = tablebases.probe_root(&board).unwrap();
+        assert!(ranked[0].1 > 1 && ranked.windows(2).all(|pair| pair[0].1 <= pair[1].1 || pair[1].1 <= 0));
+
+        // The runaway pawn zeroes at once, so Black is two plies from it
+        let board = parse_fen("7K/8/8/8/P7/8/8/7k b - -").unwrap();
+        assert_eq!(probe_dtz(&tablebases, &board), Some(-2));
+        let board = parse_fen("7K/8/8/8/P7/8/8/7k w - -").unwrap();
+        let ranked = tablebases.probe_root(&board).unwrap();
+        assert_eq!(ranked[0], ((32, 24), 1));
+
+        // The pawn table spans every file, both stored sides to move, both map
+        // widths and pair symbols
+        let dtz_table = tablebases.table("KPvK", true).unwrap();
+        let flags: Vec<u8> = dtz_table.items[0].iter().map(|d| d.flags).collect();
+        assert_eq!(flags, DTZ_LAYOUTS.map(|flags| flags | FLAG_WIN_PLIES | FLAG_LOSS_PLIES));
+        let wdl_table = tablebases.table("KPvK", false).unwrap();
+        assert!(wdl_table.items.iter().flatten().all(|d| d.symlen.iter().any(|&len| len > 0)));
+
+        // A sample of every pawn position, with the DTZ read from whichever side
+        // each file stores
+        for placement in placements(true).step_by(11) {
+            for white_to_move in [true, false] {
+                let Some(board) = fixture_board(WP, placement, white_to_move) else {
+                    continue;
+                };
+                let (file, idx) = fixture_index(&pawn_fixture.info, &pawn_fixture.groups, &board);
+                let side = !white_to_move as usize;
+                let expected = Wdl::from_value(pawn_fixture.wdl[file][side][idx] as i32 - 2);
+                assert_eq!(tablebases.probe_wdl(&board), Some(expected));
+                assert_eq!(tablebases.probe_wdl(&board.mirror()), Some(expected));
+                assert_eq!(probe_dtz(&tablebases, &board), Some(pawn_fixture.dtz[file][side][idx]));
+            }
+        }
+
+        // Tables are read on first use, so the files stay until here
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}